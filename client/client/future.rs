@@ -8,43 +8,149 @@ use protocol::mainstream::PythonResult;
 
 use crate::errors::{Error, Result};
 
+pub struct FutureResult {
+    pub py_result: PythonResult,
+    pub locals: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub enum FutureError {
+    // Server sent an ErrorResponse for the atom
+    Server(protocol::ErrorResponse),
+    // Mainstream dropped before the atom finished, with the server's notice if it sent one
+    WorkerLost(String),
+}
+
+// Server either ran the code or it failed with a FutureError
+pub type FutureMsg = std::result::Result<FutureResult, FutureError>;
+
+// Ask the background thread to cancel an atom
+pub struct CancelMsg {
+    pub id: String,
+    pub outcome_send: mpsc::Sender<protocol::CancelOutcome>,
+}
+
 #[pyclass]
 pub struct Future {
-    recv: mpsc::Receiver<PythonResult>,
+    id: String,
+    recv: mpsc::Receiver<FutureMsg>,
+    // Result once received, so we can wait many times
+    result: Option<FutureMsg>,
+    // None if the server doesn't support cancel
+    cancel_send: Option<mpsc::Sender<CancelMsg>>,
 }
 
 impl Future {
-    pub fn new(recv: mpsc::Receiver<PythonResult>) -> Self {
-        Self { recv }
+    pub fn new(
+        id: String,
+        recv: mpsc::Receiver<FutureMsg>,
+        cancel_send: Option<mpsc::Sender<CancelMsg>>,
+    ) -> Self {
+        Self {
+            id,
+            recv,
+            result: None,
+            cancel_send,
+        }
     }
 
-    fn wait_no_timeout(&self) -> Result<PythonResult> {
+    fn wait_no_timeout(&self) -> Result<FutureMsg> {
         self.recv
             .recv()
             .map_err(|_| Error::ClientThreadDoesNotExist)
     }
 
-    fn wait_timeout(&self, timeout: time::Duration) -> Result<PythonResult> {
-        self.recv.recv_timeout(timeout).or_else(|err| match err {
-            mpsc::RecvTimeoutError::Timeout => Err(Error::FutureTimeout),
-            mpsc::RecvTimeoutError::Disconnected => Err(Error::ClientThreadDoesNotExist),
+    fn wait_timeout(&self, timeout: time::Duration) -> Result<FutureMsg> {
+        self.recv.recv_timeout(timeout).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => Error::FutureTimeout,
+            mpsc::RecvTimeoutError::Disconnected => Error::ClientThreadDoesNotExist,
         })
     }
+
+    fn result(&mut self, timeout: Option<u64>) -> Result<&FutureResult> {
+        if self.result.is_none() {
+            let res = match timeout {
+                Some(t) => self.wait_timeout(time::Duration::from_secs(t)),
+                None => self.wait_no_timeout(),
+            }?;
+            self.result = Some(res);
+        }
+
+        match self.result.as_ref().unwrap() {
+            Ok(res) => Ok(res),
+            Err(FutureError::Server(err)) => Err(Error::Server(err.clone())),
+            Err(FutureError::WorkerLost(reason)) => Err(Error::WorkerLost(reason.clone())),
+        }
+    }
 }
 
 #[pymethods]
 impl Future {
-    fn wait(&self, py: Python, timeout: Option<u64>) -> Result<Py<PyBytes>> {
-        match timeout {
-            Some(t) => self.wait_timeout(time::Duration::from_secs(t)),
-            None => self.wait_no_timeout(),
-        }
-        .and_then(|msg| match msg {
-            PythonResult::Error(e) => Err(Error::PythonResultError(e)),
+    fn wait(&mut self, py: Python, timeout: Option<u64>) -> Result<Py<PyBytes>> {
+        match &self.result(timeout)?.py_result {
+            PythonResult::Error(e) => Err(Error::PythonException(e.clone())),
             PythonResult::Return(ret) => {
-                let bytes = PyBytes::new(py, &ret).into_py(py);
+                let bytes = PyBytes::new(py, ret).into_py(py);
                 Ok(bytes)
             }
-        })
+        }
+    }
+
+    // True once wait won't block, whether it returns or raises
+    fn is_done(&mut self) -> bool {
+        if self.result.is_none() {
+            match self.recv.try_recv() {
+                Ok(res) => self.result = Some(res),
+                Err(mpsc::TryRecvError::Empty) => return false,
+                Err(mpsc::TryRecvError::Disconnected) => {}
+            }
+        }
+        true
+    }
+
+    // Returns the outcome name, before-execution, during-execution or not-found
+    fn cancel(&mut self, timeout: Option<u64>) -> Result<&'static str> {
+        let cancel_send = self
+            .cancel_send
+            .as_ref()
+            .ok_or(Error::FeatureNotEnabled(protocol::Feature::Cancel))?;
+
+        // Already done, nothing to cancel
+        if self.result.is_some() {
+            return Ok(protocol::CancelOutcome::NotFound.name());
+        }
+
+        let (outcome_send, outcome_recv) = mpsc::channel();
+        cancel_send
+            .send(CancelMsg {
+                id: self.id.clone(),
+                outcome_send,
+            })
+            .map_err(|_| Error::ClientThreadDoesNotExist)?;
+
+        let outcome = match timeout {
+            Some(t) => outcome_recv
+                .recv_timeout(time::Duration::from_secs(t))
+                .map_err(|err| match err {
+                    mpsc::RecvTimeoutError::Timeout => Error::FutureTimeout,
+                    mpsc::RecvTimeoutError::Disconnected => Error::ClientThreadDoesNotExist,
+                }),
+            None => outcome_recv
+                .recv()
+                .map_err(|_| Error::ClientThreadDoesNotExist),
+        }?;
+
+        Ok(outcome.name())
+    }
+
+    fn wait_locals(&mut self, py: Python, timeout: Option<u64>) -> Result<Option<Py<PyBytes>>> {
+        let res = self.result(timeout)?;
+        match &res.py_result {
+            PythonResult::Error(e) => Err(Error::PythonException(e.clone())),
+            PythonResult::Return(_) => Ok(res
+                .locals
+                .as_ref()
+                .map(|locals| PyBytes::new(py, locals).into_py(py))),
+        }
     }
 }
@@ -42,15 +42,24 @@ impl MainStream {
         self.resp_msgs.pop_front()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn queue_source_code(
         &mut self,
         id: String,
+        mode: protocol::EvalMode,
+        namespace: protocol::Namespace,
+        return_locals: protocol::ReturnLocals,
+        timeout_ms: Option<u64>,
         code: String,
         locals: Vec<u8>,
         globals: Vec<u8>,
     ) {
         let msg = protocol::CodeString {
             future_id: id,
+            mode,
+            namespace,
+            return_locals,
+            timeout_ms,
             code,
             locals,
             globals,
@@ -63,6 +72,45 @@ impl MainStream {
         ));
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub fn queue_pickle(
+        &mut self,
+        id: String,
+        format: protocol::PickleFormat,
+        py_version: (u8, u8),
+        namespace: protocol::Namespace,
+        return_locals: protocol::ReturnLocals,
+        timeout_ms: Option<u64>,
+        pickle: Vec<u8>,
+        locals: Vec<u8>,
+        globals: Vec<u8>,
+    ) {
+        let msg = protocol::CodePickle {
+            future_id: id,
+            format,
+            py_version,
+            namespace,
+            return_locals,
+            timeout_ms,
+            pickle,
+            locals,
+            globals,
+        };
+
+        self.outbuffer.extend(&protocol::new_req(
+            protocol::MessageType::CodePickle,
+            0,
+            msg,
+        ));
+    }
+
+    pub fn queue_cancel(&mut self, id: String) {
+        let msg = protocol::Cancel { future_id: id };
+
+        self.outbuffer
+            .extend(&protocol::new_req(protocol::MessageType::Cancel, 0, msg));
+    }
+
     pub fn write(&mut self) -> io::Result<()> {
         let bytes_written = self.stream.write(&self.outbuffer)?;
         self.stream.flush()?;
@@ -77,15 +125,16 @@ impl MainStream {
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<()> {
-        let bytes_read =
-            fatal_io_error("couldn't read on mainstream session", self.stream.read(buf))?;
-
-        if bytes_read == 0 {
-            return Err(Error::MainStreamClosed);
+        // Drain the socket - responses may be larger than our read buffer
+        loop {
+            match self.stream.read(buf) {
+                Ok(0) => return Err(Error::MainStreamClosed),
+                Ok(bytes_read) => self.inbuffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => fatal_io_error("couldn't read on mainstream session", Err(err))?,
+            }
         }
 
-        self.inbuffer.extend(&buf[..bytes_read]);
-
         while self.inbuffer.len() >= protocol::RESPONSE_HEADER_SIZE {
             let mut header_raw = [0; protocol::RESPONSE_HEADER_SIZE];
             for (h, b) in header_raw.iter_mut().zip(self.inbuffer.iter()) {
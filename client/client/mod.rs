@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::io::Write;
+use std::io::{self, Write};
 use std::net::TcpStream as StdTcpStream;
 use std::sync::mpsc;
 use std::thread;
@@ -18,25 +18,36 @@ mod future;
 mod mainstream;
 mod outputstream;
 pub use future::Future;
+use future::{CancelMsg, FutureError, FutureMsg, FutureResult};
 
 const MAIN_STREAM_TK: Token = Token(0);
 const OUTPUT_STREAM_TK: Token = Token(1);
 const RO: Interest = Interest::READABLE;
 const POLL_DURATION: time::Duration = time::Duration::from_millis(100);
+// How long to wait for the server's notice once the mainstream drops
+const NOTICE_WAIT: time::Duration = time::Duration::from_millis(250);
 
 enum EvalMsg {
     // Python Source Code
-    String(String),
+    String(protocol::EvalMode, String),
+    // Pickled callable or marshalled code object
+    Pickle(protocol::PickleFormat, (u8, u8), Vec<u8>),
 }
 
 struct EvalCode {
     id: String,
     msg: EvalMsg,
+    namespace: protocol::Namespace,
+    return_locals: protocol::ReturnLocals,
+    timeout_ms: Option<u64>,
     locals: Vec<u8>,
     globals: Vec<u8>,
-    future_send: mpsc::Sender<protocol::mainstream::PythonResult>,
+    future_send: mpsc::Sender<FutureMsg>,
 }
 
+// (fd, line, future_id, seq, ts) with ts in seconds since the unix epoch
+type OutputLine = (usize, Py<PyBytes>, Option<String>, u64, f64);
+
 enum ThreadMsg {
     PipeOut(outputstream::PipeOut),
 }
@@ -45,24 +56,35 @@ enum ThreadMsg {
 pub struct PyProxyClient {
     handle: Option<thread::JoinHandle<Result<()>>>,
     code_send: mpsc::Sender<EvalCode>,
+    cancel_send: mpsc::Sender<CancelMsg>,
     thread_recv: mpsc::Receiver<ThreadMsg>,
     close_send: mpsc::Sender<()>,
+    stateful: bool,
+    features: Vec<protocol::Feature>,
 }
 
 #[pymethods]
 impl PyProxyClient {
     #[new]
-    #[pyo3(signature=(conn, name=None))]
-    fn new(conn: &mut PyConnection, name: Option<&str>) -> Result<Self> {
+    #[pyo3(signature=(conn, name=None, stateful=false))]
+    fn new(conn: &mut PyConnection, name: Option<&str>, stateful: bool) -> Result<Self> {
         // Spawn background thread
         let name = name.unwrap_or("pyproxy-client");
 
+        let features = conn.features.clone();
+        if stateful && !features.contains(&protocol::Feature::StatefulSessions) {
+            return Err(Error::FeatureNotEnabled(
+                protocol::Feature::StatefulSessions,
+            ));
+        }
+
         let stream = conn.inner.take().ok_or(Error::MissingMainStream)?;
         let session_id = conn.session_id.to_owned();
         let stream_token = conn.stream_token.to_owned();
         let output_addr = conn.output_addr.to_owned();
 
         let (code_send, code_recv) = mpsc::channel();
+        let (cancel_send, cancel_recv) = mpsc::channel();
         let (thread_send, thread_recv) = mpsc::channel();
         let (close_send, close_recv) = mpsc::channel();
 
@@ -72,6 +94,7 @@ impl PyProxyClient {
                 run_forever(
                     stream,
                     code_recv,
+                    cancel_recv,
                     thread_send,
                     close_recv,
                     session_id,
@@ -84,8 +107,11 @@ impl PyProxyClient {
         Ok(Self {
             handle: Some(handle),
             code_send,
+            cancel_send,
             thread_recv,
             close_send,
+            stateful,
+            features,
         })
     }
 
@@ -118,40 +144,122 @@ impl PyProxyClient {
         }
     }
 
+    #[pyo3(signature=(
+        id, code, locs, globs, mode="exec", replace_globals=false, return_locals=None,
+        timeout_ms=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
     pub fn eval_str(
         &mut self,
         id: &str,
         code: &str,
         locs: &PyBytes,
         globs: &PyBytes,
+        mode: &str,
+        replace_globals: bool,
+        return_locals: Option<&PyAny>,
+        timeout_ms: Option<u64>,
     ) -> Result<Future> {
+        let mode = match mode {
+            "eval" => protocol::EvalMode::Eval,
+            "exec" => protocol::EvalMode::Exec,
+            "single" => protocol::EvalMode::Single,
+            "last" => protocol::EvalMode::Last,
+            _ => return Err(Error::InvalidEvalMode(mode.to_owned())),
+        };
+
         self.check_thread()?;
         let (future_send, future_recv) = mpsc::channel();
 
         self.code_send
             .send(EvalCode {
                 id: id.to_owned(),
-                msg: EvalMsg::String(code.to_owned()),
-                locals: Vec::from_iter(locs.as_bytes().iter().map(|b| *b)),
-                globals: Vec::from_iter(globs.as_bytes().iter().map(|b| *b)),
+                msg: EvalMsg::String(mode, code.to_owned()),
+                namespace: self.namespace(replace_globals),
+                return_locals: self.return_locals(return_locals)?,
+                timeout_ms,
+                locals: Vec::from_iter(locs.as_bytes().iter().copied()),
+                globals: Vec::from_iter(globs.as_bytes().iter().copied()),
                 future_send,
             })
             .map_err(|_| {
                 Error::ThreadClosed(Box::new("failed to send code to background os thread"))
             })?;
 
-        Ok(Future::new(future_recv))
+        Ok(Future::new(
+            id.to_owned(),
+            future_recv,
+            self.cancel_sender(),
+        ))
     }
 
-    pub fn next_output(&mut self, py: Python) -> Result<Option<(usize, Py<PyBytes>)>> {
+    #[pyo3(signature=(
+        id, pickle, locs, globs, marshal=false, replace_globals=false, return_locals=None,
+        timeout_ms=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn eval_pickle(
+        &mut self,
+        py: Python,
+        id: &str,
+        pickle: &PyBytes,
+        locs: &PyBytes,
+        globs: &PyBytes,
+        marshal: bool,
+        replace_globals: bool,
+        return_locals: Option<&PyAny>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Future> {
+        self.check_thread()?;
+        let (future_send, future_recv) = mpsc::channel();
+
+        let format = if marshal {
+            protocol::PickleFormat::Marshal
+        } else {
+            protocol::PickleFormat::Pickle
+        };
+        let version = py.version_info();
+
+        self.code_send
+            .send(EvalCode {
+                id: id.to_owned(),
+                msg: EvalMsg::Pickle(
+                    format,
+                    (version.major, version.minor),
+                    pickle.as_bytes().to_owned(),
+                ),
+                namespace: self.namespace(replace_globals),
+                return_locals: self.return_locals(return_locals)?,
+                timeout_ms,
+                locals: locs.as_bytes().to_owned(),
+                globals: globs.as_bytes().to_owned(),
+                future_send,
+            })
+            .map_err(|_| {
+                Error::ThreadClosed(Box::new("failed to send code to background os thread"))
+            })?;
+
+        Ok(Future::new(
+            id.to_owned(),
+            future_recv,
+            self.cancel_sender(),
+        ))
+    }
+
+    pub fn next_output(&mut self, py: Python) -> Result<Option<OutputLine>> {
         match self.thread_recv.try_recv() {
             Ok(ThreadMsg::PipeOut(pipe_frame)) => {
                 let fd = match pipe_frame.fd {
                     protocol::outputstream::MessageType::Stdout => 1,
                     protocol::outputstream::MessageType::Stderr => 2,
+                    protocol::outputstream::MessageType::Notice => 3,
+                    protocol::outputstream::MessageType::StdoutChunk => 4,
+                    protocol::outputstream::MessageType::StderrChunk => 5,
+                    protocol::outputstream::MessageType::Dropped => 6,
                 };
                 let bytes = PyBytes::new(py, &pipe_frame.line).into_py(py);
-                Ok(Some((fd, bytes)))
+                let ts = pipe_frame.ts as f64 / 1_000_000.0;
+                Ok(Some((fd, bytes, pipe_frame.future_id, pipe_frame.seq, ts)))
             }
             Err(mpsc::TryRecvError::Empty) => Ok(None),
             Err(mpsc::TryRecvError::Disconnected) => Err(Error::ClientThreadDoesNotExist),
@@ -163,9 +271,38 @@ impl PyProxyClient {
     }
 }
 
+impl PyProxyClient {
+    fn namespace(&self, replace_globals: bool) -> protocol::Namespace {
+        match (self.stateful, replace_globals) {
+            (false, _) => protocol::Namespace::Atom,
+            (true, false) => protocol::Namespace::SessionMerge,
+            (true, true) => protocol::Namespace::SessionReplace,
+        }
+    }
+
+    fn cancel_sender(&self) -> Option<mpsc::Sender<CancelMsg>> {
+        if self.features.contains(&protocol::Feature::Cancel) {
+            Some(self.cancel_send.clone())
+        } else {
+            None
+        }
+    }
+
+    fn return_locals(&self, return_locals: Option<&PyAny>) -> Result<protocol::ReturnLocals> {
+        let return_locals = extract_return_locals(return_locals)?;
+        match return_locals {
+            protocol::ReturnLocals::Nothing => Ok(return_locals),
+            _ if self.features.contains(&protocol::Feature::ReturnLocals) => Ok(return_locals),
+            _ => Err(Error::FeatureNotEnabled(protocol::Feature::ReturnLocals)),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run_forever(
     stream: Box<dyn Connection>,
     code_recv: mpsc::Receiver<EvalCode>,
+    cancel_recv: mpsc::Receiver<CancelMsg>,
     thread_send: mpsc::Sender<ThreadMsg>,
     close_recv: mpsc::Receiver<()>,
     _session_id: String,
@@ -194,6 +331,10 @@ fn run_forever(
 
     let mut buffer = vec![0; 4096];
     let mut pending_futures = HashMap::new();
+    let mut pending_cancels = HashMap::new();
+    let mut server_error = None;
+    // Last notice the server sent on the output stream
+    let mut notice = None;
 
     loop {
         // Have we received a close?
@@ -206,9 +347,32 @@ fn run_forever(
         loop {
             match code_recv.try_recv() {
                 Ok(msg) => match msg.msg {
-                    EvalMsg::String(s) => {
+                    EvalMsg::String(mode, s) => {
+                        pending_futures.insert(msg.id.to_owned(), msg.future_send);
+                        main_stream.queue_source_code(
+                            msg.id,
+                            mode,
+                            msg.namespace,
+                            msg.return_locals,
+                            msg.timeout_ms,
+                            s,
+                            msg.locals,
+                            msg.globals,
+                        );
+                    }
+                    EvalMsg::Pickle(format, py_version, pickle) => {
                         pending_futures.insert(msg.id.to_owned(), msg.future_send);
-                        main_stream.queue_source_code(msg.id, s, msg.locals, msg.globals);
+                        main_stream.queue_pickle(
+                            msg.id,
+                            format,
+                            py_version,
+                            msg.namespace,
+                            msg.return_locals,
+                            msg.timeout_ms,
+                            pickle,
+                            msg.locals,
+                            msg.globals,
+                        );
                     }
                 },
                 // No code to send
@@ -218,17 +382,43 @@ fn run_forever(
             }
         }
 
+        // Any futures to cancel?
+        while let Ok(msg) = cancel_recv.try_recv() {
+            main_stream.queue_cancel(msg.id.clone());
+            pending_cancels.insert(msg.id, msg.outcome_send);
+        }
+
         while let Some(resp_msg) = main_stream.next_resp_msg() {
-            if let Some(sender) = pending_futures.remove(resp_msg.future_id()) {
+            if let protocol::ResponseMessage::Cancel(c) = resp_msg {
+                if let Some(sender) = pending_cancels.remove(&c.future_id) {
+                    sender.send(c.outcome).unwrap_or(());
+                }
+            } else if let Some(sender) = pending_futures.remove(resp_msg.future_id()) {
                 match resp_msg {
                     protocol::ResponseMessage::CodePickle(p) => {
-                        sender.send(p.py_result).unwrap_or(());
+                        sender
+                            .send(Ok(FutureResult {
+                                py_result: p.py_result,
+                                locals: p.locals,
+                            }))
+                            .unwrap_or(());
                     }
                     protocol::ResponseMessage::CodeString(p) => {
-                        sender.send(p.py_result).unwrap_or(());
+                        sender
+                            .send(Ok(FutureResult {
+                                py_result: p.py_result,
+                                locals: p.locals,
+                            }))
+                            .unwrap_or(());
+                    }
+                    protocol::ResponseMessage::Error(err) => {
+                        sender.send(Err(FutureError::Server(err))).unwrap_or(());
                     }
                     _ => {}
                 }
+            } else if let protocol::ResponseMessage::Error(err) = resp_msg {
+                // Not tied to any future - the server is likely about to close
+                server_error = Some(err);
             }
         }
 
@@ -267,9 +457,47 @@ fn run_forever(
                     )?;
                 }
 
-                main_stream.read(&mut buffer)?;
+                if ev.is_readable() {
+                    match main_stream.read(&mut buffer) {
+                        // Report why the server closed on us, if it told us
+                        Err(Error::MainStreamClosed) if server_error.is_some() => {
+                            let err = server_error.take().unwrap();
+                            for (_, sender) in pending_futures.drain() {
+                                sender
+                                    .send(Err(FutureError::Server(err.clone())))
+                                    .unwrap_or(());
+                            }
+                            return Err(Error::Server(err));
+                        }
+                        Err(Error::MainStreamClosed) => {
+                            let reason = match wait_for_notice(
+                                &mut poll,
+                                &mut events,
+                                &mut output_stream,
+                                &mut buffer,
+                                &thread_send,
+                                notice.take(),
+                            ) {
+                                Some(notice) => notice,
+                                None => {
+                                    "mainstream closed without a notice from the server".to_owned()
+                                }
+                            };
+                            for (_, sender) in pending_futures.drain() {
+                                sender
+                                    .send(Err(FutureError::WorkerLost(reason.clone())))
+                                    .unwrap_or(());
+                            }
+                            return Err(Error::WorkerLost(reason));
+                        }
+                        res => res?,
+                    }
+                }
             } else if ev.token() == OUTPUT_STREAM_TK {
                 for pipe_out in output_stream.read(&mut buffer)? {
+                    if matches!(pipe_out.fd, protocol::outputstream::MessageType::Notice) {
+                        notice = Some(String::from_utf8_lossy(&pipe_out.line).into_owned());
+                    }
                     if thread_send.send(ThreadMsg::PipeOut(pipe_out)).is_err() {
                         return Ok(());
                     }
@@ -279,10 +507,67 @@ fn run_forever(
     }
 }
 
+// The master sends its notice on the output stream, which may land after the mainstream closes
+fn wait_for_notice(
+    poll: &mut Poll,
+    events: &mut Events,
+    output_stream: &mut outputstream::OutputStream,
+    buffer: &mut [u8],
+    thread_send: &mpsc::Sender<ThreadMsg>,
+    mut notice: Option<String>,
+) -> Option<String> {
+    let deadline = time::Instant::now() + NOTICE_WAIT;
+    loop {
+        // Read first, its readable event may have been in the batch the mainstream closed in
+        let pipe_outs = match output_stream.read(buffer) {
+            Ok(pipe_outs) => pipe_outs,
+            Err(Error::Io(io_err)) if io_err.error.kind() == io::ErrorKind::WouldBlock => vec![],
+            Err(_) => break,
+        };
+        for pipe_out in pipe_outs {
+            if matches!(pipe_out.fd, protocol::outputstream::MessageType::Notice) {
+                notice = Some(String::from_utf8_lossy(&pipe_out.line).into_owned());
+            }
+            thread_send.send(ThreadMsg::PipeOut(pipe_out)).unwrap_or(());
+        }
+
+        let now = time::Instant::now();
+        if notice.is_some() || now >= deadline {
+            break;
+        }
+        if poll.poll(events, Some(deadline - now)).is_err() {
+            break;
+        }
+    }
+
+    notice
+}
+
+// return_locals is None, True for every local or a list of names
+fn extract_return_locals(return_locals: Option<&PyAny>) -> Result<protocol::ReturnLocals> {
+    let return_locals = match return_locals {
+        None => return Ok(protocol::ReturnLocals::Nothing),
+        Some(r) => r,
+    };
+
+    if let Ok(all) = return_locals.extract::<bool>() {
+        return Ok(if all {
+            protocol::ReturnLocals::All
+        } else {
+            protocol::ReturnLocals::Nothing
+        });
+    }
+
+    return_locals
+        .extract::<Vec<String>>()
+        .map(protocol::ReturnLocals::Names)
+        .map_err(|_| Error::InvalidReturnLocals)
+}
+
 fn connect_output_stream(output_addr: String, stream_token: String) -> Result<MioTcpStream> {
     let msg = protocol::new_req(
         protocol::MessageType::Hello,
-        0,
+        protocol::outputstream::FRAME_VERSION,
         protocol::outputstream::ClientHello { stream_token },
     );
 
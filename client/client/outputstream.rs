@@ -4,7 +4,7 @@ use mio::event::Source;
 use mio::net::TcpStream;
 use mio::{Interest, Registry, Token};
 
-use protocol::outputstream::{MessageHeader, HEADER_SIZE};
+use protocol::outputstream::{read_frame, MessageHeader, HEADER_SIZE};
 
 use crate::errors::{fatal_io_error, Error, Result};
 
@@ -16,6 +16,11 @@ pub struct OutputStream {
 pub struct PipeOut {
     pub line: Vec<u8>,
     pub fd: protocol::outputstream::MessageType,
+    // None for notices
+    pub future_id: Option<String>,
+    pub seq: u64,
+    // Microseconds since the unix epoch, by the server's clock
+    pub ts: u64,
 }
 
 impl OutputStream {
@@ -27,43 +32,44 @@ impl OutputStream {
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<Vec<PipeOut>> {
-        let bytes_read = fatal_io_error(
-            "failed to read bytes on outputstream",
-            self.stream.read(buf),
-        )?;
-
-        if bytes_read == 0 {
-            return Err(Error::OutputStreamClosed);
+        // Drain the stream, we're edge triggered and the server may have a backlog
+        loop {
+            match self.stream.read(buf) {
+                Ok(0) => return Err(Error::OutputStreamClosed),
+                Ok(bytes_read) => self.buffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => fatal_io_error("failed to read bytes on outputstream", Err(err))?,
+            }
         }
 
-        self.buffer.extend(&buf[..bytes_read]);
-
         let mut lines = vec![];
 
-        while self.buffer.len() >= HEADER_SIZE {
+        // Consumed bytes are removed once, a backlog is thousands of frames
+        let mut start = 0;
+        while self.buffer.len() - start >= HEADER_SIZE {
             let mut header_raw = [0; HEADER_SIZE];
-            for (h, b) in header_raw.iter_mut().zip(self.buffer.iter()) {
+            for (h, b) in header_raw.iter_mut().zip(self.buffer[start..].iter()) {
                 *h = *b;
             }
 
             let header = MessageHeader::from_raw(header_raw)?;
-            let body_end = HEADER_SIZE + header.msg_len;
+            let body_end = start + HEADER_SIZE + header.msg_len;
             if self.buffer.len() < body_end {
-                return Ok(lines);
+                break;
             }
 
-            let body = &self.buffer[HEADER_SIZE..body_end];
+            let frame = read_frame(&self.buffer[start + HEADER_SIZE..body_end])?;
             lines.push(PipeOut {
                 fd: header.msg_type,
-                line: body.to_owned(),
+                line: frame.data,
+                future_id: frame.future_id,
+                seq: frame.seq,
+                ts: frame.ts,
             });
 
-            let bytes_remaining = self.buffer.len() - body_end;
-            for n in 0..bytes_remaining {
-                self.buffer[n] = self.buffer[n + body_end];
-            }
-            self.buffer.truncate(bytes_remaining);
+            start = body_end;
         }
+        self.buffer.drain(..start);
 
         Ok(lines)
     }
@@ -7,12 +7,19 @@ use mio::{Interest, Registry, Token};
 use pyo3::prelude::*;
 
 use protocol::{
-    MessageType, RequestClientHello, RequestMessageHeader, ResponseClientHello,
+    Feature, MessageType, RequestClientHello, RequestMessageHeader, ResponseClientHello,
     ResponseMessageHeader,
 };
 
 use super::errors::{fatal_io_error, Error, Result};
 
+// Optional protocol features this client implements
+const CLIENT_FEATURES: [Feature; 3] = [
+    Feature::StatefulSessions,
+    Feature::ReturnLocals,
+    Feature::Cancel,
+];
+
 // Connections must be mio Source and Readers and Writers
 pub trait Connection: Source + io::Read + io::Write + Send {}
 
@@ -32,6 +39,8 @@ pub struct PyConnection {
     pub session_id: String,
     pub stream_token: String,
     pub output_addr: String,
+    pub version: u8,
+    pub features: Vec<Feature>,
 }
 
 #[pymethods]
@@ -40,6 +49,16 @@ impl PyConnection {
     pub fn session_id(&self) -> &str {
         &self.session_id[..]
     }
+
+    #[getter]
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    #[getter]
+    pub fn features(&self) -> Vec<&'static str> {
+        self.features.iter().map(|f| f.name()).collect()
+    }
 }
 
 #[pyfunction]
@@ -50,7 +69,7 @@ pub fn new_simple_connection(addr: &str) -> Result<PyConnection> {
     )?;
 
     // Send a client hello to server
-    let payload = RequestClientHello::new().into_buf();
+    let payload = RequestClientHello::new(&CLIENT_FEATURES).into_buf();
     let header = RequestMessageHeader::new(MessageType::Hello, 0, payload.len()).into_buf();
 
     // Send client hello to server
@@ -68,10 +87,6 @@ pub fn new_simple_connection(addr: &str) -> Result<PyConnection> {
         stream.read_exact(&mut header_buf),
     )?;
     let resp_header = ResponseMessageHeader::from_buf(header_buf)?;
-    match resp_header.msg_type {
-        MessageType::Hello => {}
-        _ => return Err(Error::ServerDidntSendHello),
-    }
 
     // Okay read the response payload
     let mut buffer = vec![0; resp_header.msg_len()];
@@ -79,6 +94,15 @@ pub fn new_simple_connection(addr: &str) -> Result<PyConnection> {
         "reading failed on mainstream with open TCP Stream to PyProxyServer",
         stream.read_exact(&mut buffer),
     )?;
+
+    match resp_header.msg_type {
+        MessageType::Hello => {}
+        MessageType::Error => {
+            let err: protocol::ErrorResponse = protocol::read_msg(&buffer)?;
+            return Err(Error::Server(err));
+        }
+        _ => return Err(Error::ServerDidntSendHello),
+    }
     let server_hello: ResponseClientHello = protocol::read_msg(&buffer)?;
 
     fatal_io_error(
@@ -93,6 +117,8 @@ pub fn new_simple_connection(addr: &str) -> Result<PyConnection> {
         session_id: server_hello.session_id,
         stream_token: server_hello.stream_token,
         output_addr: server_hello.output_addr,
+        version: server_hello.version,
+        features: protocol::features_from_u8(&server_hello.features),
     })
 }
 
@@ -18,8 +18,13 @@ pub enum Error {
     ThreadClosed(Box<dyn Any + Send + 'static>),
     OutputStreamClosed,
     MainStreamClosed,
-    PythonResultError(Vec<u8>),
+    PythonException(Vec<u8>),
     FutureTimeout,
+    InvalidEvalMode(String),
+    InvalidReturnLocals,
+    FeatureNotEnabled(protocol::Feature),
+    Server(protocol::ErrorResponse),
+    WorkerLost(String),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -1,13 +1,17 @@
 use pyo3::create_exception;
 use pyo3::exceptions::PyException;
 use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::PyTypeError;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
 mod client;
 mod connection;
 mod errors;
 pub use connection::new_simple_connection;
 use errors::Error;
+use protocol::ErrorCode;
 
 create_exception!(
     "pyproxy_client",
@@ -65,6 +69,82 @@ create_exception!(
     )
 );
 
+create_exception!(
+    "pyproxy_client",
+    PyProxyServerError,
+    PyProxyProtocolError,
+    concat!(
+        "PyProxyServerError is raised when the server sends an error response. ",
+        "Subclasses are raised for known error codes, args are (code, reason)."
+    )
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyUnsupportedVersionError,
+    PyProxyServerError,
+    "PyProxyUnsupportedVersionError is raised when client and server share no protocol version."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyUnrecognisedMessageError,
+    PyProxyServerError,
+    "PyProxyUnrecognisedMessageError is raised when the server doesn't know a message type we sent."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyUnexpectedMessageError,
+    PyProxyServerError,
+    "PyProxyUnexpectedMessageError is raised when we sent a message type invalid at that point."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyBadMessageError,
+    PyProxyServerError,
+    "PyProxyBadMessageError is raised when the server couldn't deserialize a message we sent."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyCancelledError,
+    PyProxyServerError,
+    "PyProxyCancelledError is raised waiting on a future cancelled before it ran."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyExecutionTimeoutError,
+    PyProxyServerError,
+    "PyProxyExecutionTimeoutError is raised waiting on a future the server interrupted at its timeout."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyWorkerLostError,
+    PyProxyClosedSessionError,
+    concat!(
+        "PyProxyWorkerLostError is raised when the session's mainstream closed while futures ",
+        "were in flight, usually because the worker running them exited."
+    )
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyShuttingDownError,
+    PyProxyServerError,
+    "PyProxyShuttingDownError is raised waiting on a future the server refused as it is shutting down."
+);
+
+create_exception!(
+    "pyproxy_client",
+    PyProxyBusyError,
+    PyProxyServerError,
+    "PyProxyBusyError is raised waiting on a future the server refused as the session had too many queued."
+);
+
 #[pymodule]
 fn pyproxy_client(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<client::PyProxyClient>()?;
@@ -81,6 +161,10 @@ fn pyproxy_client(py: Python, m: &PyModule) -> PyResult<()> {
         "PyProxyClosedSessionError",
         py.get_type::<PyProxyClosedSessionError>(),
     )?;
+    m.add(
+        "PyProxyWorkerLostError",
+        py.get_type::<PyProxyWorkerLostError>(),
+    )?;
     m.add(
         "PyProxyRemoteExceptionPickle",
         py.get_type::<PyProxyRemoteExceptionPickle>(),
@@ -89,6 +173,36 @@ fn pyproxy_client(py: Python, m: &PyModule) -> PyResult<()> {
         "PyProxyFutureTimeout",
         py.get_type::<PyProxyFutureTimeout>(),
     )?;
+    m.add("PyProxyServerError", py.get_type::<PyProxyServerError>())?;
+    m.add(
+        "PyProxyUnsupportedVersionError",
+        py.get_type::<PyProxyUnsupportedVersionError>(),
+    )?;
+    m.add(
+        "PyProxyUnrecognisedMessageError",
+        py.get_type::<PyProxyUnrecognisedMessageError>(),
+    )?;
+    m.add(
+        "PyProxyUnexpectedMessageError",
+        py.get_type::<PyProxyUnexpectedMessageError>(),
+    )?;
+    m.add(
+        "PyProxyBadMessageError",
+        py.get_type::<PyProxyBadMessageError>(),
+    )?;
+    m.add(
+        "PyProxyCancelledError",
+        py.get_type::<PyProxyCancelledError>(),
+    )?;
+    m.add(
+        "PyProxyExecutionTimeoutError",
+        py.get_type::<PyProxyExecutionTimeoutError>(),
+    )?;
+    m.add(
+        "PyProxyShuttingDownError",
+        py.get_type::<PyProxyShuttingDownError>(),
+    )?;
+    m.add("PyProxyBusyError", py.get_type::<PyProxyBusyError>())?;
     Ok(())
 }
 
@@ -113,10 +227,44 @@ impl From<errors::Error> for PyErr {
             Error::ThreadClosed(err) => PyProxyClosedSessionError::new_err(format!("{:?}", err)),
             Error::OutputStreamClosed => PyProxyIOError::new_err("output stream closed"),
             Error::MainStreamClosed => PyProxyIOError::new_err("mainstream closed"),
-            Error::PythonResultError(data) => PyProxyRemoteExceptionPickle::new_err(data),
+            Error::PythonException(data) => Python::with_gil(|py| {
+                PyProxyRemoteExceptionPickle::new_err(Py::<PyBytes>::from(PyBytes::new(py, &data)))
+            }),
             Error::FutureTimeout => {
                 PyProxyFutureTimeout::new_err("timed out waiting for future to complete")
             }
+            Error::InvalidEvalMode(mode) => PyValueError::new_err(format!(
+                "eval mode must be one of eval, exec, single or last - got {}",
+                mode
+            )),
+            Error::InvalidReturnLocals => {
+                PyTypeError::new_err("return_locals must be None, a bool or a list of names")
+            }
+            Error::FeatureNotEnabled(feature) => PyProxyProtocolError::new_err(format!(
+                "PyProxy server didn't enable protocol feature {}",
+                feature.name()
+            )),
+            Error::WorkerLost(reason) => PyProxyWorkerLostError::new_err(reason),
+            Error::Server(err) => {
+                let args = (err.code, err.reason.clone());
+                match err.code() {
+                    Some(ErrorCode::UnsupportedVersion) => {
+                        PyProxyUnsupportedVersionError::new_err(args)
+                    }
+                    Some(ErrorCode::UnrecognisedMessageType) => {
+                        PyProxyUnrecognisedMessageError::new_err(args)
+                    }
+                    Some(ErrorCode::UnexpectedMessageType) => {
+                        PyProxyUnexpectedMessageError::new_err(args)
+                    }
+                    Some(ErrorCode::BadMessage) => PyProxyBadMessageError::new_err(args),
+                    Some(ErrorCode::Cancelled) => PyProxyCancelledError::new_err(args),
+                    Some(ErrorCode::Timeout) => PyProxyExecutionTimeoutError::new_err(args),
+                    Some(ErrorCode::ShuttingDown) => PyProxyShuttingDownError::new_err(args),
+                    Some(ErrorCode::Busy) => PyProxyBusyError::new_err(args),
+                    None => PyProxyServerError::new_err(args),
+                }
+            }
         }
     }
 }
@@ -1,6 +1,7 @@
 use std::result;
 
-use super::MessageType;
+use super::mainstream::ErrorCode;
+use super::{Feature, MessageType};
 
 #[derive(Debug)]
 pub enum Error {
@@ -8,7 +9,10 @@ pub enum Error {
     UnrecognisedMessageType(u8),
     FailedDeserialze(bincode::Error),
     UnexpectedMessageType(MessageType),
+    // A request used a feature the hello didn't enable
+    FeatureNotNegotiated(Feature),
     Deserialize(bincode::Error),
+    NoCommonVersion,
 }
 
 impl Error {
@@ -22,11 +26,38 @@ impl Error {
             Error::UnexpectedMessageType(msg_type) => {
                 format!("message type invalid here {:?}", msg_type)
             }
+            Error::FeatureNotNegotiated(feature) => {
+                format!("feature {} wasn't negotiated in the hello", feature.name())
+            }
             Error::Deserialize(err) => format!("failed to deserialize message {:?}", err),
+            Error::NoCommonVersion => {
+                format!(
+                    "no protocol version in common, server supports {:?}",
+                    super::SUPPORTED_VERSIONS
+                )
+            }
         }
     }
 }
 
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::WrongVersion(_) | Error::NoCommonVersion => ErrorCode::UnsupportedVersion,
+            Error::UnrecognisedMessageType(_) => ErrorCode::UnrecognisedMessageType,
+            Error::UnexpectedMessageType(_) | Error::FeatureNotNegotiated(_) => {
+                ErrorCode::UnexpectedMessageType
+            }
+            Error::FailedDeserialze(_) | Error::Deserialize(_) => ErrorCode::BadMessage,
+        }
+    }
+
+    // Fatal errors leave the stream unusable, others only fail one request
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.code(), ErrorCode::UnsupportedVersion)
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
 impl From<bincode::Error> for Error {
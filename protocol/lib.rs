@@ -1,10 +1,15 @@
 mod errors;
 pub use errors::{Error, Result};
 pub mod mainstream;
-pub use mainstream::{CodePickle, CodeString, ResponseCodePickle, ResponseCodeString};
+pub use mainstream::{
+    Cancel, CancelOutcome, CodePickle, CodeString, ErrorCode, ErrorResponse, EvalMode, Namespace,
+    PickleFormat, ResponseCancel, ResponseCodePickle, ResponseCodeString, ReturnLocals,
+};
 pub mod outputstream;
 
-pub const VERSION: u8 = 0;
+pub const VERSION: u8 = 1;
+// Versions the server accepts, version 0 clients send an empty hello
+pub const SUPPORTED_VERSIONS: [u8; 2] = [0, 1];
 pub const REQUEST_HEADER_SIZE: usize = 8;
 pub const RESPONSE_HEADER_SIZE: usize = 12;
 pub const SESSION_ID_LENGTH: usize = 16;
@@ -25,6 +30,8 @@ pub enum MessageType {
     Hello,
     CodeString,
     CodePickle,
+    Error,
+    Cancel,
 }
 
 #[derive(Debug)]
@@ -32,28 +39,30 @@ pub enum RequestMessage {
     Hello(RequestClientHello),
     CodeString(CodeString),
     CodePickle(CodePickle),
+    Cancel(Cancel),
 }
 
 #[derive(Debug)]
 pub enum ResponseMessage {
     Hello(ResponseClientHello),
-    CodeString(ResponseCodePickle),
-    CodePickle(ResponseCodeString),
+    CodeString(ResponseCodeString),
+    CodePickle(ResponseCodePickle),
+    Error(ErrorResponse),
+    Cancel(ResponseCancel),
 }
 
 impl ResponseMessage {
     pub fn is_hello(&self) -> bool {
-        match self {
-            ResponseMessage::Hello(_) => true,
-            _ => false,
-        }
+        matches!(self, ResponseMessage::Hello(_))
     }
 
-    pub fn future_id<'s>(&'s self) -> &'s str {
+    pub fn future_id(&self) -> &str {
         match self {
             ResponseMessage::Hello(_) => "000000",
             ResponseMessage::CodeString(s) => &s.future_id,
             ResponseMessage::CodePickle(s) => &s.future_id,
+            ResponseMessage::Error(e) => e.future_id.as_deref().unwrap_or("000000"),
+            ResponseMessage::Cancel(c) => &c.future_id,
         }
     }
 }
@@ -64,7 +73,35 @@ impl RequestMessage {
             RequestMessage::Hello(_) => None,
             RequestMessage::CodeString(s) => Some(&s.future_id),
             RequestMessage::CodePickle(s) => Some(&s.future_id),
+            RequestMessage::Cancel(c) => Some(&c.future_id),
+        }
+    }
+
+    pub fn timeout_ms(&self) -> Option<u64> {
+        match self {
+            RequestMessage::CodeString(s) => s.timeout_ms,
+            RequestMessage::CodePickle(s) => s.timeout_ms,
+            _ => None,
+        }
+    }
+
+    // Optional features the request uses, each must have been negotiated
+    pub fn features(&self) -> Vec<Feature> {
+        let (namespace, return_locals) = match self {
+            RequestMessage::Hello(_) => return vec![],
+            RequestMessage::Cancel(_) => return vec![Feature::Cancel],
+            RequestMessage::CodeString(s) => (s.namespace, &s.return_locals),
+            RequestMessage::CodePickle(s) => (s.namespace, &s.return_locals),
+        };
+
+        let mut features = vec![];
+        if !matches!(namespace, Namespace::Atom) {
+            features.push(Feature::StatefulSessions);
+        }
+        if !matches!(return_locals, ReturnLocals::Nothing) {
+            features.push(Feature::ReturnLocals);
         }
+        features
     }
 }
 
@@ -77,11 +114,50 @@ pub fn new_req<T: serde::Serialize>(msg_type: MessageType, msg_sub_type: u8, msg
     msg
 }
 
+pub fn new_response<T: serde::Serialize>(
+    version: u8,
+    msg_type: MessageType,
+    msg_sub_type: u8,
+    seq_num: u32,
+    msg: T,
+) -> Vec<u8> {
+    let payload = bincode::serialize(&msg).expect("couldn't serialize response message");
+    let mut header = ResponseMessageHeader::new(msg_type, msg_sub_type, payload.len(), seq_num);
+    header.version = version;
+    let mut msg = Vec::with_capacity(payload.len() + RESPONSE_HEADER_SIZE);
+    msg.extend(&header.into_buf());
+    msg.extend(&payload);
+    msg
+}
+
 pub fn read_req(header: RequestMessageHeader, body: &[u8]) -> Result<RequestMessage> {
+    if header.version == 0 {
+        return read_req_v0(header, body);
+    }
+
     match header.msg_type {
-        MessageType::Hello => Ok(RequestMessage::Hello(RequestClientHello {})),
+        MessageType::Hello => Ok(RequestMessage::Hello(bincode::deserialize(body)?)),
         MessageType::CodeString => Ok(RequestMessage::CodeString(bincode::deserialize(body)?)),
         MessageType::CodePickle => Ok(RequestMessage::CodePickle(bincode::deserialize(body)?)),
+        MessageType::Cancel => Ok(RequestMessage::Cancel(bincode::deserialize(body)?)),
+        MessageType::Error => Err(Error::UnexpectedMessageType(header.msg_type)),
+    }
+}
+
+fn read_req_v0(header: RequestMessageHeader, body: &[u8]) -> Result<RequestMessage> {
+    match header.msg_type {
+        MessageType::Hello => Ok(RequestMessage::Hello(RequestClientHello::v0())),
+        MessageType::CodeString => {
+            let msg: mainstream::CodeStringV0 = bincode::deserialize(body)?;
+            Ok(RequestMessage::CodeString(msg.into()))
+        }
+        MessageType::CodePickle => {
+            let msg: mainstream::CodePickleV0 = bincode::deserialize(body)?;
+            Ok(RequestMessage::CodePickle(msg.into()))
+        }
+        MessageType::Error | MessageType::Cancel => {
+            Err(Error::UnexpectedMessageType(header.msg_type))
+        }
     }
 }
 
@@ -91,6 +167,8 @@ impl MessageType {
             MessageType::Hello => 1,
             MessageType::CodeString => 2,
             MessageType::CodePickle => 3,
+            MessageType::Error => 4,
+            MessageType::Cancel => 5,
         }
     }
 
@@ -99,6 +177,8 @@ impl MessageType {
             1 => Ok(MessageType::Hello),
             2 => Ok(MessageType::CodeString),
             3 => Ok(MessageType::CodePickle),
+            4 => Ok(MessageType::Error),
+            5 => Ok(MessageType::Cancel),
             _ => Err(Error::UnrecognisedMessageType(b)),
         }
     }
@@ -109,7 +189,7 @@ impl RequestMessageHeader {
         Self {
             zero: 0,
             version: VERSION,
-            msg_type: msg_type,
+            msg_type,
             msg_sub_type,
             msg_len: (msg_len as u32).to_be_bytes(),
         }
@@ -133,7 +213,7 @@ impl RequestMessageHeader {
     }
 
     pub fn from_buf(buf: [u8; REQUEST_HEADER_SIZE]) -> Result<Self> {
-        if buf[1] != VERSION {
+        if !SUPPORTED_VERSIONS.contains(&buf[1]) {
             return Err(Error::WrongVersion(buf[1]));
         }
 
@@ -152,6 +232,8 @@ pub fn read_response(header: ResponseMessageHeader, body: &[u8]) -> Result<Respo
         MessageType::Hello => Ok(ResponseMessage::Hello(read_msg(body)?)),
         MessageType::CodeString => Ok(ResponseMessage::CodeString(read_msg(body)?)),
         MessageType::CodePickle => Ok(ResponseMessage::CodePickle(read_msg(body)?)),
+        MessageType::Error => Ok(ResponseMessage::Error(read_msg(body)?)),
+        MessageType::Cancel => Ok(ResponseMessage::Cancel(read_msg(body)?)),
     }
 }
 
@@ -207,7 +289,7 @@ impl ResponseMessageHeader {
             seq_num: [buf[8], buf[9], buf[10], buf[11]],
         };
 
-        if header.version != VERSION {
+        if !SUPPORTED_VERSIONS.contains(&header.version) {
             return Err(Error::WrongVersion(header.version));
         }
 
@@ -227,16 +309,98 @@ pub struct ServerPushHeader {
     pub msg_type: u8,
 }
 
+// Optional protocol features, negotiated in the hello exchange
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Feature {
+    StatefulSessions,
+    ReturnLocals,
+    Cancel,
+}
+
+impl Feature {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Feature::StatefulSessions => 1,
+            Feature::ReturnLocals => 2,
+            Feature::Cancel => 3,
+        }
+    }
+
+    // Unknown features are None, they may come from a newer peer
+    pub fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(Feature::StatefulSessions),
+            2 => Some(Feature::ReturnLocals),
+            3 => Some(Feature::Cancel),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::StatefulSessions => "stateful-sessions",
+            Feature::ReturnLocals => "return-locals",
+            Feature::Cancel => "cancel",
+        }
+    }
+}
+
+pub fn features_from_u8(features: &[u8]) -> Vec<Feature> {
+    features
+        .iter()
+        .filter_map(|f| Feature::from_u8(*f))
+        .collect()
+}
+
+pub fn features_as_u8(features: &[Feature]) -> Vec<u8> {
+    features.iter().map(|f| f.as_u8()).collect()
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct RequestClientHello {}
+pub struct RequestClientHello {
+    // Protocol versions the client speaks
+    pub versions: Vec<u8>,
+
+    // Features the client would like enabled
+    pub features: Vec<u8>,
+}
 
 impl RequestClientHello {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(features: &[Feature]) -> Self {
+        Self {
+            versions: vec![VERSION],
+            features: features_as_u8(features),
+        }
+    }
+
+    // Version 0 clients send an empty hello and know no features
+    pub fn v0() -> Self {
+        Self {
+            versions: vec![0],
+            features: vec![],
+        }
     }
 
     pub fn into_buf(self) -> Vec<u8> {
-        vec![]
+        bincode::serialize(&self).expect("couldn't serialize RequestClientHello")
+    }
+
+    // Pick the highest version we both speak and the features we both know
+    pub fn negotiate(&self, server_features: &[Feature]) -> Result<(u8, Vec<Feature>)> {
+        let version = self
+            .versions
+            .iter()
+            .filter(|v| SUPPORTED_VERSIONS.contains(v))
+            .max()
+            .copied()
+            .ok_or(Error::NoCommonVersion)?;
+
+        let features = features_from_u8(&self.features)
+            .into_iter()
+            .filter(|f| server_features.contains(f))
+            .collect();
+
+        Ok((version, features))
     }
 }
 
@@ -250,4 +414,136 @@ pub struct ResponseClientHello {
 
     // token DNS + port
     pub output_addr: String,
+
+    // Negotiated protocol version
+    pub version: u8,
+
+    // Negotiated features
+    pub features: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(versions: &[u8], features: &[u8]) -> RequestClientHello {
+        RequestClientHello {
+            versions: versions.to_vec(),
+            features: features.to_vec(),
+        }
+    }
+
+    fn v0_header(msg_type: u8, body: &[u8]) -> RequestMessageHeader {
+        let len = (body.len() as u32).to_be_bytes();
+        RequestMessageHeader::from_buf([0, 0, msg_type, 0, len[0], len[1], len[2], len[3]])
+            .expect("version 0 is supported")
+    }
+
+    #[test]
+    fn negotiate_picks_highest_common_version() {
+        let (version, _) = hello(&[0, 1], &[]).negotiate(&[]).unwrap();
+        assert_eq!(version, 1);
+
+        // Versions from a newer client we don't speak are passed over
+        let (version, _) = hello(&[0, 1, 9], &[]).negotiate(&[]).unwrap();
+        assert_eq!(version, 1);
+
+        let (version, _) = RequestClientHello::v0().negotiate(&[]).unwrap();
+        assert_eq!(version, 0);
+    }
+
+    #[test]
+    fn negotiate_fails_without_common_version() {
+        let res = hello(&[9], &[]).negotiate(&[]);
+        assert!(matches!(res, Err(Error::NoCommonVersion)));
+
+        let res = hello(&[], &[]).negotiate(&[]);
+        assert!(matches!(res, Err(Error::NoCommonVersion)));
+    }
+
+    #[test]
+    fn negotiate_keeps_features_both_know() {
+        let server = [Feature::StatefulSessions, Feature::Cancel];
+        // 2 is a feature the server doesn't offer, 42 one it doesn't know
+        let (_, features) = hello(&[1], &[3, 2, 42, 1]).negotiate(&server).unwrap();
+        assert_eq!(features, vec![Feature::Cancel, Feature::StatefulSessions]);
+
+        let (_, features) = RequestClientHello::v0().negotiate(&server).unwrap();
+        assert!(features.is_empty());
+    }
+
+    #[test]
+    fn read_v0_hello_ignores_body() {
+        let header = v0_header(1, &[]);
+        match read_req(header, &[]).unwrap() {
+            RequestMessage::Hello(hello) => {
+                assert_eq!(hello.versions, vec![0]);
+                assert!(hello.features.is_empty());
+            }
+            msg => panic!("expected a hello, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn read_v0_code_string() {
+        let body = bincode::serialize(&mainstream::CodeStringV0 {
+            future_id: String::from("f1"),
+            code: String::from("x = 1"),
+            locals: vec![1],
+            globals: vec![2],
+        })
+        .unwrap();
+
+        let req = read_req(v0_header(2, &body), &body).unwrap();
+        // Version 0 clients negotiate no features, nor need any
+        assert!(req.features().is_empty());
+        match req {
+            RequestMessage::CodeString(msg) => {
+                assert_eq!(msg.future_id, "f1");
+                assert_eq!(msg.code, "x = 1");
+                assert!(matches!(msg.mode, EvalMode::Exec));
+                assert!(matches!(msg.namespace, Namespace::Atom));
+                assert!(matches!(msg.return_locals, ReturnLocals::Nothing));
+                assert_eq!(msg.timeout_ms, None);
+                assert_eq!((msg.locals, msg.globals), (vec![1], vec![2]));
+            }
+            msg => panic!("expected a code string, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn read_v0_code_pickle() {
+        let body = bincode::serialize(&mainstream::CodePickleV0 {
+            future_id: String::from("f2"),
+            pickle: vec![0x80, 0x04],
+            locals: vec![],
+            globals: vec![],
+        })
+        .unwrap();
+
+        match read_req(v0_header(3, &body), &body).unwrap() {
+            RequestMessage::CodePickle(msg) => {
+                assert_eq!(msg.future_id, "f2");
+                assert!(matches!(msg.format, PickleFormat::Pickle));
+                assert_eq!(msg.py_version, (0, 0));
+                assert!(matches!(msg.namespace, Namespace::Atom));
+                assert_eq!(msg.pickle, vec![0x80, 0x04]);
+            }
+            msg => panic!("expected a code pickle, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn read_v0_rejects_later_message_types() {
+        for msg_type in [4, 5] {
+            let res = read_req(v0_header(msg_type, &[]), &[]);
+            assert!(matches!(res, Err(Error::UnexpectedMessageType(_))));
+        }
+    }
+
+    #[test]
+    fn read_v0_rejects_short_body() {
+        let body = bincode::serialize(&String::from("f3")).unwrap();
+        assert!(read_req(v0_header(2, &body), &body).is_err());
+    }
 }
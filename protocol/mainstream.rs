@@ -1,14 +1,65 @@
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone)]
+pub enum EvalMode {
+    // Single expression, value is returned
+    Eval,
+    // Block of statements, None is returned
+    Exec,
+    // Single interactive statement, expression values are printed
+    Single,
+    // Block of statements, value of a trailing expression is returned
+    Last,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone)]
+pub enum Namespace {
+    // locals and globals are used for this atom only
+    Atom,
+    // globals are merged into the session namespace, which outlives the atom
+    SessionMerge,
+    // globals replace the session namespace, which outlives the atom
+    SessionReplace,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum ReturnLocals {
+    Nothing,
+    // Every local which can be pickled, except __builtins__
+    All,
+    // Only these names, those not defined are skipped
+    Names(Vec<String>),
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct CodeString {
     pub future_id: String,
+    pub mode: EvalMode,
+    pub namespace: Namespace,
+    pub return_locals: ReturnLocals,
+    // Interrupt the atom after this long, the server may lower it
+    pub timeout_ms: Option<u64>,
     pub code: String,
     pub locals: Vec<u8>,
     pub globals: Vec<u8>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone)]
+pub enum PickleFormat {
+    // pickle.dumps of a callable
+    Pickle,
+    // marshal.dumps of a code object
+    Marshal,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct CodePickle {
     pub future_id: String,
+    pub format: PickleFormat,
+    // (major, minor) version of the client interpreter
+    pub py_version: (u8, u8),
+    pub namespace: Namespace,
+    pub return_locals: ReturnLocals,
+    // Interrupt the atom after this long, the server may lower it
+    pub timeout_ms: Option<u64>,
     pub pickle: Vec<u8>,
     pub locals: Vec<u8>,
     pub globals: Vec<u8>,
@@ -24,10 +75,175 @@ pub enum PythonResult {
 pub struct ResponseCodeString {
     pub future_id: String,
     pub py_result: PythonResult,
+    // Pickled dict of post-execution locals, if requested
+    pub locals: Option<Vec<u8>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct ResponseCodePickle {
     pub future_id: String,
     pub py_result: PythonResult,
+    // Pickled dict of post-execution locals, if requested
+    pub locals: Option<Vec<u8>>,
+}
+
+// Machine readable reason for an ErrorResponse
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    UnrecognisedMessageType,
+    UnexpectedMessageType,
+    BadMessage,
+    Cancelled,
+    Timeout,
+    ShuttingDown,
+    Busy,
+}
+
+impl ErrorCode {
+    pub fn as_u16(self) -> u16 {
+        match self {
+            ErrorCode::UnsupportedVersion => 1,
+            ErrorCode::UnrecognisedMessageType => 2,
+            ErrorCode::UnexpectedMessageType => 3,
+            ErrorCode::BadMessage => 4,
+            ErrorCode::Cancelled => 5,
+            ErrorCode::Timeout => 6,
+            ErrorCode::ShuttingDown => 7,
+            ErrorCode::Busy => 8,
+        }
+    }
+
+    // Unknown codes are None, they may come from a newer server
+    pub fn from_u16(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(ErrorCode::UnsupportedVersion),
+            2 => Some(ErrorCode::UnrecognisedMessageType),
+            3 => Some(ErrorCode::UnexpectedMessageType),
+            4 => Some(ErrorCode::BadMessage),
+            5 => Some(ErrorCode::Cancelled),
+            6 => Some(ErrorCode::Timeout),
+            7 => Some(ErrorCode::ShuttingDown),
+            8 => Some(ErrorCode::Busy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    // ErrorCode as u16
+    pub code: u16,
+    pub reason: String,
+    // Set when the error belongs to a single request
+    pub future_id: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, reason: String, future_id: Option<String>) -> Self {
+        Self {
+            code: code.as_u16(),
+            reason,
+            future_id,
+        }
+    }
+
+    pub fn code(&self) -> Option<ErrorCode> {
+        ErrorCode::from_u16(self.code)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Cancel {
+    // future_id of the atom to cancel
+    pub future_id: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CancelOutcome {
+    // Atom was still queued, it will never run
+    BeforeExecution,
+    // Atom was running, KeyboardInterrupt was raised in it
+    DuringExecution,
+    // Atom has already finished, or was never sent
+    NotFound,
+}
+
+impl CancelOutcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            CancelOutcome::BeforeExecution => "before-execution",
+            CancelOutcome::DuringExecution => "during-execution",
+            CancelOutcome::NotFound => "not-found",
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ResponseCancel {
+    pub future_id: String,
+    pub outcome: CancelOutcome,
+}
+
+// Every request body starts with its future_id, so we can usually
+// attribute a request we fail to deserialize
+#[derive(serde::Deserialize)]
+struct FutureIdPrefix {
+    future_id: String,
+}
+
+pub fn peek_future_id(body: &[u8]) -> Option<String> {
+    bincode::deserialize::<FutureIdPrefix>(body)
+        .ok()
+        .map(|p| p.future_id)
+}
+
+// Version 0 layouts, kept so version 0 clients keep working
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct CodeStringV0 {
+    pub future_id: String,
+    pub code: String,
+    pub locals: Vec<u8>,
+    pub globals: Vec<u8>,
+}
+
+impl From<CodeStringV0> for CodeString {
+    fn from(msg: CodeStringV0) -> Self {
+        Self {
+            future_id: msg.future_id,
+            mode: EvalMode::Exec,
+            namespace: Namespace::Atom,
+            return_locals: ReturnLocals::Nothing,
+            timeout_ms: None,
+            code: msg.code,
+            locals: msg.locals,
+            globals: msg.globals,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct CodePickleV0 {
+    pub future_id: String,
+    pub pickle: Vec<u8>,
+    pub locals: Vec<u8>,
+    pub globals: Vec<u8>,
+}
+
+impl From<CodePickleV0> for CodePickle {
+    fn from(msg: CodePickleV0) -> Self {
+        Self {
+            future_id: msg.future_id,
+            format: PickleFormat::Pickle,
+            // Version 0 clients never sent their interpreter version
+            py_version: (0, 0),
+            namespace: Namespace::Atom,
+            return_locals: ReturnLocals::Nothing,
+            timeout_ms: None,
+            pickle: msg.pickle,
+            locals: msg.locals,
+            globals: msg.globals,
+        }
+    }
 }
@@ -2,6 +2,11 @@ use super::{Error, Result};
 
 pub const HEADER_SIZE: usize = 5;
 
+// Sent as the sub type of the client hello. Clients sending 0 get bare lines,
+// later versions get each line in a Frame, from 2 output with no newline comes as chunks,
+// from 3 output the server couldn't hold comes as Dropped rather than a notice.
+pub const FRAME_VERSION: u8 = 3;
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct ClientHello {
     pub stream_token: String,
@@ -16,6 +21,13 @@ pub fn read_hello(body: &[u8]) -> Result<ClientHello> {
 pub enum MessageType {
     Stdout,
     Stderr,
+    // From the server about the session, e.g. its worker exited
+    Notice,
+    // Written without a newline, the next frame of the same stream continues it
+    StdoutChunk,
+    StderrChunk,
+    // Lines the server dropped before sending, the data says how many
+    Dropped,
 }
 
 impl MessageType {
@@ -23,6 +35,10 @@ impl MessageType {
         match self {
             MessageType::Stdout => 1,
             MessageType::Stderr => 2,
+            MessageType::Notice => 3,
+            MessageType::StdoutChunk => 4,
+            MessageType::StderrChunk => 5,
+            MessageType::Dropped => 6,
         }
     }
 
@@ -30,6 +46,10 @@ impl MessageType {
         match b {
             1 => Ok(MessageType::Stdout),
             2 => Ok(MessageType::Stderr),
+            3 => Ok(MessageType::Notice),
+            4 => Ok(MessageType::StdoutChunk),
+            5 => Ok(MessageType::StderrChunk),
+            6 => Ok(MessageType::Dropped),
             _ => Err(Error::UnrecognisedMessageType(b)),
         }
     }
@@ -66,3 +86,25 @@ pub fn new_msg(header: MessageHeader, data: &[u8]) -> Vec<u8> {
     ans.extend(data);
     ans
 }
+
+// Body of each message from FRAME_VERSION 1
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Frame {
+    // Request which wrote the line, None for notices
+    pub future_id: Option<String>,
+    // Counts up from 0 across the session's stdout, stderr and notices
+    pub seq: u64,
+    // Server time the line was written, microseconds since the unix epoch
+    pub ts: u64,
+    pub data: Vec<u8>,
+}
+
+pub fn new_frame(msg_type: MessageType, frame: &Frame) -> Vec<u8> {
+    let body = bincode::serialize(frame).expect("couldn't serialize output frame");
+    new_msg(MessageHeader::new(msg_type, body.len()), &body)
+}
+
+pub fn read_frame(body: &[u8]) -> Result<Frame> {
+    let frame = bincode::deserialize(body)?;
+    Ok(frame)
+}
@@ -9,7 +9,18 @@ from pyproxy_client import (
     PyProxyIOError,
     PyProxyProtocolError,
     PyProxyClosedSessionError,
+    PyProxyWorkerLostError,
     PyProxyRemoteExceptionPickle,
+    PyProxyFutureTimeout,
+    PyProxyServerError,
+    PyProxyUnsupportedVersionError,
+    PyProxyUnrecognisedMessageError,
+    PyProxyUnexpectedMessageError,
+    PyProxyBadMessageError,
+    PyProxyCancelledError,
+    PyProxyExecutionTimeoutError,
+    PyProxyShuttingDownError,
+    PyProxyBusyError,
 )
 
 
@@ -24,5 +35,16 @@ __all__ = [
     'PyProxyIOError',
     'PyProxyProtocolError',
     'PyProxyClosedSessionError',
+    'PyProxyWorkerLostError',
     'PyProxyRemoteExceptionPickle',
+    'PyProxyFutureTimeout',
+    'PyProxyServerError',
+    'PyProxyUnsupportedVersionError',
+    'PyProxyUnrecognisedMessageError',
+    'PyProxyUnexpectedMessageError',
+    'PyProxyBadMessageError',
+    'PyProxyCancelledError',
+    'PyProxyExecutionTimeoutError',
+    'PyProxyShuttingDownError',
+    'PyProxyBusyError',
 ]
@@ -21,20 +21,42 @@ class Future:
         self._id = id
         self._inner_fut = inner_fut
 
-    def wait(self, timeout=None):
+    def wait(self, timeout=None, with_locals=False):
         """
         wait for a future to complete
         this will also raise any exception
         the future wishes to raise
 
+        if with_locals is True we return a tuple
+        (return value, locals) where locals is the dict
+        requested by eval(return_locals=...), or None
+
         We can call wait as many times as we like
         it will reliably produce the same behaviour
         once done
         """
         try:
-            return self._inner_fut.wait(timeout)
+            ret = pickle.loads(self._inner_fut.wait(timeout))
+            if not with_locals:
+                return ret
+
+            locs = self._inner_fut.wait_locals(timeout)
+            return ret, (pickle.loads(locs) if locs is not None else None)
         except PyProxyRemoteExceptionPickle as exc:
-            raise pickle.loads(exc[0])
+            raise pickle.loads(exc.args[0])
+
+    def cancel(self, timeout=None):
+        """
+        cancel the future on the server
+
+        returns "before-execution" if the code never ran,
+        wait will then raise PyProxyCancelledError.
+        returns "during-execution" if the code was running,
+        KeyboardInterrupt is raised in it and wait raises
+        whatever the code did with that.
+        returns "not-found" if the future had already finished
+        """
+        return self._inner_fut.cancel(timeout)
 
     def is_done(self):
         """
@@ -42,7 +64,7 @@ class Future:
         this function is exception safe
         """
 
-        self._inner_fut.try_recv()
+        return self._inner_fut.is_done()
 
 
     def wait_no_except(self, timeout=None):
@@ -1,8 +1,11 @@
 #!/usr/bin/env python
 # -*- coding: utf-8 -*-
+import marshal
 import pickle
+from collections import deque
 from functools import partial
 from time import sleep
+from types import CodeType
 
 from pyproxy_client import PyProxyClient, new_simple_connection
 
@@ -13,9 +16,13 @@ class PyProxySession:
     """
     PyProxySession is a class for establishing the mainstream
     with the PyProxy Server.
+
+    if stateful is True the server keeps one namespace for the session,
+    names assigned by one eval are visible to the next
     """
-    def __init__(self, addr="localhost:9000"):
+    def __init__(self, addr="localhost:9000", stateful=False):
         self._addr = addr
+        self._stateful = stateful
 
         # block - waitint for client conenction
         # raise exception if we fail
@@ -38,7 +45,7 @@ class PyProxySession:
         connect will spawn a background thread
         we return a RemoteProcess object
         """
-        client = PyProxyClient(self._client_conn)
+        client = PyProxyClient(self._client_conn, stateful=self._stateful)
         return RemoteProcess(client)
 
     def __exit__(self, exc_typ, exc_val, trcb):
@@ -52,35 +59,86 @@ class RemoteProcess:
     """
     def __init__(self, client):
         self._client = client
+        # lines read while waiting on another future's output
+        self._held = deque()
 
-    def output(self, future=None):
+    def output(self, future=None, with_meta=False, raw=False):
         """
         output retrieves stdout and stderr line from the remote process
+
+        kind 3 is a notice from the server about the session,
+        e.g. that the worker running it exited
+
+        kinds 4 and 5 are stdout and stderr chunks, output the server
+        sent without waiting for a newline (a prompt, a progress bar,
+        an over long line or binary data). The next line or chunk of
+        the same stream continues it
+
+        kind 6 says how many lines the server dropped, output written
+        before the output stream connected is held for it but only so much,
+        as is output this side hasn't read yet
+
+        if raw is True lines keep their newline and chunks come as
+        kinds 1 and 2, so joining a kind's lines gives back exactly
+        the bytes written
+
+        if future is given we block until it completes, yielding only
+        the lines it wrote (and notices) in the order it wrote them.
+        lines of other futures are kept for later calls
+
+        if with_meta is True we yield (kind, line, future_id, seq, ts),
+        seq numbers the session's output in the order it reached the
+        server, carrying on across reconnects. stdout and stderr are read
+        from separate pipes, so lines written to each at nearly the same
+        moment may be numbered either way round. ts is the server's
+        time.time() when the line was written, future_id is None
+        for notices
         """
 
+        def wanted(out):
+            return future is None or out[2] in (None, future.id)
+
+        def shape(out):
+            if raw and out[0] in (1, 2):
+                out = (out[0], out[1] + b"\n") + out[2:]
+            elif raw and out[0] in (4, 5):
+                out = (out[0] - 3,) + out[1:]
+            return out if with_meta else out[:2]
+
+        held, self._held = self._held, deque()
+        for out in held:
+            if wanted(out):
+                yield shape(out)
+            else:
+                self._held.append(out)
+
         break_now = True
         if future:
             break_now = False
 
         while True:
             # out is a tuple
-            # first arg is 1 or 2 (stdout or stderr)
+            # first arg is 1 or 2 (stdout or stderr), 3 for a server notice,
+            # 4 or 5 for chunks, or 6 for lines dropped
             # second arg is a string for one pipe line
             out = self._client.next_output()
             if out:
-                yield out
+                if wanted(out):
+                    yield shape(out)
+                else:
+                    self._held.append(out)
                 continue
 
             if break_now:
                 break
 
             if future and future.is_done():
-                # break on next loop iteration
+                # output travels separately from the result,
+                # give the last lines a moment then break
                 break_now = True
 
-            if not break_now:
-                # sleep for 100ms then poll again
-                sleep(0.1)
+            # sleep for 100ms then poll again
+            sleep(0.1)
 
     def stdin(self, lines):
         """
@@ -91,13 +149,38 @@ class RemoteProcess:
         raise NotImplementedError("stdin not implemented")
 
 
-    def eval(self, code, locs=None, globs=None):
+    def eval(self, code, locs=None, globs=None, mode="exec",
+             replace_globals=False, return_locals=None, timeout_ms=None):
         """
         eval will execute a code object on the remote process
         code may be a str or a code object
 
-        if code_object is a string then we send to the server as a String
-        if code_object is a code_object then we attempt to pickle
+        if code is a string then we send to the server as a String,
+        mode chooses how the server compiles it:
+            "eval" - a single expression, its value is returned
+            "exec" - a block of statements, None is returned (the default)
+            "single" - an interactive statement, expression values are printed
+            "last" - a block of statements, the value of a trailing
+                     expression is returned (as in a notebook cell)
+        if code is a code object then we marshal it, the server
+        evaluates it with locs and globs
+        if code is any other callable then we pickle it, the server
+        calls it with locs as keyword arguments
+
+        marshalled and pickled code must be run by the same
+        python (major, minor) version on the server
+
+        on a stateful session locs and globs are merged into the
+        session namespace, unless replace_globals is True in which
+        case globs replaces the session namespace
+
+        return_locals asks the server to send back the locals after
+        execution, True for every (picklable) local or a list of names,
+        see Future.wait(with_locals=True)
+
+        timeout_ms interrupts the code once it has run that long,
+        wait then raises PyProxyExecutionTimeoutError. The server has
+        a default and may lower the timeout to its maximum
         """
 
         id = future_id()
@@ -109,9 +192,21 @@ class RemoteProcess:
         globs = pickle.dumps(globs)
 
         if isinstance(code, str):
-            inner_fut = self._client.eval_str(id, code, locs, globs)
+            inner_fut = self._client.eval_str(
+                id, code, locs, globs, mode, replace_globals, return_locals,
+                timeout_ms)
+        elif isinstance(code, CodeType):
+            inner_fut = self._client.eval_pickle(
+                id, marshal.dumps(code), locs, globs, marshal=True,
+                replace_globals=replace_globals, return_locals=return_locals,
+                timeout_ms=timeout_ms)
+        elif callable(code):
+            inner_fut = self._client.eval_pickle(
+                id, pickle.dumps(code), locs, globs,
+                replace_globals=replace_globals, return_locals=return_locals,
+                timeout_ms=timeout_ms)
         else:
-            raise TypeError("code must be a string")
+            raise TypeError("code must be a string, code object or callable")
 
         return Future(id, inner_fut)
 
@@ -1,12 +1,13 @@
 use std::fs;
 use std::net::TcpListener;
 use std::os::unix::net::UnixListener;
-use std::process;
+use std::path;
 use std::rc::Rc;
 
 mod runmaster;
-use runmaster::{fatal_io_err, run_forever, Result, Worker};
+use runmaster::{fatal_io_err, run_forever, Result, Spawner};
 mod messages;
+mod signals;
 
 fn main() -> Result<()> {
     let cfg = runmaster::config::from_env().map(Rc::new)?;
@@ -24,12 +25,13 @@ fn main() -> Result<()> {
 
     let pid = unsafe { libc::getpid() };
     let mut sock_addr = cfg.rundir.clone();
-    sock_addr.push(&format!("{}", pid));
+    sock_addr.push(format!("{}", pid));
     fatal_io_err(
         "master failed to create run directory for unix socket",
         fs::create_dir_all(&sock_addr),
     )?;
     sock_addr.push("pyproxy.sock");
+    let _run_dir = RunDir(sock_addr.clone());
 
     // Open UNIX Socket
     let unix_listener = fatal_io_err(
@@ -37,24 +39,46 @@ fn main() -> Result<()> {
         UnixListener::bind(&sock_addr),
     )?;
 
-    // Spawn worker process pool
-    let mut workers = Vec::with_capacity(cfg.num_workers);
-    for _ in 0..cfg.num_workers {
-        let mut child = fatal_io_err(
-            "failed to spawn worker process",
-            process::Command::new(&cfg.workerbin)
-                .arg(&sock_addr)
-                .stdout(process::Stdio::piped())
-                .stderr(process::Stdio::piped())
-                .spawn(),
-        )?;
-
-        workers.push(Worker {
-            stdout: child.stdout.take().unwrap(),
-            stderr: child.stderr.take().unwrap(),
-            child,
-        });
+    let mut spawner = fatal_io_err(
+        "master failed to start spawning workers",
+        Spawner::new(&cfg, &sock_addr),
+    )?;
+
+    // Spawn worker process pool, the master scales it from here.
+    // Forked workers join it from the master's event loop.
+    let num_workers = cfg.num_workers.clamp(cfg.min_workers, cfg.max_workers);
+    let mut workers = Vec::with_capacity(num_workers);
+    for _ in 0..num_workers {
+        match spawner.spawn() {
+            Ok(w) => workers.extend(w),
+            Err(io_err) => {
+                // Don't leave those we've started to init
+                for w in workers {
+                    w.kill();
+                }
+                return fatal_io_err("failed to spawn worker process", Err(io_err));
+            }
+        }
     }
 
-    run_forever(cfg, main_listener, output_listener, unix_listener, workers)
+    run_forever(
+        cfg,
+        main_listener,
+        output_listener,
+        unix_listener,
+        spawner,
+        workers,
+    )
+}
+
+// Don't leave the socket or run directory behind, however we exit
+struct RunDir(path::PathBuf);
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).unwrap_or(());
+        if let Some(run_dir) = self.0.parent() {
+            fs::remove_dir(run_dir).unwrap_or(());
+        }
+    }
 }
@@ -1,8 +1,22 @@
 // Messages from worker to master
-use std::collections::HashMap;
+use std::time;
 
 pub const LOG_MESSAGE: u8 = 1;
-pub const PRINT_MESSAGE: u8 = 2;
+pub const OUTPUT_MESSAGE: u8 = 2;
+pub const SESSION_OPENED: u8 = 3;
+pub const SESSION_CLOSED: u8 = 4;
+pub const LOAD_MESSAGE: u8 = 5;
+pub const RECYCLE_MESSAGE: u8 = 6;
+
+// Messages from master to worker, with a SessionMessage. Each starts with its
+// nonzero type, where the request headers the master passes on start with zero.
+pub const PAUSE_OUTPUT: u8 = 7;
+pub const RESUME_OUTPUT: u8 = 8;
+// The master dispatches no more sessions to the worker, its session_id is empty
+pub const DISPATCH_STOPPED: u8 = 9;
+
+// From worker to master, for a session it was sent while draining
+pub const SESSION_RETURNED: u8 = 10;
 
 // Header is always five bytes, message type follow by 4 byte msg len
 
@@ -29,12 +43,55 @@ pub struct LogMessage {
     pub tags: Vec<(String, LogValue)>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputKind {
+    Stdout,
+    Stderr,
+}
+
+// A line an atom wrote, without its newline
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct OutputMessage {
+    pub session_id: String,
+    pub future_id: String,
+    pub kind: OutputKind,
+    // Written without a newline, what follows of the same kind continues it
+    pub chunk: bool,
+    // When the worker read it, see timestamp
+    pub ts: u64,
+    pub line: Vec<u8>,
+}
+
+// Tells the master which sessions a worker holds, and the worker whose output to pause
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SessionMessage {
+    pub session_id: String,
+}
+
+// Sent by a worker whenever its load changes
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct LoadMessage {
+    pub sessions: u32,
+    // Atoms queued or running
+    pub queued_atoms: u32,
+}
+
+// A session the worker didn't open, by the order it was sent in. The master
+// still holds the client's fd and dispatches it again.
 #[derive(serde::Serialize, serde::Deserialize)]
-pub struct PrintMessage {
-    pub message: String,
+pub struct ReturnedMessage {
+    pub nth: u64,
 }
 
-pub const NEW_REQUEST_START: &'static str =
-    "8b588b6fbb7eaa6a66da438c0dc1cced45c9c55cdf1eb137ba133ba1d7d95b5b";
-pub const NEW_REQUEST_END: &'static str =
-    "962375a5e9ffb94b822a69902f462e3394b33a51fbd17d9639cd0f6a9640268d";
+// Sent once by a worker past its recycle limits, it exits when its sessions close
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RecycleMessage {
+    pub reason: String,
+}
+
+// Microseconds since the unix epoch, as output frames carry it
+pub fn timestamp() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
@@ -0,0 +1,234 @@
+use std::time;
+
+use super::config::Config;
+use super::workerstream::WorkerStreams;
+
+// How often we look at the pool while it may scale
+pub const TICK: time::Duration = time::Duration::from_secs(1);
+
+pub enum Scale {
+    Hold,
+    // Spawn one more worker
+    Up,
+    // Drain and retire these idle workers, by pid
+    Down(Vec<u32>),
+}
+
+// Sizes the worker pool between min_workers and max_workers with load
+pub struct Autoscaler {
+    min_workers: usize,
+    max_workers: usize,
+    scale_up_sessions: usize,
+    scale_up_queued: usize,
+    idle_timeout: time::Duration,
+}
+
+impl Autoscaler {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            min_workers: cfg.min_workers,
+            max_workers: cfg.max_workers,
+            scale_up_sessions: cfg.scale_up_sessions,
+            scale_up_queued: cfg.scale_up_queued,
+            idle_timeout: cfg.idle_timeout,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_workers > self.min_workers
+    }
+
+    // pool counts workers running or waiting to be respawned,
+    // starting counts those which haven't connected yet
+    pub fn plan(
+        &self,
+        worker_streams: &WorkerStreams,
+        pool: usize,
+        starting: usize,
+        now: time::Instant,
+    ) -> Scale {
+        let (mut workers, mut sessions, mut queued) = (0, 0, 0);
+        for stream in worker_streams.live() {
+            let load = stream.load();
+            workers += 1;
+            sessions += load.sessions as usize;
+            queued += load.queued_atoms as usize;
+        }
+
+        // Wait for a new worker to take some load before adding another,
+        // and don't retire idle workers while the rest are busy
+        let pressure =
+            sessions > self.scale_up_sessions * workers || queued > self.scale_up_queued * workers;
+        if pressure {
+            return match pool < self.max_workers && starting == 0 {
+                true => Scale::Up,
+                false => Scale::Hold,
+            };
+        }
+
+        let idle: Vec<u32> = worker_streams
+            .live()
+            .filter(|s| {
+                s.idle_since()
+                    .is_some_and(|since| now.duration_since(since) >= self.idle_timeout)
+            })
+            .map(|s| s.pid())
+            .take(pool.saturating_sub(self.min_workers))
+            .collect();
+
+        if idle.is_empty() {
+            Scale::Hold
+        } else {
+            Scale::Down(idle)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use mio::net::UnixStream;
+    use mio::{Interest, Token};
+
+    use super::*;
+    use crate::messages::{self, LoadMessage};
+    use crate::runmaster::config::Dispatch;
+    use crate::runmaster::workerstream::WorkerStream;
+
+    // Workers reporting these loads, with the worker ends of their streams
+    fn workers(loads: &[(u32, u32)]) -> (WorkerStreams, Vec<UnixStream>) {
+        let mut streams = WorkerStreams::new(Dispatch::RoundRobin, false);
+        let mut peers = vec![];
+
+        for (n, (sessions, queued_atoms)) in loads.iter().enumerate() {
+            let (ours, mut theirs) = UnixStream::pair().unwrap();
+            let stream = WorkerStream::new(ours, Interest::READABLE).unwrap();
+
+            let msg = bincode::serialize(&LoadMessage {
+                sessions: *sessions,
+                queued_atoms: *queued_atoms,
+            })
+            .unwrap();
+            theirs.write_all(&[messages::LOAD_MESSAGE]).unwrap();
+            theirs.write_all(&(msg.len() as u32).to_be_bytes()).unwrap();
+            theirs.write_all(&msg).unwrap();
+            stream.read(&mut [0; 256]).unwrap();
+
+            streams.add(Token(n), stream);
+            peers.push(theirs);
+        }
+
+        (streams, peers)
+    }
+
+    fn idle_for(secs: u64) -> time::Instant {
+        time::Instant::now() + time::Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn scales_up_under_load() {
+        let scaler = Autoscaler::new(&Config {
+            min_workers: 2,
+            max_workers: 4,
+            ..Config::default()
+        });
+
+        // More sessions than scale_up_sessions per worker
+        let (streams, _peers) = workers(&[(5, 0), (4, 0)]);
+        assert!(matches!(
+            scaler.plan(&streams, 2, 0, idle_for(0)),
+            Scale::Up
+        ));
+
+        // More queued atoms than scale_up_queued per worker
+        let (streams, _peers) = workers(&[(1, 3), (1, 2)]);
+        assert!(matches!(
+            scaler.plan(&streams, 2, 0, idle_for(0)),
+            Scale::Up
+        ));
+
+        let (streams, _peers) = workers(&[(4, 2), (4, 2)]);
+        assert!(matches!(
+            scaler.plan(&streams, 2, 0, idle_for(0)),
+            Scale::Hold
+        ));
+    }
+
+    #[test]
+    fn holds_under_load_at_max_or_while_starting() {
+        let scaler = Autoscaler::new(&Config {
+            min_workers: 2,
+            max_workers: 4,
+            ..Config::default()
+        });
+        let (streams, _peers) = workers(&[(9, 0), (9, 0)]);
+
+        // A worker still starting up will take some of the load
+        assert!(matches!(
+            scaler.plan(&streams, 3, 1, idle_for(0)),
+            Scale::Hold
+        ));
+        // Respawns waiting count towards the pool
+        assert!(matches!(
+            scaler.plan(&streams, 4, 0, idle_for(0)),
+            Scale::Hold
+        ));
+    }
+
+    #[test]
+    fn retires_idle_workers_down_to_min() {
+        let scaler = Autoscaler::new(&Config {
+            min_workers: 1,
+            max_workers: 4,
+            ..Config::default()
+        });
+        let (streams, _peers) = workers(&[(0, 0), (0, 0), (0, 0)]);
+
+        match scaler.plan(&streams, 3, 0, idle_for(300)) {
+            Scale::Down(pids) => assert_eq!(pids.len(), 2),
+            _ => panic!("expected idle workers to be retired"),
+        }
+
+        // Not idle for long enough yet
+        assert!(matches!(
+            scaler.plan(&streams, 3, 0, idle_for(299)),
+            Scale::Hold
+        ));
+        // Already at min_workers
+        assert!(matches!(
+            scaler.plan(&streams, 1, 0, idle_for(300)),
+            Scale::Hold
+        ));
+    }
+
+    #[test]
+    fn keeps_idle_workers_while_others_are_busy() {
+        let scaler = Autoscaler::new(&Config {
+            min_workers: 1,
+            max_workers: 2,
+            ..Config::default()
+        });
+        let (streams, _peers) = workers(&[(9, 0), (0, 0)]);
+
+        assert!(matches!(
+            scaler.plan(&streams, 2, 0, idle_for(300)),
+            Scale::Hold
+        ));
+    }
+
+    #[test]
+    fn busy_workers_are_not_idle() {
+        let scaler = Autoscaler::new(&Config {
+            min_workers: 1,
+            max_workers: 4,
+            ..Config::default()
+        });
+        let (streams, _peers) = workers(&[(1, 0), (0, 0)]);
+
+        match scaler.plan(&streams, 2, 0, idle_for(300)) {
+            Scale::Down(pids) => assert_eq!(pids.len(), 1),
+            _ => panic!("expected the idle worker to be retired"),
+        }
+    }
+}
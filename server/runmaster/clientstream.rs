@@ -1,4 +1,4 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::os::fd::{AsRawFd, RawFd};
 
 use mio::event::Source;
@@ -10,13 +10,15 @@ pub struct ClientStream {
     stream: TcpStream,
     buffer: [u8; protocol::REQUEST_HEADER_SIZE],
     bytes_read: usize,
+    // Times a draining worker handed the session back
+    returns: u32,
 }
 
 #[derive(Debug)]
 pub enum ReadResult {
     Continue,
     Closed,
-    Error(io::Error),
+    Error,
     Done,
 }
 
@@ -26,6 +28,7 @@ impl ClientStream {
             stream,
             buffer: [0; protocol::REQUEST_HEADER_SIZE],
             bytes_read: 0,
+            returns: 0,
         }
     }
 
@@ -45,7 +48,7 @@ impl ClientStream {
                     }
                 }
             }
-            Err(io_err) => ReadResult::Error(io_err),
+            Err(_) => ReadResult::Error,
         }
     }
 
@@ -56,6 +59,30 @@ impl ClientStream {
     pub fn raw_fd(&self) -> RawFd {
         self.stream.as_raw_fd()
     }
+
+    // Count a worker handing the session back, returns the times it has been
+    pub fn returned(&mut self) -> u32 {
+        self.returns += 1;
+        self.returns
+    }
+
+    // No worker could take the session, tell the client why then close the connection.
+    // Version 0 clients don't know error responses, they only see it close.
+    pub fn reject(&mut self, code: protocol::ErrorCode, reason: String) {
+        let version = self.buffer[1];
+        if version > 0 && protocol::SUPPORTED_VERSIONS.contains(&version) {
+            let resp = protocol::ErrorResponse::new(code, reason, None);
+            let msg = protocol::new_response(version, protocol::MessageType::Error, 0, 1, resp);
+            // Nothing else has been written, a fresh socket has room for it
+            self.stream.write_all(&msg).unwrap_or(());
+        }
+        self.shutdown();
+    }
+
+    // Close the connection, even though the worker holds a copy of the fd
+    pub fn shutdown(&self) {
+        self.stream.shutdown(std::net::Shutdown::Both).unwrap_or(());
+    }
 }
 
 impl Source for ClientStream {
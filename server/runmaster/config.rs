@@ -1,17 +1,196 @@
 use std::env;
 use std::error;
+use std::fmt;
 use std::net;
 use std::path;
 use std::str::FromStr;
+use std::time;
 
 pub struct Config {
     pub bind_addr: net::SocketAddr,
     pub output_addr: net::SocketAddr,
     pub rundir: path::PathBuf,
+    // Workers started with, the pool then scales between min_workers and max_workers
     pub num_workers: usize,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    // Scale up once the average worker holds more sessions or queued atoms than these
+    pub scale_up_sessions: usize,
+    pub scale_up_queued: usize,
+    // Workers above min_workers without sessions for this long are drained
+    pub idle_timeout: time::Duration,
     pub workerbin: path::PathBuf,
+    // Give up once workers exit more than restart_limit times in restart_window
+    pub restart_limit: usize,
+    pub restart_window: time::Duration,
+    // How long workers have to finish in-flight atoms on shutdown
+    pub shutdown_grace: time::Duration,
+    // Output held for a session until its output stream attaches, the oldest lines go first
+    pub replay_bytes: usize,
+    pub replay_lines: usize,
+    // Output queued for a client's output stream before output_policy applies, on top
+    // of up to 64KiB already being written
+    pub output_buffer_bytes: usize,
+    pub output_policy: OutputPolicy,
+    pub dispatch: Dispatch,
+    pub worker_mode: WorkerMode,
+    pub worker_env: WorkerEnv,
 }
 
+// The python environment workers start in, passed down when we spawn them
+#[derive(Clone, Debug, Default)]
+pub struct WorkerEnv {
+    // Imported before the worker takes sessions, or once by the fork server template
+    pub preload_modules: Vec<String>,
+    // Run after the preload modules are imported
+    pub warmup_script: Option<path::PathBuf>,
+    // Prepended to sys.path
+    pub python_path: Vec<path::PathBuf>,
+    pub cwd: Option<path::PathBuf>,
+    // Set in the worker's environment, from PYPROXY_WORKER_ENV_<NAME>
+    pub env: Vec<(String, String)>,
+}
+
+// How the master picks a worker for each new session
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    RoundRobin,
+    LeastSessions,
+    LeastQueued,
+}
+
+impl FromStr for Dispatch {
+    type Err = UnknownDispatch;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Dispatch::RoundRobin),
+            "least-sessions" => Ok(Dispatch::LeastSessions),
+            "least-queued" => Ok(Dispatch::LeastQueued),
+            _ => Err(UnknownDispatch(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownDispatch(String);
+
+impl fmt::Display for UnknownDispatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown dispatch strategy {}, expected round-robin, least-sessions or least-queued",
+            self.0
+        )
+    }
+}
+
+impl error::Error for UnknownDispatch {}
+
+// How the master starts worker processes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WorkerMode {
+    // Exec the worker binary for each worker
+    Exec,
+    // Fork each worker from a template which has preloaded python
+    ForkServer,
+    // As ForkServer, but each worker only ever runs one session
+    ForkPerSession,
+}
+
+impl FromStr for WorkerMode {
+    type Err = UnknownWorkerMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exec" => Ok(WorkerMode::Exec),
+            "fork-server" => Ok(WorkerMode::ForkServer),
+            "fork-per-session" => Ok(WorkerMode::ForkPerSession),
+            _ => Err(UnknownWorkerMode(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownWorkerMode(String);
+
+impl fmt::Display for UnknownWorkerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown worker mode {}, expected exec, fork-server or fork-per-session",
+            self.0
+        )
+    }
+}
+
+impl error::Error for UnknownWorkerMode {}
+
+// What the master does with a session's output once its output stream is behind
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputPolicy {
+    DropOldest,
+    DropNewest,
+    // Stop reading the session's atoms' output, they block on their next write
+    Pause,
+}
+
+impl FromStr for OutputPolicy {
+    type Err = UnknownOutputPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OutputPolicy::DropOldest),
+            "drop-newest" => Ok(OutputPolicy::DropNewest),
+            "pause" => Ok(OutputPolicy::Pause),
+            _ => Err(UnknownOutputPolicy(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownOutputPolicy(String);
+
+impl fmt::Display for UnknownOutputPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown output policy {}, expected drop-oldest, drop-newest or pause",
+            self.0
+        )
+    }
+}
+
+impl error::Error for UnknownOutputPolicy {}
+
+#[derive(Debug)]
+pub struct NotADirectory;
+
+impl fmt::Display for NotADirectory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not a directory")
+    }
+}
+
+impl error::Error for NotADirectory {}
+
+#[derive(Debug)]
+pub struct WorkerBounds {
+    min_workers: usize,
+}
+
+impl fmt::Display for WorkerBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "max workers must be at least 1 and at least min workers ({})",
+            self.min_workers
+        )
+    }
+}
+
+impl error::Error for WorkerBounds {}
+
 impl Default for Config {
     fn default() -> Self {
         let bind = net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0));
@@ -30,12 +209,29 @@ impl Default for Config {
             output_addr: net::SocketAddr::new(bind, 9001),
             rundir,
             num_workers: 3,
+            min_workers: 3,
+            max_workers: 3,
+            scale_up_sessions: 4,
+            scale_up_queued: 2,
+            idle_timeout: time::Duration::from_secs(300),
             workerbin,
+            restart_limit: 5,
+            restart_window: time::Duration::from_secs(60),
+            shutdown_grace: time::Duration::from_secs(30),
+            replay_bytes: 1024 * 1024,
+            replay_lines: 10_000,
+            output_buffer_bytes: 4 * 1024 * 1024,
+            output_policy: OutputPolicy::DropOldest,
+            dispatch: Dispatch::RoundRobin,
+            worker_mode: WorkerMode::Exec,
+            worker_env: WorkerEnv::default(),
         }
     }
 }
 
+// Fields are read through Debug, when main returns the error
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct EnvError {
     pub env_var: String,
     pub env_val: String,
@@ -43,6 +239,7 @@ pub struct EnvError {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Error {
     pub errors: Vec<EnvError>,
 }
@@ -50,6 +247,9 @@ pub struct Error {
 pub fn from_env() -> Result<Config, Error> {
     let mut slf = Config::default();
     let mut errors = vec![];
+    // Both default to num_workers, a fixed size pool
+    let mut min_workers = None;
+    let mut max_workers = None;
 
     for (key, val) in env::vars() {
         match key.as_ref() {
@@ -91,10 +291,278 @@ pub fn from_env() -> Result<Config, Error> {
                 }
             },
 
-            _ => {}
+            "PYPROXY_MIN_WORKERS" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(n) => {
+                    min_workers = Some(n);
+                }
+            },
+
+            "PYPROXY_MAX_WORKERS" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(n) => {
+                    max_workers = Some(n);
+                }
+            },
+
+            "PYPROXY_SCALE_UP_SESSIONS" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(scale_up_sessions) => {
+                    slf.scale_up_sessions = scale_up_sessions;
+                }
+            },
+
+            "PYPROXY_SCALE_UP_QUEUED" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(scale_up_queued) => {
+                    slf.scale_up_queued = scale_up_queued;
+                }
+            },
+
+            "PYPROXY_IDLE_TIMEOUT_SECS" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(secs) => {
+                    slf.idle_timeout = time::Duration::from_secs(secs);
+                }
+            },
+
+            "PYPROXY_RESTART_LIMIT" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(restart_limit) => {
+                    slf.restart_limit = restart_limit;
+                }
+            },
+
+            "PYPROXY_RESTART_WINDOW_SECS" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(secs) => {
+                    slf.restart_window = time::Duration::from_secs(secs);
+                }
+            },
+
+            "PYPROXY_SHUTDOWN_GRACE_SECS" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(secs) => {
+                    slf.shutdown_grace = time::Duration::from_secs(secs);
+                }
+            },
+
+            "PYPROXY_OUTPUT_REPLAY_BYTES" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(replay_bytes) => {
+                    slf.replay_bytes = replay_bytes;
+                }
+            },
+
+            "PYPROXY_OUTPUT_REPLAY_LINES" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(replay_lines) => {
+                    slf.replay_lines = replay_lines;
+                }
+            },
+
+            "PYPROXY_OUTPUT_BUFFER_BYTES" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(output_buffer_bytes) => {
+                    slf.output_buffer_bytes = output_buffer_bytes;
+                }
+            },
+
+            "PYPROXY_OUTPUT_POLICY" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(output_policy) => {
+                    slf.output_policy = output_policy;
+                }
+            },
+
+            "PYPROXY_DISPATCH" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(dispatch) => {
+                    slf.dispatch = dispatch;
+                }
+            },
+
+            "PYPROXY_PRELOAD_MODULES" => {
+                slf.worker_env.preload_modules = val
+                    .split(',')
+                    .map(|module| module.trim())
+                    .filter(|module| !module.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+
+            // Workers may run somewhere else, so paths are made absolute here
+            "PYPROXY_WARMUP_SCRIPT" => match path::Path::new(&val).canonicalize() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(warmup_script) => {
+                    slf.worker_env.warmup_script = Some(warmup_script);
+                }
+            },
+
+            "PYPROXY_PYTHON_PATH" => match env::current_dir() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(cwd) => {
+                    slf.worker_env.python_path = env::split_paths(&val)
+                        .filter(|entry| !entry.as_os_str().is_empty())
+                        .map(|entry| cwd.join(entry))
+                        .collect();
+                }
+            },
+
+            "PYPROXY_WORKER_CWD" => match path::Path::new(&val).canonicalize() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(cwd) if !cwd.is_dir() => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(NotADirectory),
+                    });
+                }
+                Ok(cwd) => {
+                    slf.worker_env.cwd = Some(cwd);
+                }
+            },
+
+            "PYPROXY_WORKER_MODE" => match val.parse() {
+                Err(e) => {
+                    errors.push(EnvError {
+                        env_var: key.clone(),
+                        env_val: val.clone(),
+                        error: Box::new(e),
+                    });
+                }
+                Ok(worker_mode) => {
+                    slf.worker_mode = worker_mode;
+                }
+            },
+
+            _ => {
+                if let Some(name) = key.strip_prefix("PYPROXY_WORKER_ENV_") {
+                    slf.worker_env.env.push((name.to_owned(), val.clone()));
+                }
+            }
         }
     }
 
+    // Workers started in another directory must still find the worker binary,
+    // a bare name is looked up in PATH
+    if slf.worker_env.cwd.is_some()
+        && slf.workerbin.is_relative()
+        && slf.workerbin.components().count() > 1
+    {
+        if let Ok(cwd) = env::current_dir() {
+            slf.workerbin = cwd.join(&slf.workerbin);
+        }
+    }
+
+    slf.min_workers = min_workers.unwrap_or(slf.num_workers);
+    slf.max_workers = max_workers.unwrap_or(slf.num_workers.max(slf.min_workers));
+    if slf.max_workers < slf.min_workers || slf.max_workers == 0 {
+        errors.push(EnvError {
+            env_var: String::from("PYPROXY_MAX_WORKERS"),
+            env_val: format!("{}", slf.max_workers),
+            error: Box::new(WorkerBounds {
+                min_workers: slf.min_workers,
+            }),
+        });
+    }
+
     if errors.is_empty() {
         Ok(slf)
     } else {
@@ -3,17 +3,22 @@ use std::result;
 
 use super::config;
 
+// Fields are read through Debug, when main returns the error
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct IoError {
     pub action: &'static str,
     pub err: io::Error,
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum Error {
     Io(IoError),
     Config(config::Error),
     Protocol(protocol::Error),
+    // Workers exited this many times within the restart window
+    WorkerCrashLoop(usize),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,360 @@
+// Workers forked from a template process, which initialises python and warms
+// up once. The template forks twice so each worker is
+// re-parented to the master, we reap it like any exec'd worker.
+// Forking waits on the template, so it's done on a helper thread which
+// hands each worker back to the master's event loop.
+use std::io::{self, Read, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::path;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use fd_queue::mio::UnixStream as FdUnixStream;
+use fd_queue::EnqueueFd;
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use ndjsonlogger::{error, info};
+
+use super::config::WorkerEnv;
+use super::supervisor::{worker_command, Worker};
+
+// The template warms up before it's ready
+const START_TIMEOUT: time::Duration = time::Duration::from_secs(300);
+const FORK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// Sent by the template once it's ready, and by us to ask for a worker
+const READY: u8 = b'r';
+const FORK: u8 = b'f';
+
+pub struct ForkServer {
+    // Dropped first, the helper exits once it has forked what it was asked for
+    requests: Option<mpsc::Sender<()>>,
+    forked: mpsc::Receiver<io::Result<Worker>>,
+
+    // The helper writes a byte for each worker it sends, readable through mio
+    ready: pipe::Receiver,
+
+    // Forks asked for which haven't come back yet
+    pending: usize,
+
+    helper: Option<thread::JoinHandle<()>>,
+}
+
+// Owns the template, on the helper thread
+struct Helper {
+    workerbin: path::PathBuf,
+    sock_path: path::PathBuf,
+    worker_env: WorkerEnv,
+
+    // None once the template has failed, it's restarted on the next fork
+    template: Option<Template>,
+}
+
+struct Template {
+    child: process::Child,
+    stream: FdUnixStream,
+}
+
+impl ForkServer {
+    // Waits for the template to warm up, the master has nothing else to do yet
+    pub fn start(
+        workerbin: &path::Path,
+        sock_path: &path::Path,
+        worker_env: &WorkerEnv,
+    ) -> io::Result<Self> {
+        // Workers are orphaned as soon as they're forked, they must come to us rather than init
+        if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let template = Template::start(workerbin, sock_path, worker_env)?;
+        info!("fork server template ready", { pid: u32 = template.child.id() });
+
+        let helper = Helper {
+            workerbin: workerbin.to_owned(),
+            sock_path: sock_path.to_owned(),
+            worker_env: worker_env.clone(),
+            template: Some(template),
+        };
+        let (requests, requests_recv) = mpsc::channel();
+        let (forked_send, forked) = mpsc::channel();
+        let (ready_send, ready) = pipe::new()?;
+        let helper = thread::Builder::new()
+            .name(String::from("fork server"))
+            .spawn(move || helper.run(requests_recv, forked_send, ready_send))?;
+
+        Ok(Self {
+            requests: Some(requests),
+            forked,
+            ready,
+            pending: 0,
+            helper: Some(helper),
+        })
+    }
+
+    // The worker comes back from forked once the template has forked it
+    pub fn fork(&mut self) -> io::Result<()> {
+        let sent = self
+            .requests
+            .as_ref()
+            .is_some_and(|requests| requests.send(()).is_ok());
+        if !sent {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "fork server helper thread has exited",
+            ));
+        }
+
+        self.pending += 1;
+        Ok(())
+    }
+
+    // Forks which have finished since we last looked
+    pub fn forked(&mut self) -> Vec<io::Result<Worker>> {
+        // Drain the pipe, we're edge triggered
+        let mut buf = [0; 64];
+        while matches!(self.ready.read(&mut buf), Ok(n) if n > 0) {}
+
+        let forked: Vec<_> = self.forked.try_iter().collect();
+        self.pending -= forked.len();
+        forked
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+impl Drop for ForkServer {
+    fn drop(&mut self) {
+        self.requests = None;
+
+        // An idle helper exits straight away, stopping the template. One still
+        // forking may wait on the template for a while, it's left to exit with us.
+        if self.pending == 0 {
+            if let Some(helper) = self.helper.take() {
+                helper.join().unwrap_or(());
+            }
+        }
+    }
+}
+
+impl Source for ForkServer {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.ready.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.ready.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.ready.deregister(registry)
+    }
+}
+
+impl Helper {
+    fn run(
+        mut self,
+        requests: mpsc::Receiver<()>,
+        forked: mpsc::Sender<io::Result<Worker>>,
+        mut ready: pipe::Sender,
+    ) {
+        while requests.recv().is_ok() {
+            if forked.send(self.fork()).is_err() {
+                break;
+            }
+
+            // A full pipe is readable already
+            ready.write_all(&[0]).unwrap_or(());
+        }
+    }
+
+    fn fork(&mut self) -> io::Result<Worker> {
+        let template = match self.template.as_mut() {
+            Some(template) => template,
+            None => {
+                let template = Template::start(&self.workerbin, &self.sock_path, &self.worker_env)?;
+                info!("fork server template restarted", { pid: u32 = template.child.id() });
+                self.template.insert(template)
+            }
+        };
+
+        match template.fork() {
+            Ok(w) => Ok(w),
+            Err(io_err) => {
+                error!("fork server template failed, stopping it", {
+                    pid: u32 = template.child.id(),
+                    error = &format!("{}", io_err)
+                });
+                self.template = None;
+                Err(io_err)
+            }
+        }
+    }
+}
+
+impl Template {
+    fn start(
+        workerbin: &path::Path,
+        sock_path: &path::Path,
+        worker_env: &WorkerEnv,
+    ) -> io::Result<Self> {
+        let (stream, template_stream) = UnixStream::pair()?;
+
+        // The template logs straight to our stdout, it never runs atoms itself
+        // Forked workers inherit its environment
+        let child = worker_command(workerbin, worker_env)
+            .arg("--fork-server")
+            .arg(sock_path)
+            .stdin(process::Stdio::from(OwnedFd::from(template_stream)))
+            .spawn()?;
+
+        let template = Self {
+            child,
+            stream: FdUnixStream::from_std(stream.try_clone()?),
+        };
+
+        let mut ready = [0; 1];
+        stream.set_read_timeout(Some(START_TIMEOUT))?;
+        match (&stream).read_exact(&mut ready) {
+            Ok(()) if ready[0] == READY => {}
+            Ok(()) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "fork server template sent an unexpected message",
+                ));
+            }
+            // It logs why before exiting, eg a preload module failed to import
+            Err(io_err) => return Err(io_err),
+        }
+        stream.set_read_timeout(Some(FORK_TIMEOUT))?;
+
+        Ok(template)
+    }
+
+    fn fork(&mut self) -> io::Result<Worker> {
+        let (stdout_tx, stdout) = pipe::new()?;
+        let (stderr_tx, stderr) = pipe::new()?;
+
+        // Only our ends are non-blocking, the worker writes as usual
+        stdout_tx.set_nonblocking(false)?;
+        stderr_tx.set_nonblocking(false)?;
+
+        for fd in [&stdout_tx, &stderr_tx] {
+            self.stream
+                .enqueue(fd)
+                .map_err(|_| io::Error::other("fork server fd queue is full"))?;
+        }
+        self.stream.write_all(&[FORK])?;
+
+        let mut pid = [0; 4];
+        self.stream.read_exact(&mut pid)?;
+
+        // The template has its own copies of the write ends, ours are closed when dropped
+        match u32::from_be_bytes(pid) {
+            0 => Err(io::Error::other(
+                "fork server template couldn't fork a worker",
+            )),
+            pid => Ok(Worker {
+                pid,
+                stdout,
+                stderr,
+            }),
+        }
+    }
+}
+
+impl Drop for Template {
+    fn drop(&mut self) {
+        self.child.kill().unwrap_or(());
+        self.child.wait().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    // A template which says it's ready then exits, it appends a line to dir/starts each time
+    fn dying_template(dir: &path::Path) -> path::PathBuf {
+        let script = dir.join("template");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho started >> {}/starts\nprintf r >&0\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script
+    }
+
+    // Waits for the fork server to send a worker back, as the master's event loop does
+    fn next_forked(poll: &mut mio::Poll, fork_server: &mut ForkServer) -> io::Result<Worker> {
+        let mut events = mio::Events::with_capacity(4);
+        loop {
+            if let Some(res) = fork_server.forked().pop() {
+                return res;
+            }
+            poll.poll(&mut events, Some(time::Duration::from_secs(10)))
+                .unwrap();
+            assert!(!events.is_empty());
+        }
+    }
+
+    #[test]
+    fn dead_template_fails_the_fork_and_is_restarted() {
+        let dir = env::temp_dir().join(format!("pyproxy-forkserver-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let workerbin = dying_template(&dir);
+        let starts = || {
+            fs::read_to_string(dir.join("starts"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let mut fork_server =
+            ForkServer::start(&workerbin, &dir.join("sock"), &WorkerEnv::default()).unwrap();
+        assert_eq!(starts(), 1);
+        let mut poll = mio::Poll::new().unwrap();
+        poll.registry()
+            .register(&mut fork_server, Token(0), Interest::READABLE)
+            .unwrap();
+
+        // The template has gone by the time we ask it to fork
+        fork_server.fork().unwrap();
+        assert_eq!(fork_server.pending(), 1);
+        assert!(next_forked(&mut poll, &mut fork_server).is_err());
+        assert_eq!(fork_server.pending(), 0);
+        assert_eq!(starts(), 1);
+
+        // The next fork starts another, which goes the same way
+        fork_server.fork().unwrap();
+        assert!(next_forked(&mut poll, &mut fork_server).is_err());
+        assert_eq!(starts(), 2);
+
+        drop(fork_server);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
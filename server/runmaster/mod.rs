@@ -1,33 +1,42 @@
+use std::cell::Cell;
 use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::process;
 use std::rc::Rc;
+use std::time;
 
 use mio::net::{TcpListener, UnixListener};
-use mio::{unix::pipe, Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Registry, Token};
 use ndjsonlogger::{error, info};
 
+use crate::signals::{self, SignalFd};
+
 mod errors;
 pub use errors::{fatal_io_err, Error, Result};
+mod autoscale;
+use autoscale::Scale;
 pub mod config;
 use config::Config;
 mod clientstream;
+mod forkserver;
 mod outputstream;
 mod pipeframe;
+mod replay;
+mod stats;
+mod supervisor;
+pub use supervisor::{Spawner, Worker};
 mod workerstream;
 
-pub struct Worker {
-    pub child: process::Child,
-    pub stdout: process::ChildStdout,
-    pub stderr: process::ChildStderr,
-}
-
 const MAIN_LISTENER_TK: Token = Token(0);
 const OUTPUT_LISTENER_TK: Token = Token(1);
 const UNIX_LISTENER_TK: Token = Token(2);
-const TOKEN_START: usize = 3;
+const SIGNAL_TK: Token = Token(3);
+const FORK_SERVER_TK: Token = Token(4);
+const TOKEN_START: usize = 5;
 const RO: Interest = Interest::READABLE;
 
+// Workers retiring as they start would pass a session between them forever
+const MAX_SESSION_RETURNS: u32 = 3;
+
 #[derive(Debug)]
 enum IoAction {
     MainListener(TcpListener),
@@ -38,21 +47,8 @@ enum IoAction {
     Stderr(pipeframe::PipeFrame),
     Stdout(pipeframe::PipeFrame),
     WorkerStream(workerstream::WorkerStream),
-}
-
-impl IoAction {
-    fn name(&self) -> &'static str {
-        match self {
-            IoAction::MainListener(_) => "main listener",
-            IoAction::ClientStream(_) => "client stream",
-            IoAction::OutputListener(_) => "output listener",
-            IoAction::OutputStream(_) => "output stream",
-            IoAction::UnixListener(_) => "unix listener",
-            IoAction::Stderr(_) => "stderr",
-            IoAction::Stdout(_) => "stdout",
-            IoAction::WorkerStream(_) => "worker stream",
-        }
-    }
+    Child(supervisor::ChildWatch),
+    Signal(SignalFd),
 }
 
 pub fn run_forever(
@@ -60,6 +56,7 @@ pub fn run_forever(
     main_listener: std::net::TcpListener,
     output_listener: std::net::TcpListener,
     unix_listener: std::os::unix::net::UnixListener,
+    mut spawner: Spawner,
     workers: Vec<Worker>,
 ) -> Result<()> {
     fatal_io_err(
@@ -112,88 +109,329 @@ pub fn run_forever(
     )?;
     io_actions.insert(UNIX_LISTENER_TK, IoAction::UnixListener(unix_listener));
 
-    let mut events = Events::with_capacity(1024);
-
-    // Register stdout/stderr of workers
-    for w in workers {
-        let mut stdout = pipe::Receiver::from(w.stdout);
-        // Stdout
-        fatal_io_err(
-            "master couldn't set worker stdout to non-blocking",
-            stdout.set_nonblocking(true),
-        )?;
-
-        fatal_io_err(
-            "master couldn't register worker stdout for reading",
-            poll.registry().register(&mut stdout, Token(io_token), RO),
-        )?;
-
-        io_actions.insert(
-            Token(io_token),
-            IoAction::Stdout(pipeframe::PipeFrame::new(stdout)),
-        );
-
-        io_token += 1;
+    // Shut down on SIGTERM and SIGINT
+    let mut signal_fd = fatal_io_err(
+        "master failed to create signal fd",
+        SignalFd::new(&signals::SHUTDOWN_SIGNALS),
+    )?;
+    fatal_io_err(
+        "master failed to register signal fd for reading",
+        poll.registry().register(&mut signal_fd, SIGNAL_TK, RO),
+    )?;
+    io_actions.insert(SIGNAL_TK, IoAction::Signal(signal_fd));
 
-        // Stderr
-        let mut stderr = pipe::Receiver::from(w.stderr);
+    // Forked workers come back through the fork server
+    if let Spawner::Fork(fork_server) = &mut spawner {
         fatal_io_err(
-            "master couldn't set worker stderr to non-blocking",
-            stderr.set_nonblocking(true),
+            "master failed to register fork server for reading",
+            poll.registry().register(fork_server, FORK_SERVER_TK, RO),
         )?;
+    }
 
-        fatal_io_err(
-            "master couldn't register worker stderr for reading",
-            poll.registry().register(&mut stderr, Token(io_token), RO),
-        )?;
-        io_actions.insert(
-            Token(io_token),
-            IoAction::Stderr(pipeframe::PipeFrame::new(stderr)),
-        );
+    let mut events = Events::with_capacity(1024);
 
-        io_token += 1;
+    // Register stdout/stderr of workers
+    for w in workers {
+        register_worker(poll.registry(), &mut io_actions, &mut io_token, w)?;
     }
 
     let mut buffer = vec![0; 4096];
     let mut to_remove = Vec::with_capacity(16);
     let mut to_insert = Vec::with_capacity(16);
-    let mut worker_streams = workerstream::WorkerStreams::new();
+    let mut to_close = Vec::with_capacity(16);
+    let mut returned = Vec::new();
+    let mut worker_streams = workerstream::WorkerStreams::new(
+        cfg.dispatch,
+        cfg.worker_mode == config::WorkerMode::ForkPerSession,
+    );
     let mut new_requests = VecDeque::with_capacity(64);
     let mut output_streams: HashMap<String, outputstream::OutputStream> = HashMap::new();
+    let mut replays = replay::Replays::new(&cfg);
+    // Each session's next output seq, carried over when its output stream reconnects
+    let mut output_seqs: HashMap<String, Rc<Cell<u64>>> = HashMap::new();
+    let mut stats = stats::Stats::new();
+    let mut supervisor = supervisor::Supervisor::new(&cfg);
+    let autoscaler = autoscale::Autoscaler::new(&cfg);
+    // Workers exiting idle or to be recycled, with the reason, they aren't respawned
+    let mut retiring = HashMap::new();
+    let mut recycled: Vec<(u32, String)> = Vec::new();
+    let mut signalled = None;
+    // Workers are killed if they haven't drained by the deadline
+    let mut shutdown: Option<Shutdown> = None;
 
     loop {
         for tk in to_remove.drain(..) {
-            io_actions.remove(&tk);
+            // Its session's output is held again until the client reconnects
+            if let Some(IoAction::OutputStream(_)) = io_actions.remove(&tk) {
+                output_streams.retain(|session_id, output_stream| {
+                    if output_stream.token != tk {
+                        return true;
+                    }
+                    if output_stream.is_paused() {
+                        worker_streams.pause_output(session_id, false);
+                    }
+                    false
+                });
+            }
+        }
+
+        stats.report_due(time::Instant::now());
+
+        if let Some(sig) = signalled.take() {
+            match shutdown.as_mut() {
+                None => {
+                    info!("master shutting down, draining workers", {
+                        signal: i32 = sig,
+                        grace_secs: u64 = cfg.shutdown_grace.as_secs()
+                    });
+
+                    // Dropping the listeners closes them
+                    io_actions.remove(&MAIN_LISTENER_TK);
+                    io_actions.remove(&OUTPUT_LISTENER_TK);
+                    io_actions.remove(&UNIX_LISTENER_TK);
+
+                    let notice = format!(
+                        "pyproxy server shutting down, in-flight atoms have {}s to finish",
+                        cfg.shutdown_grace.as_secs()
+                    );
+                    for output_stream in output_streams.values() {
+                        output_stream.send_notice(notice.as_bytes());
+                    }
+
+                    for act in io_actions.values() {
+                        if let IoAction::Child(child) = act {
+                            child.terminate();
+                        }
+                    }
+
+                    shutdown = Some(Shutdown {
+                        deadline: time::Instant::now() + cfg.shutdown_grace,
+                        killed: false,
+                        error: None,
+                    });
+                }
+                Some(shutdown) => {
+                    info!("master signalled again, not waiting for workers", { signal: i32 = sig });
+                    shutdown.deadline = time::Instant::now();
+                }
+            }
+        }
+
+        if let Some(shutdown) = shutdown.as_mut() {
+            if !shutdown.killed && shutdown.deadline <= time::Instant::now() {
+                for act in io_actions.values_mut() {
+                    if let IoAction::Child(child) = act {
+                        if shutdown.error.is_none() {
+                            error!("worker didn't drain in time, killing it", {
+                                pid: u32 = child.pid()
+                            });
+                        }
+                        child.kill();
+                    }
+                }
+                shutdown.killed = true;
+            }
+
+            // Every worker has been reaped, forks still under way are waited for
+            // until the deadline
+            if !io_actions
+                .values()
+                .any(|act| matches!(act, IoAction::Child(_)))
+                && (spawner.pending() == 0 || shutdown.killed)
+            {
+                for output_stream in output_streams.values() {
+                    output_stream.write().unwrap_or(());
+                }
+
+                stats.report();
+                if let Some(err) = shutdown.error.take() {
+                    return Err(err);
+                }
+                info!("master shut down");
+                return Ok(());
+            }
+        }
+
+        // Workers the fork server has finished forking
+        for res in spawner.forked() {
+            match res {
+                Ok(w) => {
+                    let pid = w.pid;
+                    info!("forked worker", { pid: u32 = pid });
+                    register_worker(poll.registry(), &mut io_actions, &mut io_token, w)?;
+
+                    // Asked for before we started shutting down
+                    if shutdown.is_some() {
+                        for act in io_actions.values() {
+                            if let IoAction::Child(child) = act {
+                                if child.pid() == pid {
+                                    child.terminate();
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(io_err) => {
+                    error!("master failed to fork worker", {
+                        error = &format!("{}", io_err)
+                    });
+
+                    // Counted as a crash, it's retried like one
+                    if shutdown.is_none() {
+                        if let Err(err) = supervisor.exited(time::Instant::now()) {
+                            error!("workers are crash looping - giving up");
+                            shutdown = Some(Shutdown {
+                                deadline: time::Instant::now(),
+                                killed: false,
+                                error: Some(err),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        // Replace workers which have exited, unless we're shutting down
+        let respawns = match shutdown {
+            None => supervisor.due(time::Instant::now()),
+            Some(_) => 0,
+        };
+        for _ in 0..respawns {
+            match spawner.spawn() {
+                Ok(Some(w)) => {
+                    info!("respawned worker", { pid: u32 = w.pid });
+                    register_worker(poll.registry(), &mut io_actions, &mut io_token, w)?;
+                }
+                Ok(None) => {}
+                Err(io_err) => {
+                    error!("master failed to respawn worker", {
+                        error = &format!("{}", io_err)
+                    });
+
+                    // A worker we can't start counts as one which crashed
+                    if let Err(err) = supervisor.exited(time::Instant::now()) {
+                        error!("workers are crash looping - giving up");
+                        shutdown = Some(Shutdown {
+                            deadline: time::Instant::now(),
+                            killed: false,
+                            error: Some(err),
+                        });
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Replace workers past their recycle limits, they exit once their sessions close
+        for (pid, reason) in recycled.drain(..) {
+            info!("recycling worker", { pid: u32 = pid, reason = &reason[..] });
+            retiring.insert(pid, reason);
+
+            if shutdown.is_some() {
+                continue;
+            }
+
+            match spawner.spawn() {
+                Ok(Some(w)) => {
+                    info!("spawned replacement worker", {
+                        pid: u32 = w.pid,
+                        replacing: u32 = pid
+                    });
+                    register_worker(poll.registry(), &mut io_actions, &mut io_token, w)?;
+                }
+                Ok(None) => {}
+                Err(io_err) => {
+                    error!("master failed to spawn replacement worker", {
+                        error = &format!("{}", io_err)
+                    });
+                }
+            }
+        }
+
+        // Grow or shrink the pool with load
+        if shutdown.is_none() && autoscaler.enabled() {
+            // Retiring workers can briefly outnumber those we count, e.g. as they crash
+            let running = io_actions
+                .values()
+                .filter(|act| matches!(act, IoAction::Child(_)))
+                .count()
+                .saturating_sub(retiring.len());
+            let starting =
+                running.saturating_sub(worker_streams.live().count()) + spawner.pending();
+            let pool = running + supervisor.pending() + spawner.pending();
+
+            match autoscaler.plan(&worker_streams, pool, starting, time::Instant::now()) {
+                Scale::Hold => {}
+                Scale::Up => match spawner.spawn() {
+                    Ok(Some(w)) => {
+                        info!("spawned worker under load", {
+                            pid: u32 = w.pid,
+                            workers: usize = pool + 1
+                        });
+                        register_worker(poll.registry(), &mut io_actions, &mut io_token, w)?;
+                    }
+                    Ok(None) => {}
+                    Err(io_err) => {
+                        error!("master failed to spawn worker under load", {
+                            error = &format!("{}", io_err)
+                        });
+                    }
+                },
+                Scale::Down(pids) => {
+                    worker_streams.close_pids(&pids);
+                    for act in io_actions.values() {
+                        if let IoAction::Child(child) = act {
+                            if pids.contains(&child.pid()) {
+                                info!("retiring idle worker", { pid: u32 = child.pid() });
+                                child.terminate();
+                            }
+                        }
+                    }
+                    for pid in pids {
+                        retiring.insert(pid, String::from("idle"));
+                    }
+                }
+            }
         }
 
         for (tk, act) in to_insert.drain(..) {
             io_actions.insert(tk, act);
         }
 
-        worker_streams.dispatch(&mut new_requests);
+        // Client streams of workers which have exited
+        for tk in to_close.drain(..) {
+            if let Some(IoAction::ClientStream(client_stream)) = io_actions.remove(&tk) {
+                client_stream.shutdown();
+            }
+        }
 
-        // Do we need to write to our worker streams?
-        for (tk, worker_stream) in worker_streams.iter_mut() {
-            if worker_stream.has_data() && worker_stream.interest() == RO {
-                worker_stream.set_interest(Interest::READABLE | Interest::WRITABLE);
-                fatal_io_err(
-                    "master couldn't re-register worker stream RW",
-                    poll.registry()
-                        .reregister(worker_stream, *tk, worker_stream.interest()),
-                )?;
+        // Sessions a draining worker was sent, no worker has read from them yet
+        for tk in returned.drain(..) {
+            if let Some(IoAction::ClientStream(client_stream)) = io_actions.get_mut(&tk) {
+                if shutdown.is_none() && client_stream.returned() <= MAX_SESSION_RETURNS {
+                    new_requests.push_back((client_stream.header(), client_stream.raw_fd(), tk));
+                    continue;
+                }
+                client_stream.reject(
+                    protocol::ErrorCode::ShuttingDown,
+                    String::from("every worker the session was sent to was shutting down"),
+                );
+                io_actions.remove(&tk);
             }
+        }
 
-            if !worker_stream.has_data() && worker_stream.interest().is_writable() {
-                worker_stream.set_interest(RO);
-                fatal_io_err(
-                    "master couldn't re-register worker stream RO",
-                    poll.registry()
-                        .reregister(worker_stream, *tk, worker_stream.interest()),
-                )?;
+        for tk in worker_streams.dispatch(&mut new_requests) {
+            // Dropping it closes our copy of the fd
+            if let Some(IoAction::ClientStream(mut client_stream)) = io_actions.remove(&tk) {
+                client_stream.reject(
+                    protocol::ErrorCode::Busy,
+                    String::from("no worker could take the session"),
+                );
             }
         }
 
-        for (_, output_stream) in output_streams.iter_mut() {
+        // Ahead of the worker streams, pausing or resuming a session is a write to its worker
+        for (session_id, output_stream) in output_streams.iter_mut() {
             if output_stream.interest() == RO && output_stream.has_out_data() {
                 let int = Interest::READABLE | Interest::WRITABLE;
                 output_stream.set_interest(int);
@@ -217,12 +455,59 @@ pub fn run_forever(
                     poll.registry().deregister(output_stream).unwrap_or(());
                     to_remove.push(output_stream.token);
                 }
+            } else if output_stream.interest().is_writable() && output_stream.write().is_err() {
+                // Queued since our last write, we won't get another writable event for it
+                poll.registry().deregister(output_stream).unwrap_or(());
+                to_remove.push(output_stream.token);
+            }
+
+            stats.output_lines_dropped += output_stream.take_drops();
+            if let Some(pause) = output_stream.take_pause() {
+                if pause {
+                    stats.output_pauses += 1;
+                }
+                worker_streams.pause_output(session_id, pause);
             }
         }
 
+        // Do we need to write to our worker streams?
+        for (tk, worker_stream) in worker_streams.iter_mut() {
+            if worker_stream.has_data() && worker_stream.interest() == RO {
+                worker_stream.set_interest(Interest::READABLE | Interest::WRITABLE);
+                fatal_io_err(
+                    "master couldn't re-register worker stream RW",
+                    poll.registry()
+                        .reregister(worker_stream, *tk, worker_stream.interest()),
+                )?;
+            }
+
+            if !worker_stream.has_data() && worker_stream.interest().is_writable() {
+                worker_stream.set_interest(RO);
+                fatal_io_err(
+                    "master couldn't re-register worker stream RO",
+                    poll.registry()
+                        .reregister(worker_stream, *tk, worker_stream.interest()),
+                )?;
+            }
+        }
+
+        // Wake up when the next respawn, scaling check or the shutdown deadline is due
+        let now = time::Instant::now();
+        let timeout = match &shutdown {
+            None if autoscaler.enabled() => Some(
+                supervisor
+                    .next_due(now)
+                    .map_or(autoscale::TICK, |due| due.min(autoscale::TICK)),
+            ),
+            None => supervisor.next_due(now),
+            Some(shutdown) if !shutdown.killed => {
+                Some(shutdown.deadline.saturating_duration_since(now))
+            }
+            Some(_) => None,
+        };
         fatal_io_err(
             "master failed to poll mio for events",
-            poll.poll(&mut events, None),
+            poll.poll(&mut events, timeout),
         )?;
 
         for ev in &events {
@@ -250,24 +535,33 @@ pub fn run_forever(
 
                         io_token += 1;
                     }
-                    Err(e) => {
+                    Err(_) => {
                         // Ignore the error - just drop the stream
                     }
                 },
                 Some(IoAction::OutputListener(output_listener)) => match output_listener.accept() {
                     Ok((stream, _)) => {
-                        let mut output_stream =
-                            outputstream::OutputStream::new(stream, Token(io_token), RO);
+                        let mut output_stream = outputstream::OutputStream::new(
+                            stream,
+                            Token(io_token),
+                            RO,
+                            cfg.output_buffer_bytes,
+                            cfg.output_policy,
+                        );
                         if poll
                             .registry()
                             .register(&mut output_stream, Token(io_token), RO)
                             .is_ok()
                         {
-                            io_actions
-                                .insert(Token(io_token), IoAction::OutputStream(output_stream));
+                            to_insert
+                                .push((Token(io_token), IoAction::OutputStream(output_stream)));
                         }
 
-                        // Ignore errors
+                        fatal_io_err(
+                            "couldn't reregister output_listener to accept more output streams",
+                            poll.registry()
+                                .reregister(output_listener, OUTPUT_LISTENER_TK, RO),
+                        )?;
 
                         io_token += 1;
                     }
@@ -290,10 +584,11 @@ pub fn run_forever(
 
                             // NOTE: We don't remove it - leave it hanging around
                             // in the io_actions HashMap
-                            let new_req = (client_stream.header(), client_stream.raw_fd());
+                            let new_req =
+                                (client_stream.header(), client_stream.raw_fd(), ev.token());
                             new_requests.push_back(new_req);
                         }
-                        clientstream::ReadResult::Error(_) => {
+                        clientstream::ReadResult::Error => {
                             // Error reading TcpStream - ignore it
                             poll.registry().deregister(client_stream).unwrap_or(());
                             to_remove.push(ev.token());
@@ -315,16 +610,25 @@ pub fn run_forever(
                                 .is_ok()
                             {
                                 info!("new output stream opened", { session_id = &session_id[..] });
-                                output_streams.insert(session_id, output_stream.clone());
+                                output_stream.set_seq(
+                                    output_seqs.entry(session_id.clone()).or_default().clone(),
+                                );
+                                replays.flush(&session_id, output_stream);
+                                // A reconnect beating the old stream's close, which may have paused
+                                if let Some(old) =
+                                    output_streams.insert(session_id.clone(), output_stream.clone())
+                                {
+                                    if old.is_paused() {
+                                        worker_streams.pause_output(&session_id, false);
+                                    }
+                                }
                             }
                         }
                     }
 
-                    if ev.is_writable() {
-                        if output_stream.write().is_err() {
-                            poll.registry().deregister(output_stream).unwrap_or(());
-                            to_remove.push(ev.token());
-                        }
+                    if ev.is_writable() && output_stream.write().is_err() {
+                        poll.registry().deregister(output_stream).unwrap_or(());
+                        to_remove.push(ev.token());
                     }
                 }
                 Some(IoAction::UnixListener(unix_listener)) => match unix_listener.accept() {
@@ -342,11 +646,18 @@ pub fn run_forever(
                             "master couldn't create worker stream instance",
                             workerstream::WorkerStream::new(stream, RO),
                         )?;
-                        io_actions.insert(
+                        to_insert.push((
                             Token(io_token),
                             IoAction::WorkerStream(worker_stream.clone()),
-                        );
+                        ));
                         worker_streams.add(Token(io_token), worker_stream);
+
+                        fatal_io_err(
+                            "couldn't reregister unix_listener to accept more workers",
+                            poll.registry()
+                                .reregister(unix_listener, UNIX_LISTENER_TK, RO),
+                        )?;
+
                         io_token += 1;
                     }
                 },
@@ -354,9 +665,37 @@ pub fn run_forever(
                     if ev.is_readable() {
                         if worker_stream.read(&mut buffer).is_err() {
                             // Drop stream
+                            // Kept in worker_streams until its process is reaped
                             poll.registry().deregister(worker_stream).unwrap_or(());
+                            worker_stream.close();
                             to_remove.push(ev.token());
                         }
+
+                        // Output of the worker's atoms, tagged with the session it belongs to
+                        for output in worker_stream.take_output() {
+                            match output_streams.get(&output.session_id) {
+                                Some(output_stream) => output_stream.send_output(&output),
+                                None => stats.output_lines_dropped += replays.push(output),
+                            }
+                        }
+
+                        for session_id in worker_stream.take_closed_sessions() {
+                            replays.discard(&session_id);
+                            output_seqs.remove(&session_id);
+                        }
+
+                        // A worker returns sessions once it's draining, it takes no more
+                        let worker_returned = worker_stream.take_returned();
+                        if !worker_returned.is_empty() {
+                            worker_stream.retire();
+                            returned.extend(worker_returned);
+                        }
+
+                        // Past its recycle limits, stop dispatching to it straight away
+                        if let Some(reason) = worker_stream.take_recycle() {
+                            worker_stream.retire();
+                            recycled.push((worker_stream.pid(), reason));
+                        }
                     }
 
                     if ev.is_writable() {
@@ -367,6 +706,98 @@ pub fn run_forever(
                         }
                     }
                 }
+                Some(IoAction::Child(child)) => {
+                    let status = match child.try_wait() {
+                        Ok(Some(status)) => status,
+                        // Spurious wake up, still running
+                        Ok(None) => continue,
+                        Err(io_err) => {
+                            error!("master failed to reap worker", {
+                                pid: u32 = child.pid(),
+                                error = &format!("{}", io_err)
+                            });
+                            continue;
+                        }
+                    };
+
+                    poll.registry().deregister(child).unwrap_or(());
+                    to_remove.push(ev.token());
+
+                    // Stop dispatching to it, its stream may not have closed yet
+                    if let Some((tk, mut worker_stream)) = worker_streams.remove_pid(child.pid()) {
+                        poll.registry().deregister(&mut worker_stream).unwrap_or(());
+                        to_remove.push(tk);
+
+                        // Tell its sessions why they are about to lose their mainstream
+                        let notice = format!(
+                            "pyproxy worker {} running this session exited ({})",
+                            child.pid(),
+                            status
+                        );
+                        for session_id in worker_stream.sessions() {
+                            if let Some(output_stream) = output_streams.get(&session_id) {
+                                output_stream.send_notice(notice.as_bytes());
+                            }
+                            replays.discard(&session_id);
+                            output_seqs.remove(&session_id);
+                        }
+
+                        to_close.extend(worker_stream.take_clients());
+                    }
+
+                    if let Some(reason) = retiring.remove(&child.pid()) {
+                        info!("worker retired", {
+                            pid: u32 = child.pid(),
+                            reason = &reason[..],
+                            status = &format!("{}", status)
+                        });
+                        continue;
+                    }
+
+                    if shutdown.is_some() {
+                        info!("worker exited", {
+                            pid: u32 = child.pid(),
+                            status = &format!("{}", status)
+                        });
+                        continue;
+                    }
+
+                    match supervisor.exited(time::Instant::now()) {
+                        Ok(backoff) => {
+                            error!("worker exited, respawning", {
+                                pid: u32 = child.pid(),
+                                status = &format!("{}", status),
+                                backoff_ms: u128 = backoff.as_millis()
+                            });
+                        }
+                        Err(err) => {
+                            error!("worker exited, workers are crash looping - giving up", {
+                                pid: u32 = child.pid(),
+                                status = &format!("{}", status)
+                            });
+
+                            // Don't leave the other workers to init, they're killed
+                            // and reaped as at the shutdown deadline before we return
+                            shutdown = Some(Shutdown {
+                                deadline: time::Instant::now(),
+                                killed: false,
+                                error: Some(err),
+                            });
+                        }
+                    }
+                }
+                Some(IoAction::Signal(signal_fd)) => loop {
+                    match signal_fd.read() {
+                        Ok(Some(sig)) => signalled = Some(sig),
+                        Ok(None) => break,
+                        Err(io_err) => {
+                            error!("master failed to read signal fd", {
+                                error = &format!("{}", io_err)
+                            });
+                            break;
+                        }
+                    }
+                },
                 Some(IoAction::Stdout(pipe_frame)) => {
                     match pipe_frame.read(&mut buffer, io::stdout()) {
                         Err(io_err) => {
@@ -376,19 +807,12 @@ pub fn run_forever(
                             poll.registry().deregister(pipe_frame).unwrap_or(());
                             to_remove.push(ev.token());
                         }
-                        Ok(None) => {
-                            // No complete lines
-                            continue;
-                        }
-                        Ok(Some((session_id, lines))) => {
-                            if let Some(output_stream) = output_streams.get_mut(session_id) {
-                                for line in lines {
-                                    output_stream.send_stdout(line);
-                                }
-                            }
-
-                            pipe_frame.clear();
+                        Ok(true) => {
+                            // Worker has exited
+                            poll.registry().deregister(pipe_frame).unwrap_or(());
+                            to_remove.push(ev.token());
                         }
+                        Ok(false) => {}
                     }
                 }
                 Some(IoAction::Stderr(pipe_frame)) => {
@@ -400,22 +824,84 @@ pub fn run_forever(
                             poll.registry().deregister(pipe_frame).unwrap_or(());
                             to_remove.push(ev.token());
                         }
-                        Ok(None) => {
-                            // No complete lines
-                            continue;
-                        }
-                        Ok(Some((session_id, lines))) => {
-                            if let Some(output_stream) = output_streams.get_mut(session_id) {
-                                for line in lines {
-                                    output_stream.send_stderr(line);
-                                }
-                            }
-
-                            pipe_frame.clear();
+                        Ok(true) => {
+                            // Worker has exited
+                            poll.registry().deregister(pipe_frame).unwrap_or(());
+                            to_remove.push(ev.token());
                         }
+                        Ok(false) => {}
                     }
                 }
             }
         }
     }
 }
+
+struct Shutdown {
+    deadline: time::Instant,
+    // Workers still running at the deadline have been sent SIGKILL
+    killed: bool,
+    // Why we're giving up, returned once every worker is reaped
+    error: Option<Error>,
+}
+
+// Watch a worker's stdout, stderr and exit
+fn register_worker(
+    registry: &Registry,
+    io_actions: &mut HashMap<Token, IoAction>,
+    io_token: &mut usize,
+    w: Worker,
+) -> Result<()> {
+    let mut stdout = w.stdout;
+    // Stdout
+    fatal_io_err(
+        "master couldn't set worker stdout to non-blocking",
+        stdout.set_nonblocking(true),
+    )?;
+
+    fatal_io_err(
+        "master couldn't register worker stdout for reading",
+        registry.register(&mut stdout, Token(*io_token), RO),
+    )?;
+
+    io_actions.insert(
+        Token(*io_token),
+        IoAction::Stdout(pipeframe::PipeFrame::new(stdout)),
+    );
+
+    *io_token += 1;
+
+    // Stderr
+    let mut stderr = w.stderr;
+    fatal_io_err(
+        "master couldn't set worker stderr to non-blocking",
+        stderr.set_nonblocking(true),
+    )?;
+
+    fatal_io_err(
+        "master couldn't register worker stderr for reading",
+        registry.register(&mut stderr, Token(*io_token), RO),
+    )?;
+    io_actions.insert(
+        Token(*io_token),
+        IoAction::Stderr(pipeframe::PipeFrame::new(stderr)),
+    );
+
+    *io_token += 1;
+
+    // Exit
+    let mut child = fatal_io_err(
+        "master couldn't open pidfd for worker",
+        supervisor::ChildWatch::new(w.pid),
+    )?;
+
+    fatal_io_err(
+        "master couldn't register worker pidfd for reading",
+        registry.register(&mut child, Token(*io_token), RO),
+    )?;
+    io_actions.insert(Token(*io_token), IoAction::Child(child));
+
+    *io_token += 1;
+
+    Ok(())
+}
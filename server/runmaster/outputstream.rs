@@ -1,15 +1,24 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
+use std::mem;
 use std::rc::Rc;
 
 use mio::event::Source;
 use mio::net::TcpStream;
 use mio::{Interest, Registry, Token};
 
-use protocol::outputstream::{new_msg, MessageHeader, MessageType};
+use protocol::outputstream::{new_frame, new_msg, Frame, MessageHeader, MessageType};
 
+use crate::messages::{self, OutputKind, OutputMessage};
+
+use super::config::OutputPolicy;
 use super::errors::{fatal_io_err, Result};
 
+// Most we frame ahead of writing, the rest stays queued where it can be dropped.
+// Policies only count what's queued, what's framed can't be dropped.
+const WRITE_BYTES: usize = 64 * 1024;
+
 #[derive(Clone, Debug)]
 pub struct OutputStream {
     pub token: Token,
@@ -17,10 +26,18 @@ pub struct OutputStream {
 }
 
 impl OutputStream {
-    pub fn new(stream: TcpStream, token: Token, interest: Interest) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        token: Token,
+        interest: Interest,
+        max_bytes: usize,
+        policy: OutputPolicy,
+    ) -> Self {
         Self {
             token,
-            inner: Rc::new(RefCell::new(Inner::new(stream, interest))),
+            inner: Rc::new(RefCell::new(Inner::new(
+                stream, interest, max_bytes, policy,
+            ))),
         }
     }
 
@@ -40,12 +57,61 @@ impl OutputStream {
         self.inner.borrow_mut().take_session_id()
     }
 
-    pub fn send_stdout(&self, line: &[u8]) {
-        self.inner.borrow_mut().send_stdout(line)
+    // Shared with the session's earlier and later streams, so seq carries on across them
+    pub fn set_seq(&self, seq: Rc<Cell<u64>>) {
+        self.inner.borrow_mut().seq = seq;
     }
 
-    pub fn send_stderr(&self, line: &[u8]) {
-        self.inner.borrow_mut().send_stderr(line)
+    pub fn send_output(&self, output: &OutputMessage) {
+        // Clients from before chunks get them as lines
+        let chunk = output.chunk && self.inner.borrow().frame_version >= 2;
+        let msg_type = match (output.kind, chunk) {
+            (OutputKind::Stdout, false) => MessageType::Stdout,
+            (OutputKind::Stderr, false) => MessageType::Stderr,
+            (OutputKind::Stdout, true) => MessageType::StdoutChunk,
+            (OutputKind::Stderr, true) => MessageType::StderrChunk,
+        };
+        self.inner.borrow_mut().send_output(Queued {
+            msg_type,
+            future_id: Some(output.future_id.clone()),
+            ts: output.ts,
+            data: output.line.clone(),
+        })
+    }
+
+    pub fn send_notice(&self, notice: &[u8]) {
+        self.inner.borrow_mut().queue(Queued {
+            msg_type: MessageType::Notice,
+            future_id: None,
+            ts: messages::timestamp(),
+            data: notice.to_vec(),
+        })
+    }
+
+    pub fn send_dropped(&self, lines: u64) {
+        let mut inner = self.inner.borrow_mut();
+        let dropped = inner.dropped_notice(lines);
+        inner.queue(dropped);
+    }
+
+    // Output lines dropped since last taken, for the master's stats
+    pub fn take_drops(&self) -> u64 {
+        mem::take(&mut self.inner.borrow_mut().drops)
+    }
+
+    // Some(true) once the session's atoms should pause, Some(false) once they may resume
+    pub fn take_pause(&self) -> Option<bool> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.paused == inner.pause_taken {
+            return None;
+        }
+        inner.pause_taken = inner.paused;
+        Some(inner.paused)
+    }
+
+    // Whether the session's atoms were last told to pause
+    pub fn is_paused(&self) -> bool {
+        self.inner.borrow().pause_taken
     }
 
     pub fn write(&self) -> io::Result<()> {
@@ -62,18 +128,55 @@ struct Inner {
     stream: TcpStream,
     interest: Interest,
     inbuffer: Vec<u8>,
+    // Framed, being written
     outbuffer: Vec<u8>,
+    queue: VecDeque<Queued>,
+    queued_bytes: usize,
+    // Past this much buffered output, policy applies
+    max_bytes: usize,
+    policy: OutputPolicy,
+    // Output lines dropped, not yet told to the client
+    dropped: u64,
+    // Output lines dropped, not yet counted in the master's stats
+    drops: u64,
+    // Whether the session's atoms should pause, and what we last said
+    paused: bool,
+    pause_taken: bool,
     session_id: Option<String>,
+    // From the client hello, see protocol::outputstream::FRAME_VERSION
+    frame_version: u8,
+    // Of the session's next frame, see set_seq
+    seq: Rc<Cell<u64>>,
+}
+
+// Waiting to be written, it's framed then so dropped output leaves no gap in seq
+#[derive(Debug)]
+struct Queued {
+    msg_type: MessageType,
+    // None for notices
+    future_id: Option<String>,
+    ts: u64,
+    data: Vec<u8>,
 }
 
 impl Inner {
-    fn new(stream: TcpStream, interest: Interest) -> Self {
+    fn new(stream: TcpStream, interest: Interest, max_bytes: usize, policy: OutputPolicy) -> Self {
         Self {
             stream,
             interest,
             inbuffer: Vec::with_capacity(64),
             outbuffer: Vec::with_capacity(4096),
+            queue: VecDeque::with_capacity(64),
+            queued_bytes: 0,
+            max_bytes,
+            policy,
+            dropped: 0,
+            drops: 0,
+            paused: false,
+            pause_taken: false,
             session_id: None,
+            frame_version: 0,
+            seq: Rc::new(Cell::new(0)),
         }
     }
 
@@ -86,8 +189,15 @@ impl Inner {
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<()> {
-        let bytes_read = fatal_io_err("failed to read output stream", self.stream.read(buf))?;
-        self.inbuffer.extend(&buf[..bytes_read]);
+        // Drain the stream, we're edge triggered
+        let closed = loop {
+            match self.stream.read(buf) {
+                Ok(0) => break true,
+                Ok(bytes_read) => self.inbuffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(err) => fatal_io_err("failed to read output stream", Err(err))?,
+            }
+        };
 
         // Read the header
         while self.inbuffer.len() >= protocol::REQUEST_HEADER_SIZE {
@@ -110,6 +220,7 @@ impl Inner {
             let msg = protocol::outputstream::read_hello(payload)?;
 
             self.session_id = Some(msg.stream_token);
+            self.frame_version = header.msg_sub_type;
 
             let bytes_remaining = self.inbuffer.len() - msg_end;
             for n in 0..bytes_remaining {
@@ -117,17 +228,52 @@ impl Inner {
             }
             self.inbuffer.truncate(bytes_remaining);
         }
+
+        // After taking a hello sent just before the client went
+        if closed {
+            fatal_io_err(
+                "output stream closed",
+                Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            )?;
+        }
+
         Ok(())
     }
 
     fn write(&mut self) -> io::Result<()> {
-        let bytes_written = self.stream.write(&self.outbuffer)?;
+        // Write until we'd block, we're edge triggered
+        loop {
+            self.frame_queued();
+
+            let mut bytes_written = 0;
+            let mut blocked = false;
+            while bytes_written < self.outbuffer.len() {
+                match self.stream.write(&self.outbuffer[bytes_written..]) {
+                    Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    Ok(n) => bytes_written += n,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        blocked = true;
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
 
-        let bytes_remaining = self.outbuffer.len() - bytes_written;
-        for n in 0..bytes_remaining {
-            self.outbuffer[n] = self.outbuffer[n + bytes_written];
+            let bytes_remaining = self.outbuffer.len() - bytes_written;
+            for n in 0..bytes_remaining {
+                self.outbuffer[n] = self.outbuffer[n + bytes_written];
+            }
+            self.outbuffer.truncate(bytes_remaining);
+
+            if blocked || !self.has_out_data() {
+                break;
+            }
+        }
+
+        // Resume well below the limit, so atoms aren't paused for every line
+        if self.paused && self.queued_bytes <= self.max_bytes / 2 {
+            self.paused = false;
         }
-        self.outbuffer.truncate(bytes_remaining);
 
         Ok(())
     }
@@ -136,18 +282,114 @@ impl Inner {
         self.session_id.take()
     }
 
-    fn send_stdout(&mut self, line: &[u8]) {
-        let msg_header = MessageHeader::new(MessageType::Stdout, line.len());
-        self.outbuffer.extend(&new_msg(msg_header, line));
+    fn send_output(&mut self, output: Queued) {
+        match self.policy {
+            OutputPolicy::DropOldest => {
+                self.queue(output);
+                while self.queued_bytes > self.max_bytes {
+                    // Notices are kept, they're few and say what happened to the session
+                    let oldest = match self.queue.iter().position(|queued| queued.is_output()) {
+                        Some(n) => self.queue.remove(n).expect("position is in the queue"),
+                        None => break,
+                    };
+                    self.queued_bytes -= oldest.data.len();
+                    self.dropped += 1;
+                    self.drops += 1;
+                }
+            }
+            OutputPolicy::DropNewest => {
+                if self.queued_bytes + output.data.len() > self.max_bytes {
+                    self.dropped += 1;
+                    self.drops += 1;
+                    return;
+                }
+
+                // Where the gap is, ahead of the output after it
+                if self.dropped > 0 {
+                    let lines = mem::take(&mut self.dropped);
+                    let dropped = self.dropped_notice(lines);
+                    self.queue(dropped);
+                }
+                self.queue(output);
+            }
+            OutputPolicy::Pause => {
+                // Output already on its way still comes, the limit is only where we pause
+                self.queue(output);
+                if self.queued_bytes > self.max_bytes {
+                    self.paused = true;
+                }
+            }
+        }
+    }
+
+    fn queue(&mut self, queued: Queued) {
+        self.queued_bytes += queued.data.len();
+        self.queue.push_back(queued);
     }
 
-    fn send_stderr(&mut self, line: &[u8]) {
-        let msg_header = MessageHeader::new(MessageType::Stderr, line.len());
-        self.outbuffer.extend(&new_msg(msg_header, line));
+    // Clients from before Dropped get it as a notice
+    fn dropped_notice(&self, lines: u64) -> Queued {
+        let msg_type = match self.frame_version {
+            0..=2 => MessageType::Notice,
+            _ => MessageType::Dropped,
+        };
+        Queued {
+            msg_type,
+            future_id: None,
+            ts: messages::timestamp(),
+            data: format!("{} lines dropped", lines).into_bytes(),
+        }
+    }
+
+    // Frames queued output for writing, up to WRITE_BYTES
+    fn frame_queued(&mut self) {
+        while self.outbuffer.len() < WRITE_BYTES {
+            // Dropping oldest the gap is ahead of the queue, dropping newest after it
+            if self.dropped > 0
+                && (self.policy == OutputPolicy::DropOldest || self.queue.is_empty())
+            {
+                let lines = mem::take(&mut self.dropped);
+                let dropped = self.dropped_notice(lines);
+                self.frame(dropped);
+                continue;
+            }
+
+            match self.queue.pop_front() {
+                Some(queued) => {
+                    self.queued_bytes -= queued.data.len();
+                    self.frame(queued);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn frame(&mut self, queued: Queued) {
+        // Clients from before frames get the bare line
+        if self.frame_version == 0 {
+            let msg_header = MessageHeader::new(queued.msg_type, queued.data.len());
+            self.outbuffer.extend(&new_msg(msg_header, &queued.data));
+            return;
+        }
+
+        let frame = Frame {
+            future_id: queued.future_id,
+            seq: self.seq.get(),
+            ts: queued.ts,
+            data: queued.data,
+        };
+        self.outbuffer.extend(&new_frame(queued.msg_type, &frame));
+        self.seq.set(frame.seq + 1);
     }
 
     fn has_out_data(&self) -> bool {
-        !self.outbuffer.is_empty()
+        !self.outbuffer.is_empty() || !self.queue.is_empty() || self.dropped > 0
+    }
+}
+
+impl Queued {
+    fn is_output(&self) -> bool {
+        !matches!(self.msg_type, MessageType::Notice | MessageType::Dropped)
     }
 }
 
@@ -201,3 +443,263 @@ impl Source for Inner {
         Source::deregister(&mut self.stream, registry)
     }
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::net;
+
+    use protocol::outputstream::{read_frame, ClientHello, FRAME_VERSION, HEADER_SIZE};
+
+    use super::*;
+    use crate::runmaster::config::{Config, OutputPolicy};
+
+    // Our end of an output stream once the client's hello is read, and the client's end
+    pub(in crate::runmaster) fn connect(frame_version: u8) -> (OutputStream, net::TcpStream) {
+        let cfg = Config::default();
+        connect_with(frame_version, cfg.output_buffer_bytes, cfg.output_policy)
+    }
+
+    fn connect_with(
+        frame_version: u8,
+        max_bytes: usize,
+        policy: OutputPolicy,
+    ) -> (OutputStream, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (ours, _) = listener.accept().unwrap();
+        ours.set_nonblocking(true).unwrap();
+
+        let hello = protocol::new_req(
+            protocol::MessageType::Hello,
+            frame_version,
+            ClientHello {
+                stream_token: String::from("s1"),
+            },
+        );
+        client.write_all(&hello).unwrap();
+
+        let stream = OutputStream::new(
+            TcpStream::from_std(ours),
+            Token(0),
+            Interest::READABLE,
+            max_bytes,
+            policy,
+        );
+        stream.read(&mut [0; 256]).unwrap();
+        assert_eq!(stream.take_session_id().as_deref(), Some("s1"));
+
+        (stream, client)
+    }
+
+    // Everything the client gets once what's queued is written and the stream closed
+    pub(in crate::runmaster) fn received(
+        stream: OutputStream,
+        mut client: net::TcpStream,
+    ) -> Vec<String> {
+        stream.write().unwrap();
+        drop(stream);
+
+        let mut received = vec![];
+        loop {
+            let mut raw = [0; HEADER_SIZE];
+            match client.read_exact(&mut raw) {
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                res => res.unwrap(),
+            }
+            let header = MessageHeader::from_raw(raw).unwrap();
+            let mut body = vec![0; header.msg_len];
+            client.read_exact(&mut body).unwrap();
+            received.push(String::from_utf8(read_frame(&body).unwrap().data).unwrap());
+        }
+        received
+    }
+
+    fn read_msg(client: &mut net::TcpStream) -> (MessageType, Vec<u8>) {
+        let mut raw = [0; HEADER_SIZE];
+        client.read_exact(&mut raw).unwrap();
+        let header = MessageHeader::from_raw(raw).unwrap();
+
+        let mut body = vec![0; header.msg_len];
+        client.read_exact(&mut body).unwrap();
+        (header.msg_type, body)
+    }
+
+    pub(in crate::runmaster) fn output(
+        future_id: &str,
+        kind: OutputKind,
+        chunk: bool,
+        line: &[u8],
+    ) -> OutputMessage {
+        OutputMessage {
+            session_id: String::from("s1"),
+            future_id: future_id.to_owned(),
+            kind,
+            chunk,
+            ts: 42,
+            line: line.to_vec(),
+        }
+    }
+
+    #[test]
+    fn frames_tag_lines() {
+        let (stream, mut client) = connect(FRAME_VERSION);
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"one"));
+        stream.send_output(&output("f2", OutputKind::Stderr, false, b"two"));
+        stream.send_notice(b"worker exited");
+        stream.write().unwrap();
+
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Stdout));
+        let frame = read_frame(&body).unwrap();
+        assert_eq!(frame.future_id.as_deref(), Some("f1"));
+        assert_eq!((frame.seq, frame.ts, &frame.data[..]), (0, 42, &b"one"[..]));
+
+        // One sequence across stdout, stderr and notices
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Stderr));
+        let frame = read_frame(&body).unwrap();
+        assert_eq!(frame.future_id.as_deref(), Some("f2"));
+        assert_eq!((frame.seq, &frame.data[..]), (1, &b"two"[..]));
+
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Notice));
+        let frame = read_frame(&body).unwrap();
+        assert_eq!(frame.future_id, None);
+        assert_eq!(frame.seq, 2);
+        assert!(frame.ts > 42);
+    }
+
+    #[test]
+    fn version_0_clients_get_bare_lines() {
+        let (stream, mut client) = connect(0);
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"one"));
+        stream.write().unwrap();
+
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Stdout));
+        assert_eq!(body, b"one");
+    }
+
+    #[test]
+    fn chunks_have_their_own_kinds() {
+        let (stream, mut client) = connect(FRAME_VERSION);
+        stream.send_output(&output("f1", OutputKind::Stdout, true, b"prompt> "));
+        stream.send_output(&output("f1", OutputKind::Stderr, true, b"50%\r"));
+        stream.write().unwrap();
+
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::StdoutChunk));
+        assert_eq!(read_frame(&body).unwrap().data, b"prompt> ");
+        let (msg_type, _) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::StderrChunk));
+    }
+
+    #[test]
+    fn version_1_clients_get_chunks_as_lines() {
+        let (stream, mut client) = connect(1);
+        stream.send_output(&output("f1", OutputKind::Stdout, true, b"prompt> "));
+        stream.write().unwrap();
+
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Stdout));
+        assert_eq!(read_frame(&body).unwrap().data, b"prompt> ");
+    }
+
+    #[test]
+    fn drop_oldest_keeps_newest() {
+        let (stream, client) = connect_with(FRAME_VERSION, 10, OutputPolicy::DropOldest);
+        for line in [b"aaaa", b"bbbb", b"cccc"] {
+            stream.send_output(&output("f1", OutputKind::Stdout, false, line));
+        }
+
+        assert_eq!(stream.take_drops(), 1);
+        assert_eq!(stream.take_pause(), None);
+        // The gap is marked ahead of what's left
+        assert_eq!(
+            received(stream, client),
+            ["1 lines dropped", "bbbb", "cccc"]
+        );
+    }
+
+    #[test]
+    fn drop_oldest_keeps_notices() {
+        let (stream, client) = connect_with(FRAME_VERSION, 10, OutputPolicy::DropOldest);
+        stream.send_notice(b"notice");
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"aaaa"));
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"bbbb"));
+
+        assert_eq!(
+            received(stream, client),
+            ["1 lines dropped", "notice", "bbbb"]
+        );
+    }
+
+    #[test]
+    fn drop_newest_keeps_oldest() {
+        let (stream, client) = connect_with(FRAME_VERSION, 10, OutputPolicy::DropNewest);
+        for line in [b"aaaa", b"bbbb", b"cccc", b"dddd"] {
+            stream.send_output(&output("f1", OutputKind::Stdout, false, line));
+        }
+
+        assert_eq!(stream.take_drops(), 2);
+        assert_eq!(stream.take_pause(), None);
+        assert_eq!(
+            received(stream, client),
+            ["aaaa", "bbbb", "2 lines dropped"]
+        );
+    }
+
+    #[test]
+    fn drop_newest_marks_the_gap_before_later_output() {
+        let (stream, mut client) = connect_with(FRAME_VERSION, 10, OutputPolicy::DropNewest);
+        for line in [b"aaaa", b"bbbb", b"cccc"] {
+            stream.send_output(&output("f1", OutputKind::Stdout, false, line));
+        }
+        stream.write().unwrap();
+        for _ in 0..2 {
+            read_msg(&mut client);
+        }
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Dropped));
+        assert_eq!(read_frame(&body).unwrap().data, b"1 lines dropped");
+
+        // What's written no longer counts against the limit
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"dddddddd"));
+        assert_eq!(stream.take_drops(), 1);
+        assert_eq!(received(stream, client), ["dddddddd"]);
+    }
+
+    #[test]
+    fn version_2_clients_get_dropped_as_a_notice() {
+        let (stream, mut client) = connect_with(2, 4, OutputPolicy::DropNewest);
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"aaaa"));
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"bbbb"));
+        stream.write().unwrap();
+
+        read_msg(&mut client);
+        let (msg_type, body) = read_msg(&mut client);
+        assert!(matches!(msg_type, MessageType::Notice));
+        assert_eq!(read_frame(&body).unwrap().data, b"1 lines dropped");
+    }
+
+    #[test]
+    fn pause_keeps_everything() {
+        let (stream, client) = connect_with(FRAME_VERSION, 10, OutputPolicy::Pause);
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"aaaa"));
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"bbbb"));
+        assert_eq!(stream.take_pause(), None);
+
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"cccc"));
+        assert_eq!(stream.take_pause(), Some(true));
+        assert!(stream.is_paused());
+        stream.send_output(&output("f1", OutputKind::Stdout, false, b"dddd"));
+        assert_eq!(stream.take_pause(), None);
+
+        // Resumes once written out
+        stream.write().unwrap();
+        assert_eq!(stream.take_pause(), Some(false));
+        assert!(!stream.is_paused());
+        assert_eq!(stream.take_drops(), 0);
+        assert_eq!(received(stream, client), ["aaaa", "bbbb", "cccc", "dddd"]);
+    }
+}
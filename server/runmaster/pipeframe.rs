@@ -4,16 +4,11 @@ use mio::event::Source;
 use mio::unix::pipe;
 use mio::{Interest, Registry, Token};
 
-use protocol::SESSION_ID_LENGTH;
-
-use crate::messages;
-
+// A worker's own stdout or stderr, atoms' output reaches us over the worker stream
 #[derive(Debug)]
 pub struct PipeFrame {
     buffer: Vec<u8>,
     recv: pipe::Receiver,
-    current_session_id: Option<String>,
-    last_newline: Option<usize>,
 }
 
 impl PipeFrame {
@@ -21,80 +16,32 @@ impl PipeFrame {
         Self {
             recv,
             buffer: Vec::with_capacity(1024),
-            current_session_id: None,
-            last_newline: None,
         }
     }
 
-    pub fn read<'s, W: Write>(
-        &'s mut self,
-        buf: &mut [u8],
-        mut w: W,
-    ) -> io::Result<Option<(&'s str, Vec<&'s [u8]>)>> {
-        let bytes_read = self.recv.read(buf)?;
-        self.buffer.extend(&buf[..bytes_read]);
-
-        let new_req_start_len = messages::NEW_REQUEST_START.as_bytes().len();
-
-        let mut start = 0;
-        self.last_newline = None;
-
-        let mut lines = vec![];
-
-        for (n, c) in self.buffer.iter().enumerate() {
-            if *c != b'\n' {
-                continue;
-            }
-
-            let line = &self.buffer[start..n];
-            self.last_newline = Some(n);
-            start = n + 1;
-
-            // Are we starting a python callable?
-            if line.len() == new_req_start_len + (SESSION_ID_LENGTH * 2) {
-                if line.starts_with(messages::NEW_REQUEST_START.as_bytes()) {
-                    if let Ok(session_id) = std::str::from_utf8(&line[new_req_start_len..]) {
-                        self.current_session_id = Some(session_id.to_owned());
-                        continue;
-                    }
-                }
+    // Passes complete lines on to w, true once the worker has closed the pipe
+    pub fn read<W: Write>(&mut self, buf: &mut [u8], mut w: W) -> io::Result<bool> {
+        // Drain the pipe, we're edge triggered
+        let closed = loop {
+            match self.recv.read(buf) {
+                Ok(0) => break true,
+                Ok(bytes_read) => self.buffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(err) => return Err(err),
             }
+        };
 
-            // Are we ending a python callable?
-            if line == messages::NEW_REQUEST_END.as_bytes() {
-                self.current_session_id = None;
-                continue;
-            }
+        let end = match self.buffer.iter().rposition(|c| *c == b'\n') {
+            Some(last_newline) => last_newline + 1,
+            None if closed => self.buffer.len(),
+            None => return Ok(false),
+        };
 
-            // Save the line if we are in python callable
-            if self.current_session_id.is_some() {
-                lines.push(line);
-            } else {
-                // otherwise write it
-                w.write_all(line)?;
-                w.write_all(b"\n")?;
-                w.flush()?;
-            }
-        }
-
-        Ok(self
-            .current_session_id
-            .as_ref()
-            .map(|s| &s[..])
-            .zip(Some(lines)))
-    }
-
-    pub fn clear(&mut self) {
-        if let Some(last_newline) = self.last_newline {
-            let bytes_remaining = self.buffer.len() - last_newline;
-            for n in 0..bytes_remaining {
-                self.buffer[n] = self.buffer[n + last_newline];
-            }
-
-            self.buffer.truncate(bytes_remaining);
-        }
+        w.write_all(&self.buffer[..end])?;
+        w.flush()?;
+        self.buffer.drain(..end);
 
-        self.last_newline = None;
+        Ok(closed)
     }
 }
 
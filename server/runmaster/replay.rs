@@ -0,0 +1,152 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::messages::OutputMessage;
+
+use super::config::Config;
+use super::outputstream::OutputStream;
+
+// Output of sessions without an output stream, e.g. printed before the client's
+// connected or while it reconnects. Sent once a stream attaches.
+pub struct Replays {
+    max_bytes: usize,
+    max_lines: usize,
+    sessions: HashMap<String, Replay>,
+}
+
+#[derive(Default)]
+struct Replay {
+    outputs: VecDeque<OutputMessage>,
+    bytes: usize,
+    // Oldest lines pushed out by newer ones
+    dropped: u64,
+}
+
+impl Replays {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            max_bytes: cfg.replay_bytes,
+            max_lines: cfg.replay_lines,
+            sessions: HashMap::new(),
+        }
+    }
+
+    // Returns how many lines it dropped to make room
+    pub fn push(&mut self, output: OutputMessage) -> u64 {
+        let replay = self.sessions.entry(output.session_id.clone()).or_default();
+        replay.bytes += output.line.len();
+        replay.outputs.push_back(output);
+
+        let mut dropped = 0;
+        while replay.outputs.len() > self.max_lines || replay.bytes > self.max_bytes {
+            match replay.outputs.pop_front() {
+                Some(oldest) => {
+                    replay.bytes -= oldest.line.len();
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+
+        replay.dropped += dropped;
+        dropped
+    }
+
+    // Sends what the session's stream missed, how much was dropped first as that came first
+    pub fn flush(&mut self, session_id: &str, output_stream: &OutputStream) {
+        if let Some(replay) = self.sessions.remove(session_id) {
+            if replay.dropped > 0 {
+                output_stream.send_dropped(replay.dropped);
+            }
+            for output in replay.outputs.iter() {
+                output_stream.send_output(output);
+            }
+        }
+    }
+
+    // The session has gone, nobody will attach for its output
+    pub fn discard(&mut self, session_id: &str) {
+        self.sessions.remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::outputstream::FRAME_VERSION;
+
+    use super::*;
+    use crate::messages::OutputKind;
+    use crate::runmaster::outputstream::tests::{connect, output, received};
+
+    fn push(replays: &mut Replays, session_id: &str, line: &str) {
+        let mut output = output("future", OutputKind::Stdout, false, line.as_bytes());
+        output.session_id = session_id.to_owned();
+        replays.push(output);
+    }
+
+    // What a client attaching for the session is sent
+    fn replayed(replays: &mut Replays, session_id: &str) -> Vec<String> {
+        let (stream, client) = connect(FRAME_VERSION);
+        replays.flush(session_id, &stream);
+        received(stream, client)
+    }
+
+    fn limits(replay_lines: usize, replay_bytes: usize) -> Config {
+        Config {
+            replay_lines,
+            replay_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn output_within_limits_is_replayed_once() {
+        let mut replays = Replays::new(&limits(3, 100));
+        for line in ["one", "two", "three"] {
+            push(&mut replays, "s", line);
+        }
+
+        assert_eq!(replayed(&mut replays, "s"), ["one", "two", "three"]);
+        assert!(replayed(&mut replays, "s").is_empty());
+    }
+
+    #[test]
+    fn line_limit_drops_oldest() {
+        let mut replays = Replays::new(&limits(2, 100));
+        for line in ["one", "two", "three", "four"] {
+            push(&mut replays, "s", line);
+        }
+
+        // How much was dropped comes first
+        assert_eq!(
+            replayed(&mut replays, "s"),
+            ["2 lines dropped", "three", "four"]
+        );
+    }
+
+    #[test]
+    fn byte_limit_drops_as_many_as_it_takes() {
+        let mut replays = Replays::new(&limits(100, 10));
+        for line in ["aaaa", "bbbb", "cccc", "dddddddd"] {
+            push(&mut replays, "s", line);
+        }
+        assert_eq!(replayed(&mut replays, "s"), ["3 lines dropped", "dddddddd"]);
+
+        // A line past the limit on its own goes too
+        push(&mut replays, "s", "much too long a line");
+        assert_eq!(replayed(&mut replays, "s"), ["1 lines dropped"]);
+    }
+
+    #[test]
+    fn sessions_are_held_apart() {
+        let mut replays = Replays::new(&limits(1, 100));
+        push(&mut replays, "a", "a1");
+        push(&mut replays, "b", "b1");
+        push(&mut replays, "a", "a2");
+        push(&mut replays, "c", "c1");
+
+        replays.discard("c");
+        assert!(replayed(&mut replays, "c").is_empty());
+        assert_eq!(replayed(&mut replays, "a"), ["1 lines dropped", "a2"]);
+        assert_eq!(replayed(&mut replays, "b"), ["b1"]);
+    }
+}
@@ -0,0 +1,43 @@
+use std::time;
+
+use ndjsonlogger::info;
+
+// How often the master logs its stats, if they've changed
+const REPORT_EVERY: time::Duration = time::Duration::from_secs(60);
+
+// Counters logged now and then, and once more as the master shuts down
+pub struct Stats {
+    // Lines of output dropped for output streams behind or not yet attached
+    pub output_lines_dropped: u64,
+    // Times a session's atoms were paused for its output stream to catch up
+    pub output_pauses: u64,
+    reported: (u64, u64),
+    last_report: time::Instant,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            output_lines_dropped: 0,
+            output_pauses: 0,
+            reported: (0, 0),
+            last_report: time::Instant::now(),
+        }
+    }
+
+    pub fn report_due(&mut self, now: time::Instant) {
+        let counts = (self.output_lines_dropped, self.output_pauses);
+        if counts != self.reported && now.duration_since(self.last_report) >= REPORT_EVERY {
+            self.report();
+        }
+    }
+
+    pub fn report(&mut self) {
+        info!("master stats", {
+            output_lines_dropped: u64 = self.output_lines_dropped,
+            output_pauses: u64 = self.output_pauses
+        });
+        self.reported = (self.output_lines_dropped, self.output_pauses);
+        self.last_report = time::Instant::now();
+    }
+}
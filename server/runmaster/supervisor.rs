@@ -0,0 +1,441 @@
+use std::collections::VecDeque;
+use std::env;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path;
+use std::process;
+use std::time;
+
+use mio::event::Source;
+use mio::unix::{pipe, SourceFd};
+use mio::{Interest, Registry, Token};
+
+use crate::signals;
+
+use super::config::{Config, WorkerEnv, WorkerMode};
+use super::errors::{Error, Result};
+use super::forkserver::ForkServer;
+
+// Respawn delay after the first crash, doubled for each further crash in the restart window
+const BACKOFF_START: time::Duration = time::Duration::from_millis(100);
+const BACKOFF_MAX: time::Duration = time::Duration::from_secs(30);
+
+// A worker process, either exec'd or forked, always our child
+pub struct Worker {
+    pub pid: u32,
+    pub stdout: pipe::Receiver,
+    pub stderr: pipe::Receiver,
+}
+
+impl Worker {
+    pub fn spawn(
+        workerbin: &path::Path,
+        sock_path: &path::Path,
+        worker_env: &WorkerEnv,
+    ) -> io::Result<Self> {
+        let mut child = worker_command(workerbin, worker_env)
+            .arg(sock_path)
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()?;
+
+        // We reap it through ChildWatch, dropping the Child doesn't kill it
+        Ok(Self {
+            pid: child.id(),
+            stdout: pipe::Receiver::from(child.stdout.take().unwrap()),
+            stderr: pipe::Receiver::from(child.stderr.take().unwrap()),
+        })
+    }
+
+    // For a worker we're giving up on before it's watched
+    pub fn kill(self) {
+        unsafe {
+            libc::kill(self.pid as libc::pid_t, libc::SIGKILL);
+            libc::waitpid(self.pid as libc::pid_t, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+// The worker binary, run in the python environment workers are configured with
+pub fn worker_command(workerbin: &path::Path, worker_env: &WorkerEnv) -> process::Command {
+    let mut cmd = process::Command::new(workerbin);
+
+    let join = |paths: &[path::PathBuf]| env::join_paths(paths).unwrap_or_default();
+    cmd.env(
+        "PYPROXY_PRELOAD_MODULES",
+        worker_env.preload_modules.join(","),
+    )
+    .env("PYPROXY_PYTHON_PATH", join(&worker_env.python_path))
+    .envs(worker_env.env.iter().map(|(k, v)| (k, v)));
+
+    if let Some(warmup_script) = &worker_env.warmup_script {
+        cmd.env("PYPROXY_WARMUP_SCRIPT", warmup_script);
+    }
+
+    if let Some(cwd) = &worker_env.cwd {
+        cmd.current_dir(cwd);
+    }
+
+    // We block the shutdown signals for our signal fd, workers and what they
+    // start must still be able to receive them
+    unsafe {
+        cmd.pre_exec(|| signals::unblock(&signals::SHUTDOWN_SIGNALS));
+    }
+
+    cmd
+}
+
+// Starts workers the way PYPROXY_WORKER_MODE asks
+pub enum Spawner {
+    Exec {
+        workerbin: path::PathBuf,
+        sock_path: path::PathBuf,
+        worker_env: WorkerEnv,
+    },
+    Fork(ForkServer),
+}
+
+impl Spawner {
+    pub fn new(cfg: &Config, sock_path: &path::Path) -> io::Result<Self> {
+        match cfg.worker_mode {
+            WorkerMode::Exec => Ok(Spawner::Exec {
+                workerbin: cfg.workerbin.clone(),
+                sock_path: sock_path.to_owned(),
+                worker_env: cfg.worker_env.clone(),
+            }),
+            WorkerMode::ForkServer | WorkerMode::ForkPerSession => {
+                ForkServer::start(&cfg.workerbin, sock_path, &cfg.worker_env).map(Spawner::Fork)
+            }
+        }
+    }
+
+    // Exec'd workers start straight away, forked ones come back
+    // from forked once the fork server has forked them
+    pub fn spawn(&mut self) -> io::Result<Option<Worker>> {
+        match self {
+            Spawner::Exec {
+                workerbin,
+                sock_path,
+                worker_env,
+            } => Worker::spawn(workerbin, sock_path, worker_env).map(Some),
+            Spawner::Fork(fork_server) => fork_server.fork().map(|()| None),
+        }
+    }
+
+    pub fn forked(&mut self) -> Vec<io::Result<Worker>> {
+        match self {
+            Spawner::Exec { .. } => vec![],
+            Spawner::Fork(fork_server) => fork_server.forked(),
+        }
+    }
+
+    // Workers asked for which haven't come back from forked yet
+    pub fn pending(&self) -> usize {
+        match self {
+            Spawner::Exec { .. } => 0,
+            Spawner::Fork(fork_server) => fork_server.pending(),
+        }
+    }
+}
+
+// A worker process, readable through mio once it exits.
+// One still running when dropped is killed, so an error return leaves none behind.
+#[derive(Debug)]
+pub struct ChildWatch {
+    pid: u32,
+    pidfd: OwnedFd,
+    reaped: bool,
+}
+
+impl ChildWatch {
+    pub fn new(pid: u32) -> io::Result<Self> {
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if pidfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            pid,
+            pidfd: unsafe { OwnedFd::from_raw_fd(pidfd as i32) },
+            reaped: false,
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    // Ask the worker to finish its in-flight atoms and exit
+    pub fn terminate(&self) {
+        unsafe {
+            libc::kill(self.pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+
+    pub fn kill(&mut self) {
+        unsafe {
+            libc::kill(self.pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+
+    // Reap the worker, None if it's still running
+    pub fn try_wait(&mut self) -> io::Result<Option<process::ExitStatus>> {
+        let mut status = 0;
+        match unsafe { libc::waitpid(self.pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+            0 => Ok(None),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => {
+                self.reaped = true;
+                Ok(Some(process::ExitStatus::from_raw(status)))
+            }
+        }
+    }
+}
+
+impl Drop for ChildWatch {
+    fn drop(&mut self) {
+        if !self.reaped {
+            self.kill();
+            unsafe {
+                libc::waitpid(self.pid as libc::pid_t, std::ptr::null_mut(), 0);
+            }
+        }
+    }
+}
+
+impl Source for ChildWatch {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.pidfd.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.pidfd.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.pidfd.as_raw_fd()).deregister(registry)
+    }
+}
+
+// Decides when dead workers are respawned
+pub struct Supervisor {
+    restart_limit: usize,
+    restart_window: time::Duration,
+
+    // When workers exited, within the restart window
+    crashes: VecDeque<time::Instant>,
+
+    // When each pending respawn is due, in order
+    respawns: VecDeque<time::Instant>,
+}
+
+impl Supervisor {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            restart_limit: cfg.restart_limit,
+            restart_window: cfg.restart_window,
+            crashes: VecDeque::with_capacity(cfg.restart_limit + 1),
+            respawns: VecDeque::with_capacity(cfg.num_workers),
+        }
+    }
+
+    // Schedule a respawn for a worker which exited, returning the backoff.
+    // Errors once workers exit more than restart_limit times in the restart window.
+    pub fn exited(&mut self, now: time::Instant) -> Result<time::Duration> {
+        while let Some(crash) = self.crashes.front() {
+            if now.duration_since(*crash) < self.restart_window {
+                break;
+            }
+            self.crashes.pop_front();
+        }
+
+        self.crashes.push_back(now);
+        if self.crashes.len() > self.restart_limit {
+            return Err(Error::WorkerCrashLoop(self.crashes.len()));
+        }
+
+        let backoff = BACKOFF_START
+            .saturating_mul(1 << (self.crashes.len() - 1).min(16))
+            .min(BACKOFF_MAX);
+
+        // Keep respawns in order, a later crash may have a shorter backoff
+        let due = self
+            .respawns
+            .back()
+            .map_or(now + backoff, |last| (*last).max(now + backoff));
+        self.respawns.push_back(due);
+
+        Ok(backoff)
+    }
+
+    // Take the number of respawns which are due
+    pub fn due(&mut self, now: time::Instant) -> usize {
+        let mut n = 0;
+        while self.respawns.front().is_some_and(|due| *due <= now) {
+            self.respawns.pop_front();
+            n += 1;
+        }
+        n
+    }
+
+    // Respawns scheduled but not yet due
+    pub fn pending(&self) -> usize {
+        self.respawns.len()
+    }
+
+    // How long until the next respawn is due, None if there are none pending
+    pub fn next_due(&self, now: time::Instant) -> Option<time::Duration> {
+        self.respawns
+            .front()
+            .map(|due| due.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> time::Duration {
+        time::Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn backoff_doubles_per_crash() {
+        let mut sup = Supervisor::new(&Config {
+            restart_limit: 10,
+            ..Config::default()
+        });
+        let now = time::Instant::now();
+
+        let backoffs: Vec<_> = (0..4).map(|_| sup.exited(now).unwrap()).collect();
+        assert_eq!(backoffs, [ms(100), ms(200), ms(400), ms(800)]);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut sup = Supervisor::new(&Config {
+            restart_limit: 100,
+            restart_window: time::Duration::from_secs(3600),
+            ..Config::default()
+        });
+        let now = time::Instant::now();
+
+        let backoffs: Vec<_> = (0..40).map(|_| sup.exited(now).unwrap()).collect();
+        assert_eq!(backoffs[9], ms(30_000));
+        assert!(backoffs.iter().all(|backoff| *backoff <= BACKOFF_MAX));
+        assert_eq!(backoffs[39], BACKOFF_MAX);
+    }
+
+    #[test]
+    fn crashes_outside_window_are_forgotten() {
+        let cfg = Config {
+            restart_limit: 2,
+            ..Config::default()
+        };
+        let mut sup = Supervisor::new(&cfg);
+        let start = time::Instant::now();
+        sup.exited(start).unwrap();
+        sup.exited(start).unwrap();
+
+        // A third crash in the window would be a crash loop, once it has passed we start over
+        let later = start + cfg.restart_window;
+        assert_eq!(sup.exited(later).unwrap(), ms(100));
+    }
+
+    #[test]
+    fn crash_loop_past_restart_limit() {
+        let mut sup = Supervisor::new(&Config {
+            restart_limit: 3,
+            ..Config::default()
+        });
+        let now = time::Instant::now();
+        for _ in 0..3 {
+            sup.exited(now).unwrap();
+        }
+
+        assert!(matches!(sup.exited(now), Err(Error::WorkerCrashLoop(4))));
+    }
+
+    #[test]
+    fn failed_respawns_count_towards_crash_loop() {
+        let cfg = Config {
+            restart_limit: 2,
+            workerbin: path::PathBuf::from("/nonexistent/worker"),
+            ..Config::default()
+        };
+        let mut sup = Supervisor::new(&cfg);
+        let mut spawner = Spawner::new(&cfg, path::Path::new("/nonexistent/sock")).unwrap();
+        let mut now = time::Instant::now();
+        sup.exited(now).unwrap();
+
+        // Each respawn fails in turn, as the master's would, until it gives up
+        let mut attempts = 0;
+        let err = loop {
+            now += sup.next_due(now).unwrap();
+            assert_eq!(sup.due(now), 1);
+            attempts += 1;
+            assert!(spawner.spawn().is_err());
+            if let Err(err) = sup.exited(now) {
+                break err;
+            }
+        };
+        assert_eq!(attempts, 2);
+        assert!(matches!(err, Error::WorkerCrashLoop(3)));
+    }
+
+    #[test]
+    fn respawns_come_due_in_order() {
+        let mut sup = Supervisor::new(&Config::default());
+        let now = time::Instant::now();
+        sup.exited(now).unwrap();
+        sup.exited(now).unwrap();
+        assert_eq!(sup.next_due(now), Some(ms(100)));
+
+        assert_eq!(sup.due(now + ms(99)), 0);
+        assert_eq!(sup.due(now + ms(100)), 1);
+        assert_eq!(sup.next_due(now + ms(100)), Some(ms(100)));
+        assert_eq!(sup.due(now + ms(200)), 1);
+        assert_eq!(sup.next_due(now), None);
+    }
+
+    // The ChildWatch reaps it
+    #[test]
+    #[allow(clippy::zombie_processes)]
+    fn child_watch_readable_on_exit() {
+        let child = process::Command::new("true").spawn().unwrap();
+        let mut watch = ChildWatch::new(child.id()).unwrap();
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(4);
+        poll.registry()
+            .register(&mut watch, Token(0), Interest::READABLE)
+            .unwrap();
+
+        poll.poll(&mut events, Some(time::Duration::from_secs(5)))
+            .unwrap();
+        assert!(events.iter().any(|ev| ev.token() == Token(0)));
+        assert!(watch.try_wait().unwrap().unwrap().success());
+    }
+
+    // The ChildWatch reaps it
+    #[test]
+    #[allow(clippy::zombie_processes)]
+    fn dropped_child_watch_kills_its_worker() {
+        let child = process::Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+        drop(ChildWatch::new(child.id()).unwrap());
+
+        // Reaped as well, so the pid has gone
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+    }
+}
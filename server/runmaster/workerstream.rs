@@ -1,8 +1,10 @@
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::io::{self, Read, Write};
-use std::os::fd::RawFd;
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
 use std::rc::Rc;
+use std::time;
 
 use fd_queue::mio::UnixStream as FdUnixStream;
 use fd_queue::EnqueueFd;
@@ -10,11 +12,10 @@ use mio::event::Source;
 use mio::net::UnixStream as MioUnixStream;
 use mio::{Interest, Registry, Token};
 use ndjsonlogger::{error, warn};
-use ndjsonloggercore as logger;
-use serde_json::Value as JsonValue;
 
-use crate::messages::{self, LogValue};
+use crate::messages::{self, LoadMessage};
 
+use super::config::Dispatch;
 use super::errors::{self, fatal_io_err};
 
 #[derive(Clone, Debug)]
@@ -25,6 +26,9 @@ pub struct WorkerStream {
 pub struct WorkerStreams {
     streams: Vec<(Token, WorkerStream)>,
     last_send: usize,
+    strategy: Dispatch,
+    // Each worker is closed once it's been sent a session, see WorkerMode::ForkPerSession
+    single_session: bool,
 }
 
 #[derive(Debug)]
@@ -35,27 +39,180 @@ struct Inner {
 
     // Current Mio interest
     interest: Interest,
+
+    // pid of the worker process on the other end
+    pid: u32,
+
+    // Set once the worker has gone or is retiring, we no longer dispatch to it
+    closed: bool,
+
+    // Sessions the worker holds
+    sessions: HashSet<String>,
+
+    // Client streams we've dispatched to the worker in order, we keep their fds open.
+    // Those it returns are taken out.
+    clients: Vec<Option<Token>>,
+
+    // Client streams the worker returned unopened, to dispatch again
+    returned: Vec<Token>,
+
+    // Last load the worker reported
+    load: LoadMessage,
+
+    // Sessions dispatched since the worker last reported its load.
+    // Their atoms aren't queued until the worker reads them.
+    dispatched: u32,
+
+    // When the worker last held no sessions
+    idle_since: Option<time::Instant>,
+
+    // Why the worker wants to be recycled, until the master takes it
+    recycle: Option<String>,
+
+    // Output of the worker's atoms, until the master routes it
+    output: Vec<messages::OutputMessage>,
+
+    // Sessions the worker has closed, until the master drops their held output
+    closed_sessions: Vec<String>,
 }
 
 impl WorkerStream {
     pub fn new(stream: MioUnixStream, interest: Interest) -> io::Result<Self> {
+        let pid = peer_pid(stream.as_raw_fd())?;
+
         Ok(Self {
             inner: Rc::new(RefCell::new(Inner {
                 stream: FdUnixStream::try_from(stream)?,
                 outbuffer: Vec::with_capacity(1024),
                 inbuffer: Vec::with_capacity(64),
                 interest,
+                pid,
+                closed: false,
+                sessions: HashSet::new(),
+                clients: vec![],
+                returned: vec![],
+                load: LoadMessage::default(),
+                dispatched: 0,
+                idle_since: Some(time::Instant::now()),
+                recycle: None,
+                output: vec![],
+                closed_sessions: vec![],
             })),
         })
     }
 
-    pub fn dispatch(&mut self, header: [u8; protocol::REQUEST_HEADER_SIZE], fd: RawFd) {
-        if self.inner.borrow_mut().send_fd(fd).is_err() {
-            error!("master couldn't send fd to worker - queue full");
+    pub fn pid(&self) -> u32 {
+        self.inner.borrow().pid
+    }
+
+    pub fn dispatch(
+        &mut self,
+        header: [u8; protocol::REQUEST_HEADER_SIZE],
+        fd: RawFd,
+        tk: Token,
+    ) -> Result<(), fd_queue::QueueFullError> {
+        self.inner.borrow_mut().send_fd(fd)?;
+
+        let mut inner = self.inner.borrow_mut();
+        // Anything else we send the worker starts with its nonzero type
+        let mut header = header;
+        header[0] = 0;
+        inner.append_buf(&header);
+        inner.clients.push(Some(tk));
+
+        // Count it now, so a burst of sessions doesn't all pick this worker
+        // before it reports its new load
+        inner.dispatched += 1;
+        inner.idle_since = None;
+        Ok(())
+    }
+
+    // Last load the worker reported, counting sessions dispatched since
+    pub fn load(&self) -> LoadMessage {
+        let inner = self.inner.borrow();
+        LoadMessage {
+            sessions: inner.load.sessions + inner.dispatched,
+            ..inner.load
+        }
+    }
+
+    pub fn dispatched(&self) -> u32 {
+        self.inner.borrow().dispatched
+    }
+
+    pub fn idle_since(&self) -> Option<time::Instant> {
+        self.inner.borrow().idle_since
+    }
+
+    pub fn take_recycle(&self) -> Option<String> {
+        self.inner.borrow_mut().recycle.take()
+    }
+
+    pub fn take_output(&self) -> Vec<messages::OutputMessage> {
+        mem::take(&mut self.inner.borrow_mut().output)
+    }
+
+    pub fn take_closed_sessions(&self) -> Vec<String> {
+        mem::take(&mut self.inner.borrow_mut().closed_sessions)
+    }
+
+    pub fn close(&self) {
+        self.inner.borrow_mut().closed = true;
+    }
+
+    // Stop dispatching to a worker which is still there, and tell it none are to come
+    pub fn retire(&self) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.closed {
             return;
         }
+        inner.closed = true;
+
+        let msg = bincode::serialize(&messages::SessionMessage {
+            session_id: String::new(),
+        })
+        .expect("couldn't serialize SessionMessage");
+        inner.outbuffer.push(messages::DISPATCH_STOPPED);
+        inner.outbuffer.extend(&(msg.len() as u32).to_be_bytes());
+        inner.outbuffer.extend(&msg);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().closed
+    }
+
+    pub fn sessions(&self) -> Vec<String> {
+        self.inner.borrow().sessions.iter().cloned().collect()
+    }
+
+    pub fn holds(&self, session_id: &str) -> bool {
+        self.inner.borrow().sessions.contains(session_id)
+    }
+
+    // Stop or start reading the output of the session's atoms
+    pub fn pause_output(&self, session_id: &str, pause: bool) {
+        let msg_type = match pause {
+            true => messages::PAUSE_OUTPUT,
+            false => messages::RESUME_OUTPUT,
+        };
+        let msg = bincode::serialize(&messages::SessionMessage {
+            session_id: session_id.to_owned(),
+        })
+        .expect("couldn't serialize SessionMessage");
+
+        let mut inner = self.inner.borrow_mut();
+        inner.outbuffer.push(msg_type);
+        inner.outbuffer.extend(&(msg.len() as u32).to_be_bytes());
+        inner.outbuffer.extend(&msg);
+    }
+
+    pub fn take_clients(&self) -> Vec<Token> {
+        let mut inner = self.inner.borrow_mut();
+        inner.clients.drain(..).flatten().collect()
+    }
 
-        self.inner.borrow_mut().append_buf(&header);
+    pub fn take_returned(&self) -> Vec<Token> {
+        mem::take(&mut self.inner.borrow_mut().returned)
     }
 
     pub fn has_data(&self) -> bool {
@@ -80,10 +237,12 @@ impl WorkerStream {
 }
 
 impl WorkerStreams {
-    pub fn new() -> Self {
+    pub fn new(strategy: Dispatch, single_session: bool) -> Self {
         Self {
             streams: vec![],
             last_send: 0,
+            strategy,
+            single_session,
         }
     }
 
@@ -91,30 +250,134 @@ impl WorkerStreams {
         self.streams.push((tk, stream));
     }
 
+    // Remove the stream of a worker process which has exited
+    pub fn remove_pid(&mut self, pid: u32) -> Option<(Token, WorkerStream)> {
+        let n = self.streams.iter().position(|(_, s)| s.pid() == pid)?;
+        Some(self.streams.remove(n))
+    }
+
+    // Returns the client streams of sessions no worker could take
     pub fn dispatch(
         &mut self,
-        new_requests: &mut VecDeque<([u8; protocol::REQUEST_HEADER_SIZE], RawFd)>,
-    ) {
-        if self.streams.is_empty() {
-            if !new_requests.is_empty() {
-                warn!("no registered workers to dispatch request to");
+        new_requests: &mut VecDeque<([u8; protocol::REQUEST_HEADER_SIZE], RawFd, Token)>,
+    ) -> Vec<Token> {
+        let mut rejected = vec![];
+
+        while let Some((header, fd, tk)) = new_requests.front() {
+            // A worker whose fd queue is full is passed over for the next
+            let mut full = vec![];
+            loop {
+                let n = match self.pick(&full) {
+                    Some(n) => n,
+                    None if full.is_empty() => {
+                        warn!("no registered workers to dispatch request to");
+                        return rejected;
+                    }
+                    None => {
+                        rejected.push(*tk);
+                        break;
+                    }
+                };
+
+                if self.streams[n].1.dispatch(*header, *fd, *tk).is_err() {
+                    error!("master couldn't send fd to worker - queue full", {
+                        pid: u32 = self.streams[n].1.pid()
+                    });
+                    full.push(n);
+                    continue;
+                }
+
+                if self.single_session {
+                    // The worker asks to be recycled when it opens the session
+                    self.streams[n].1.retire();
+                }
+                break;
             }
-            return;
+            new_requests.pop_front();
+        }
+
+        rejected
+    }
+
+    // Worker for the next session, ties go to the next worker round robin
+    fn pick(&mut self, skip: &[usize]) -> Option<usize> {
+        let len = self.streams.len();
+        if len == 0 {
+            return None;
         }
 
         self.last_send += 1;
-        self.last_send %= self.streams.len();
+        self.last_send %= len;
+
+        // Workers which have gone stay here until their process is reaped
+        (0..len)
+            .map(|n| (self.last_send + n) % len)
+            .filter(|n| !self.streams[*n].1.is_closed() && !skip.contains(n))
+            .min_by_key(|n| {
+                let stream = &self.streams[*n].1;
+                let load = stream.load();
+                match self.strategy {
+                    Dispatch::RoundRobin => (0, 0),
+                    Dispatch::LeastSessions => (load.sessions, 0),
+                    // Sessions not yet counted in the queue break ties
+                    Dispatch::LeastQueued => (load.queued_atoms, stream.dispatched()),
+                }
+            })
+    }
 
-        while let Some((header, fd)) = new_requests.pop_front() {
-            self.streams[self.last_send].1.dispatch(header, fd);
+    // On the worker holding the session, workers we no longer dispatch to included
+    pub fn pause_output(&self, session_id: &str, pause: bool) {
+        for (_, stream) in self.streams.iter() {
+            if stream.holds(session_id) {
+                stream.pause_output(session_id, pause);
+            }
         }
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<(Token, WorkerStream)> {
+    // Workers we still dispatch to
+    pub fn live(&self) -> impl Iterator<Item = &WorkerStream> {
+        self.streams
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| !s.is_closed())
+    }
+
+    // Stop dispatching to workers we're about to retire
+    pub fn close_pids(&self, pids: &[u32]) {
+        for stream in self.live() {
+            if pids.contains(&stream.pid()) {
+                stream.retire();
+            }
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, (Token, WorkerStream)> {
         self.streams.iter_mut()
     }
 }
 
+// Workers connect to our unix socket, the kernel tells us who they are
+fn peer_pid(fd: RawFd) -> io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(cred.pid as u32)
+}
+
 impl Inner {
     fn send_fd(&mut self, fd: RawFd) -> Result<(), fd_queue::QueueFullError> {
         self.stream.enqueue(&fd)
@@ -139,32 +402,34 @@ impl Inner {
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        let bytes_read = self.stream.read(buf)?;
-        if bytes_read == 0 {
-            Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "worker stream closed",
-            ))?;
-        }
-
-        self.inbuffer.extend(&buf[..bytes_read]);
+        // Drain the stream, we're edge triggered
+        let closed = loop {
+            match self.stream.read(buf) {
+                Ok(0) => break true,
+                Ok(bytes_read) => self.inbuffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(err) => return Err(err),
+            }
+        };
 
-        while self.inbuffer.len() >= 5 {
-            let msg_type = self.inbuffer[0];
+        // Output comes thousands of messages at a time, consumed bytes are removed once
+        let mut start = 0;
+        while self.inbuffer.len() - start >= 5 {
+            let msg_type = self.inbuffer[start];
             let msg_len = (u32::from_be_bytes([
-                self.inbuffer[1],
-                self.inbuffer[2],
-                self.inbuffer[3],
-                self.inbuffer[4],
+                self.inbuffer[start + 1],
+                self.inbuffer[start + 2],
+                self.inbuffer[start + 3],
+                self.inbuffer[start + 4],
             ])) as usize;
 
-            let msg_end = msg_len + 5;
+            let msg_end = start + msg_len + 5;
 
             if self.inbuffer.len() < msg_end {
                 break;
             }
 
-            let msg = &self.inbuffer[5..msg_end];
+            let msg = &self.inbuffer[start + 5..msg_end];
 
             match msg_type {
                 messages::LOG_MESSAGE => {
@@ -173,9 +438,46 @@ impl Inner {
 
                     println!("got log message {:?}", msg);
                 }
-                messages::PRINT_MESSAGE => {
-                    let msg: messages::PrintMessage =
-                        bincode::deserialize(msg).expect("master couldn't deserialze PrintMessage");
+                messages::OUTPUT_MESSAGE => {
+                    let msg: messages::OutputMessage = bincode::deserialize(msg)
+                        .expect("master couldn't deserialize OutputMessage");
+                    self.output.push(msg);
+                }
+                messages::LOAD_MESSAGE => {
+                    self.load =
+                        bincode::deserialize(msg).expect("master couldn't deserialize LoadMessage");
+                    self.dispatched = 0;
+                    self.idle_since = match self.load.sessions {
+                        0 => self.idle_since.or_else(|| Some(time::Instant::now())),
+                        _ => None,
+                    };
+                }
+                messages::RECYCLE_MESSAGE => {
+                    let msg: messages::RecycleMessage = bincode::deserialize(msg)
+                        .expect("master couldn't deserialize RecycleMessage");
+                    self.recycle = Some(msg.reason);
+                }
+                messages::SESSION_RETURNED => {
+                    let msg: messages::ReturnedMessage = bincode::deserialize(msg)
+                        .expect("master couldn't deserialize ReturnedMessage");
+                    if let Some(tk) = self
+                        .clients
+                        .get_mut(msg.nth as usize)
+                        .and_then(Option::take)
+                    {
+                        self.returned.push(tk);
+                    }
+                }
+                messages::SESSION_OPENED => {
+                    let msg: messages::SessionMessage = bincode::deserialize(msg)
+                        .expect("master couldn't deserialize SessionMessage");
+                    self.sessions.insert(msg.session_id);
+                }
+                messages::SESSION_CLOSED => {
+                    let msg: messages::SessionMessage = bincode::deserialize(msg)
+                        .expect("master couldn't deserialize SessionMessage");
+                    self.sessions.remove(&msg.session_id);
+                    self.closed_sessions.push(msg.session_id);
                 }
                 _ => {
                     error!("master received unrecognised message type", {
@@ -184,11 +486,16 @@ impl Inner {
                 }
             }
 
-            let bytes_remaining = self.inbuffer.len() - msg_end;
-            for n in 0..bytes_remaining {
-                self.inbuffer[n] = self.inbuffer[n + msg_end];
-            }
-            self.inbuffer.truncate(bytes_remaining);
+            start = msg_end;
+        }
+        self.inbuffer.drain(..start);
+
+        // After taking what the worker sent before it went
+        if closed {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "worker stream closed",
+            ))?;
         }
 
         Ok(())
@@ -245,3 +552,119 @@ impl Source for Inner {
         Source::deregister(&mut self.stream, registry)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use mio::net::UnixStream;
+
+    use super::*;
+
+    // Workers reporting these (sessions, queued_atoms) loads, with the worker ends of their streams
+    fn workers(strategy: Dispatch, loads: &[(u32, u32)]) -> (WorkerStreams, Vec<UnixStream>) {
+        let mut streams = WorkerStreams::new(strategy, false);
+        let mut peers = vec![];
+
+        for (n, (sessions, queued_atoms)) in loads.iter().enumerate() {
+            let (ours, mut theirs) = UnixStream::pair().unwrap();
+            let stream = WorkerStream::new(ours, Interest::READABLE).unwrap();
+
+            let msg = bincode::serialize(&LoadMessage {
+                sessions: *sessions,
+                queued_atoms: *queued_atoms,
+            })
+            .unwrap();
+            theirs.write_all(&[messages::LOAD_MESSAGE]).unwrap();
+            theirs.write_all(&(msg.len() as u32).to_be_bytes()).unwrap();
+            theirs.write_all(&msg).unwrap();
+            stream.read(&mut [0; 256]).unwrap();
+
+            streams.add(Token(n), stream);
+            peers.push(theirs);
+        }
+
+        (streams, peers)
+    }
+
+    // Dispatch new sessions, returning how many each worker was sent
+    fn dispatch(streams: &mut WorkerStreams, sessions: usize) -> Vec<u32> {
+        let (client, _peer) = UnixStream::pair().unwrap();
+        let mut new_requests: VecDeque<_> = (0..sessions)
+            .map(|n| {
+                (
+                    [0; protocol::REQUEST_HEADER_SIZE],
+                    client.as_raw_fd(),
+                    Token(100 + n),
+                )
+            })
+            .collect();
+
+        assert!(streams.dispatch(&mut new_requests).is_empty());
+        streams.live().map(|stream| stream.dispatched()).collect()
+    }
+
+    #[test]
+    fn round_robin_ignores_load() {
+        let (mut streams, _peers) = workers(Dispatch::RoundRobin, &[(9, 9), (0, 0), (5, 5)]);
+        assert_eq!(dispatch(&mut streams, 3), [1, 1, 1]);
+        assert_eq!(dispatch(&mut streams, 3), [2, 2, 2]);
+    }
+
+    #[test]
+    fn least_sessions_counts_dispatched_sessions() {
+        let (mut streams, _peers) = workers(Dispatch::LeastSessions, &[(3, 0), (1, 0)]);
+        assert_eq!(dispatch(&mut streams, 2), [0, 2]);
+
+        // Level now, so they take turns
+        assert_eq!(dispatch(&mut streams, 2), [1, 3]);
+        let loads: Vec<_> = streams.live().map(|s| s.load()).collect();
+        assert_eq!(loads[0].sessions, 4);
+        assert_eq!(loads[1].sessions, 4);
+    }
+
+    #[test]
+    fn least_queued_doesnt_count_sessions_as_atoms() {
+        let (mut streams, _peers) = workers(Dispatch::LeastQueued, &[(0, 2), (4, 0)]);
+
+        // Sessions dispatched to the second don't make its queue look longer
+        assert_eq!(dispatch(&mut streams, 3), [0, 3]);
+        let loads: Vec<_> = streams.live().map(|s| s.load()).collect();
+        assert_eq!(loads[1].queued_atoms, 0);
+        assert_eq!(loads[1].sessions, 7);
+    }
+
+    #[test]
+    fn least_queued_spreads_a_burst_over_equal_queues() {
+        let (mut streams, _peers) = workers(Dispatch::LeastQueued, &[(0, 1), (0, 1), (3, 4)]);
+        assert_eq!(dispatch(&mut streams, 4), [2, 2, 0]);
+    }
+
+    #[test]
+    fn reported_load_replaces_dispatched_sessions() {
+        let (mut streams, mut peers) = workers(Dispatch::LeastSessions, &[(0, 0)]);
+        assert_eq!(dispatch(&mut streams, 2), [2]);
+
+        let msg = bincode::serialize(&LoadMessage {
+            sessions: 2,
+            queued_atoms: 1,
+        })
+        .unwrap();
+        peers[0].write_all(&[messages::LOAD_MESSAGE]).unwrap();
+        peers[0]
+            .write_all(&(msg.len() as u32).to_be_bytes())
+            .unwrap();
+        peers[0].write_all(&msg).unwrap();
+
+        let stream = streams.live().next().unwrap();
+        stream.read(&mut [0; 256]).unwrap();
+        assert_eq!(stream.dispatched(), 0);
+        assert_eq!(
+            stream.load(),
+            LoadMessage {
+                sessions: 2,
+                queued_atoms: 1
+            }
+        );
+    }
+}
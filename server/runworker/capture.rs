@@ -0,0 +1,593 @@
+// Per-atom output capture. Each atom gets stdout and stderr pipes of its own, output
+// is attributed by the pipe it was read from, never by what it says.
+// With one exec thread fds 1 and 2 point at the pipes while the atom runs,
+// catching C extensions and subprocesses too. Fds belong to the whole process,
+// so python's sys.stdout and sys.stderr are also routed by thread, and what other
+// threads print, e.g. ones earlier atoms left running, goes to the worker's own
+// fds 1 and 2. With more exec threads only sys.stdout and sys.stderr are routed.
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::time;
+
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyDict};
+
+use crate::messages::OutputKind;
+
+use super::workerstream::Logger;
+
+// Installed as sys.stdout and sys.stderr when atoms run on several threads,
+// writes go to the capture of the atom running on the writing thread
+const ROUTER: &str = r#"
+import io, threading
+
+class AtomOutput(io.TextIOBase):
+    def __init__(self, default):
+        self._default = default
+        self._local = threading.local()
+
+    # Returns the capture it replaces
+    def capture(self, target):
+        prev = getattr(self._local, "target", None)
+        self._local.target = target
+        return prev
+
+    def _target(self):
+        return getattr(self._local, "target", None) or self._default
+
+    @property
+    def encoding(self):
+        return self._target().encoding
+
+    def write(self, s):
+        return self._target().write(s)
+
+    def flush(self):
+        self._target().flush()
+
+    def fileno(self):
+        return self._target().fileno()
+
+    def isatty(self):
+        return False
+
+    @property
+    def buffer(self):
+        return self._target().buffer
+"#;
+
+// The read end of one of an atom's pipes, read by the worker event loop
+pub struct Capture {
+    pub session_id: String,
+    pub future_id: String,
+    kind: OutputKind,
+    recv: pipe::Receiver,
+    // Output after the last newline
+    partial: Vec<u8>,
+    // When the first byte of partial was read
+    partial_since: Option<time::Instant>,
+}
+
+impl Capture {
+    // Sends each complete line to the master, and lines past max_line_bytes in chunks.
+    // True once every writer has closed the pipe.
+    pub fn read(
+        &mut self,
+        buf: &mut [u8],
+        logger: &Logger,
+        max_line_bytes: usize,
+    ) -> io::Result<bool> {
+        let closed = loop {
+            match self.recv.read(buf) {
+                Ok(0) => break true,
+                Ok(bytes_read) => self.partial.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        let mut start = 0;
+        for (n, c) in self.partial.iter().enumerate() {
+            if *c == b'\n' {
+                self.send(logger, &self.partial[start..n], false);
+                start = n + 1;
+            } else if n + 1 - start == max_line_bytes {
+                self.send(logger, &self.partial[start..=n], true);
+                start = n + 1;
+            }
+        }
+
+        if start > 0 {
+            self.partial.drain(..start);
+            self.partial_since = None;
+        }
+        if !self.partial.is_empty() && self.partial_since.is_none() {
+            self.partial_since = Some(time::Instant::now());
+        }
+
+        if closed {
+            self.flush(logger);
+        }
+
+        Ok(closed)
+    }
+
+    // Sends output with no newline as a chunk once it has waited delay,
+    // returns how long until the rest is due
+    pub fn flush_after(
+        &mut self,
+        delay: time::Duration,
+        logger: &Logger,
+    ) -> Option<time::Duration> {
+        let waited = self.partial_since?.elapsed();
+        if waited < delay {
+            return Some(delay - waited);
+        }
+
+        self.flush(logger);
+        None
+    }
+
+    // Sends the output after the last newline as a chunk
+    pub fn flush(&mut self, logger: &Logger) {
+        if !self.partial.is_empty() {
+            self.send(logger, &self.partial, true);
+            self.partial.clear();
+        }
+        self.partial_since = None;
+    }
+
+    fn send(&self, logger: &Logger, data: &[u8], chunk: bool) {
+        logger.output(&self.session_id, &self.future_id, self.kind, chunk, data);
+    }
+}
+
+impl Source for Capture {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        Source::register(&mut self.recv, registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        Source::reregister(&mut self.recv, registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        Source::deregister(&mut self.recv, registry)
+    }
+}
+
+// Routers for sys.stdout and sys.stderr, shared by every exec thread
+#[derive(Clone)]
+pub struct Routers {
+    stdout: PyObject,
+    stderr: PyObject,
+}
+
+impl Routers {
+    // Threads not running an atom write where they did before
+    pub fn install(py: Python) -> PyResult<Self> {
+        let sys = py.import("sys")?;
+        Self::install_over(py, sys.getattr("stdout")?, sys.getattr("stderr")?)
+    }
+
+    // Threads not running an atom write to stdout and stderr
+    fn install_over(py: Python, stdout: &PyAny, stderr: &PyAny) -> PyResult<Self> {
+        let globals = PyDict::new(py);
+        py.run(ROUTER, Some(globals), None)?;
+        let atom_output = globals
+            .get_item("AtomOutput")
+            .expect("ROUTER defines AtomOutput");
+
+        let sys = py.import("sys")?;
+        let stdout: PyObject = atom_output.call1((stdout,))?.into();
+        let stderr: PyObject = atom_output.call1((stderr,))?.into();
+        sys.setattr("stdout", &stdout)?;
+        sys.setattr("stderr", &stderr)?;
+
+        Ok(Self { stdout, stderr })
+    }
+
+    // Routes this thread's writes to stdout and stderr
+    fn capture(&self, py: Python, stdout: PyObject, stderr: PyObject) -> PyResult<()> {
+        self.stdout.call_method1(py, "capture", (stdout,))?;
+        self.stderr.call_method1(py, "capture", (stderr,))?;
+        Ok(())
+    }
+
+    // Routes this thread's writes back to the defaults, returning where they went
+    fn release(&self, py: Python) -> Vec<PyObject> {
+        [&self.stdout, &self.stderr]
+            .into_iter()
+            .filter_map(|router| router.call_method1(py, "capture", (py.None(),)).ok())
+            .filter(|target| !target.is_none(py))
+            .collect()
+    }
+}
+
+// Points an exec thread's output at each atom's pipes in turn
+pub enum Redirector {
+    Fds {
+        // Copies of the worker's own fds 1 and 2, restored after each atom
+        stdout: OwnedFd,
+        stderr: OwnedFd,
+        // Other threads' python output goes to the copies
+        routers: Routers,
+        // sys.__stdout__ and sys.__stderr__, on fds 1 and 2
+        streams: (PyObject, PyObject),
+    },
+    Python(Routers),
+}
+
+impl Redirector {
+    pub fn fds(py: Python) -> io::Result<Self> {
+        let py_err = |err: PyErr| io::Error::other(format!("{}", err));
+        let sys = py.import("sys").map_err(py_err)?;
+        let streams = (
+            sys.getattr("__stdout__").map_err(py_err)?,
+            sys.getattr("__stderr__").map_err(py_err)?,
+        );
+
+        // Like a terminal, so output reaches the client as it's printed
+        for stream in [streams.0, streams.1] {
+            let kwargs = [("line_buffering", true)].into_py_dict(py);
+            stream.call_method("reconfigure", (), Some(kwargs)).ok();
+        }
+
+        let stdout = dup_cloexec(1)?;
+        let stderr = dup_cloexec(2)?;
+
+        // Other threads print to copies of their own
+        let copy = |fd: &OwnedFd| -> io::Result<PyObject> {
+            let fd = dup_cloexec(fd.as_raw_fd())?;
+            open_text(py, fd.into_raw_fd()).map_err(py_err)
+        };
+        let (stdout_copy, stderr_copy) = (copy(&stdout)?, copy(&stderr)?);
+        let routers = Routers::install_over(py, stdout_copy.as_ref(py), stderr_copy.as_ref(py))
+            .map_err(py_err)?;
+
+        Ok(Redirector::Fds {
+            stdout,
+            stderr,
+            routers,
+            streams: (streams.0.into(), streams.1.into()),
+        })
+    }
+
+    // Returns the read ends, for the event loop
+    pub fn start(
+        &self,
+        py: Python,
+        session_id: &str,
+        future_id: &str,
+    ) -> io::Result<(Capture, Capture)> {
+        let (stdout_tx, stdout_rx) = pipe::new()?;
+        let (stderr_tx, stderr_rx) = pipe::new()?;
+
+        // Only the event loop's end is non-blocking, a full pipe holds up the atom
+        stdout_tx.set_nonblocking(false)?;
+        stderr_tx.set_nonblocking(false)?;
+
+        match self {
+            Redirector::Fds {
+                routers, streams, ..
+            } => {
+                // Whatever is buffered was written before the atom started
+                flush_python(py);
+                unsafe {
+                    libc::fflush(std::ptr::null_mut());
+                    if libc::dup2(stdout_tx.as_raw_fd(), 1) < 0
+                        || libc::dup2(stderr_tx.as_raw_fd(), 2) < 0
+                    {
+                        let err = io::Error::last_os_error();
+                        self.finish(py);
+                        return Err(err);
+                    }
+                }
+
+                let (stdout, stderr) = streams.clone();
+                if let Err(err) = routers.capture(py, stdout, stderr) {
+                    self.finish(py);
+                    return Err(io::Error::other(format!("{}", err)));
+                }
+            }
+            Redirector::Python(routers) => {
+                let res = open_text(py, stdout_tx.into_raw_fd()).and_then(|stdout| {
+                    let stderr = open_text(py, stderr_tx.into_raw_fd())?;
+                    routers.capture(py, stdout, stderr)
+                });
+
+                if let Err(err) = res {
+                    self.finish(py);
+                    return Err(io::Error::other(format!("{}", err)));
+                }
+            }
+        }
+
+        let capture = |kind, recv| Capture {
+            session_id: session_id.to_owned(),
+            future_id: future_id.to_owned(),
+            kind,
+            recv,
+            partial: Vec::with_capacity(256),
+            partial_since: None,
+        };
+
+        Ok((
+            capture(OutputKind::Stdout, stdout_rx),
+            capture(OutputKind::Stderr, stderr_rx),
+        ))
+    }
+
+    // Closes the atom's write ends, the pipes close once any subprocesses holding them exit
+    pub fn finish(&self, py: Python) {
+        match self {
+            Redirector::Fds {
+                stdout,
+                stderr,
+                routers,
+                ..
+            } => {
+                flush_python(py);
+                routers.release(py);
+                unsafe {
+                    libc::fflush(std::ptr::null_mut());
+                    libc::dup2(stdout.as_raw_fd(), 1);
+                    libc::dup2(stderr.as_raw_fd(), 2);
+                }
+            }
+            Redirector::Python(routers) => {
+                for target in routers.release(py) {
+                    target.call_method0(py, "close").ok();
+                }
+            }
+        }
+    }
+}
+
+fn dup_cloexec(fd: i32) -> io::Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+// Line buffered like the fd redirection, it owns fd once opened
+fn open_text(py: Python, fd: i32) -> PyResult<PyObject> {
+    let kwargs = [
+        ("buffering", 1.into_py(py)),
+        ("encoding", "utf-8".into_py(py)),
+        ("errors", "backslashreplace".into_py(py)),
+    ]
+    .into_py_dict(py);
+
+    match py
+        .import("io")?
+        .getattr("open")?
+        .call((fd, "w"), Some(kwargs))
+    {
+        Ok(target) => Ok(target.into()),
+        Err(err) => {
+            unsafe { libc::close(fd) };
+            Err(err)
+        }
+    }
+}
+
+fn flush_python(py: Python) {
+    if let Ok(sys) = py.import("sys") {
+        for name in ["stdout", "stderr"] {
+            if let Ok(stream) = sys.getattr(name) {
+                stream.call_method0("flush").ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Mutex, MutexGuard};
+
+    use super::*;
+    use crate::messages::{self, OutputMessage};
+    use crate::runworker::workerstream::WorkerStream;
+
+    // Tests routing output replace the process' sys.stdout, sys.stderr and fds 1 and 2
+    static ROUTING: Mutex<()> = Mutex::new(());
+
+    pub(in crate::runworker) fn routing_output() -> MutexGuard<'static, ()> {
+        ROUTING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Atom output as the master gets it, over a real worker stream
+    pub(in crate::runworker) struct Master {
+        worker_stream: WorkerStream,
+        pub(in crate::runworker) logger: Logger,
+        stream: UnixStream,
+    }
+
+    impl Master {
+        pub(in crate::runworker) fn connect() -> Self {
+            let (ours, stream) = UnixStream::pair().unwrap();
+            ours.set_nonblocking(true).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            let worker_stream = WorkerStream::new(fd_queue::mio::UnixStream::from_std(ours));
+            let logger = worker_stream.new_logger();
+
+            Self {
+                worker_stream,
+                logger,
+                stream,
+            }
+        }
+
+        // Reads what the capture has, true once closed
+        pub(in crate::runworker) fn read(
+            &self,
+            capture: &mut Capture,
+            max_line_bytes: usize,
+        ) -> bool {
+            capture
+                .read(&mut [0; 64], &self.logger, max_line_bytes)
+                .unwrap()
+        }
+
+        // Output messages sent so far, as (line, chunk)
+        pub(in crate::runworker) fn sent(&mut self) -> Vec<(String, bool)> {
+            self.worker_stream.write().unwrap();
+
+            let mut buf = vec![];
+            let mut chunk = [0; 4096];
+            loop {
+                match io::Read::read(&mut self.stream, &mut chunk) {
+                    Ok(n) if n > 0 => buf.extend(&chunk[..n]),
+                    Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+                        panic!("reading the worker stream failed {}", err)
+                    }
+                    _ => break,
+                }
+            }
+
+            let mut sent = vec![];
+            while !buf.is_empty() {
+                let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+                if buf[0] == messages::OUTPUT_MESSAGE {
+                    let output: OutputMessage = bincode::deserialize(&buf[5..5 + len]).unwrap();
+                    assert_eq!(output.future_id, "future");
+                    sent.push((String::from_utf8(output.line).unwrap(), output.chunk));
+                }
+                buf.drain(..5 + len);
+            }
+            sent
+        }
+    }
+
+    // Runs python with its output captured as an atom's would be, on this thread
+    fn atom(code: &str) -> (Capture, Capture) {
+        Python::with_gil(|py| {
+            let redirector = Redirector::Python(Routers::install(py).unwrap());
+            let captures = redirector.start(py, "session", "future").unwrap();
+            py.run(code, None, None).unwrap();
+            redirector.finish(py);
+            captures
+        })
+    }
+
+    fn lines(lines: &[(&str, bool)]) -> Vec<(String, bool)> {
+        lines
+            .iter()
+            .map(|(line, chunk)| (line.to_string(), *chunk))
+            .collect()
+    }
+
+    #[test]
+    fn lines_are_sent_once_complete() {
+        let _routing = routing_output();
+        let mut master = Master::connect();
+        let (mut stdout, _stderr) = atom("print('one')\nprint('two')\nprint('thr', end='')");
+
+        assert!(master.read(&mut stdout, 1024));
+        assert_eq!(
+            master.sent(),
+            lines(&[("one", false), ("two", false), ("thr", true)])
+        );
+    }
+
+    #[test]
+    fn stderr_has_its_own_capture() {
+        let _routing = routing_output();
+        let mut master = Master::connect();
+        let (mut stdout, mut stderr) = atom("import sys\nprint('oops', file=sys.stderr)");
+
+        assert!(master.read(&mut stdout, 1024));
+        assert_eq!(master.sent(), lines(&[]));
+        assert!(master.read(&mut stderr, 1024));
+        assert_eq!(master.sent(), lines(&[("oops", false)]));
+    }
+
+    #[test]
+    fn long_lines_are_chunked_at_max_line_bytes() {
+        let _routing = routing_output();
+        let mut master = Master::connect();
+        let (mut stdout, _stderr) = atom("print('abcdefghij')\nprint('xy')");
+
+        assert!(master.read(&mut stdout, 4));
+        assert_eq!(
+            master.sent(),
+            lines(&[("abcd", true), ("efgh", true), ("ij", false), ("xy", false)])
+        );
+    }
+
+    #[test]
+    fn chunks_rebuild_what_was_written() {
+        let written = "abcd\n\nabcdefgh\nabc\nabcdefghi";
+        let _routing = routing_output();
+        let mut master = Master::connect();
+        let (mut stdout, _stderr) = atom(&format!("print({:?}, end='')", written));
+
+        assert!(master.read(&mut stdout, 4));
+        let sent = master.sent();
+        assert!(sent.iter().all(|(line, _)| line.len() <= 4));
+
+        // Each line gets its newline back, each chunk is as it was
+        let rebuilt: String = sent
+            .iter()
+            .map(|(line, chunk)| match chunk {
+                true => line.clone(),
+                false => format!("{}\n", line),
+            })
+            .collect();
+        assert_eq!(rebuilt, written);
+    }
+
+    #[test]
+    fn partial_line_waits_for_flush_delay() {
+        let _routing = routing_output();
+        let mut master = Master::connect();
+
+        // One with_gil throughout, the thread state holds the capture's target
+        Python::with_gil(|py| {
+            let redirector = Redirector::Python(Routers::install(py).unwrap());
+            let (mut stdout, _stderr) = redirector.start(py, "session", "future").unwrap();
+            py.run(
+                "import sys\nsys.stdout.write('prompt> ')\nsys.stdout.flush()",
+                None,
+                None,
+            )
+            .unwrap();
+
+            // Still running, the prompt waits for its newline
+            assert!(!master.read(&mut stdout, 1024));
+            let delay = time::Duration::from_secs(60);
+            let remaining = stdout.flush_after(delay, &master.logger);
+            assert!(remaining.is_some_and(|remaining| remaining <= delay));
+            assert_eq!(master.sent(), lines(&[]));
+
+            assert_eq!(
+                stdout.flush_after(time::Duration::ZERO, &master.logger),
+                None
+            );
+            assert_eq!(master.sent(), lines(&[("prompt> ", true)]));
+
+            // Nothing left waiting
+            assert_eq!(stdout.flush_after(delay, &master.logger), None);
+            redirector.finish(py);
+        });
+    }
+}
@@ -4,11 +4,20 @@ use std::io::{self, Read, Write};
 use mio::event::Source;
 use mio::net::TcpStream;
 use mio::{Interest, Registry, Token};
+use protocol::mainstream::PythonResult;
 use rand::Rng;
 
 use crate::config::Config;
 
 use super::errors::{io_error, Error, Result};
+use super::pythread;
+
+// Optional protocol features this worker implements
+const SERVER_FEATURES: [protocol::Feature; 3] = [
+    protocol::Feature::StatefulSessions,
+    protocol::Feature::ReturnLocals,
+    protocol::Feature::Cancel,
+];
 
 pub struct ClientStream {
     stream: TcpStream,
@@ -16,8 +25,19 @@ pub struct ClientStream {
     inbuffer: Vec<u8>,
     interest: Interest,
     session_id: String,
+    output_addr: String,
     seq_num: u32,
     req_msgs: VecDeque<protocol::RequestMessage>,
+
+    // Negotiated in the hello exchange
+    version: u8,
+    features: Vec<protocol::Feature>,
+
+    // Set while we wait on the client hello body
+    hello_header: Option<protocol::RequestMessageHeader>,
+
+    // Set after a fatal error, we close once the error response is written
+    closing: bool,
 }
 
 impl ClientStream {
@@ -26,47 +46,108 @@ impl ClientStream {
         header: [u8; protocol::REQUEST_HEADER_SIZE],
         stream: TcpStream,
         interest: Interest,
-    ) -> protocol::Result<Self> {
-        let msg_header = protocol::RequestMessageHeader::from_buf(header)?;
+    ) -> Self {
+        let session_id: [u8; protocol::SESSION_ID_LENGTH] = rand::thread_rng().gen();
+
+        let mut slf = Self {
+            stream,
+            outbuffer: Vec::with_capacity(4096),
+            inbuffer: Vec::with_capacity(4096),
+            interest,
+            session_id: hex::encode(session_id),
+            output_addr: cfg.output_addr.to_string(),
+            seq_num: 0,
+            req_msgs: VecDeque::with_capacity(64),
+            version: protocol::VERSION,
+            features: vec![],
+            hello_header: None,
+            closing: false,
+        };
+
+        let msg_header = match protocol::RequestMessageHeader::from_buf(header) {
+            Ok(msg_header) => msg_header,
+            Err(err) => {
+                slf.close_with_error(err);
+                return slf;
+            }
+        };
+
+        slf.version = msg_header.version;
 
         // Check it's client hello
         match msg_header.msg_type {
             protocol::MessageType::Hello => {}
-            _ => return Err(protocol::Error::UnexpectedMessageType(msg_header.msg_type)),
+            _ => {
+                slf.close_with_error(protocol::Error::UnexpectedMessageType(msg_header.msg_type));
+                return slf;
+            }
         }
 
-        // Okay - populate our buffer with
-        let session_id: [u8; protocol::SESSION_ID_LENGTH] = rand::thread_rng().gen();
-        let stream_token = session_id.clone();
+        // Version 0 clients send an empty hello, the master
+        // has only read the header so any body is still on the stream
+        if msg_header.msg_len() == 0 {
+            if let Err(err) = slf.hello(protocol::RequestClientHello::v0()) {
+                slf.close_with_error(err);
+            }
+        } else {
+            slf.hello_header = Some(msg_header);
+        }
 
-        let server_hello = bincode::serialize(&protocol::ResponseClientHello {
-            session_id: hex::encode(&session_id),
-            stream_token: hex::encode(&stream_token),
-            output_addr: cfg.output_addr.to_string(),
-        })
-        .expect("couldn't serialize ResponseClientHello");
+        slf
+    }
 
-        let resp_header = protocol::ResponseMessageHeader::new(
-            protocol::MessageType::Hello,
-            0,
-            server_hello.len(),
-            1,
-        )
-        .into_buf();
+    fn hello(&mut self, client_hello: protocol::RequestClientHello) -> protocol::Result<()> {
+        let (version, features) = client_hello.negotiate(&SERVER_FEATURES)?;
+        self.version = version;
+        self.features = features;
+
+        // The session_id doubles as the output stream token
+        let server_hello = protocol::ResponseClientHello {
+            session_id: self.session_id.clone(),
+            stream_token: self.session_id.clone(),
+            output_addr: self.output_addr.clone(),
+            version: self.version,
+            features: protocol::features_as_u8(&self.features),
+        };
+
+        self.queue_response(protocol::MessageType::Hello, server_hello);
+        Ok(())
+    }
 
-        let mut outbuffer = Vec::with_capacity(4096);
-        outbuffer.extend(&resp_header);
-        outbuffer.extend(&server_hello);
+    // Tell the client why, fatal errors close the stream once written
+    fn send_error(&mut self, err: protocol::Error, future_id: Option<String>) {
+        if err.is_fatal() {
+            self.closing = true;
+        }
 
-        Ok(Self {
-            stream,
-            outbuffer,
-            inbuffer: Vec::with_capacity(4096),
-            interest,
-            session_id: hex::encode(&session_id),
-            seq_num: 1,
-            req_msgs: VecDeque::with_capacity(64),
-        })
+        let resp = protocol::ErrorResponse::new(err.code(), err.reason(), future_id);
+        self.queue_error(resp);
+    }
+
+    // Version 0 clients can't parse error responses. One for a future gets them the
+    // exception pickle they'd have had before, anything else closes the connection
+    pub fn queue_error(&mut self, resp: protocol::ErrorResponse) {
+        if self.version > 0 {
+            self.queue_response(protocol::MessageType::Error, resp);
+            return;
+        }
+
+        match resp.future_id {
+            Some(future_id) => {
+                let resp = protocol::ResponseCodeString {
+                    future_id,
+                    py_result: PythonResult::Error(pythread::runtime_error_pickle(&resp.reason)),
+                    locals: None,
+                };
+                self.queue_response(protocol::MessageType::CodeString, resp);
+            }
+            None => self.closing = true,
+        }
+    }
+
+    fn close_with_error(&mut self, err: protocol::Error) {
+        self.send_error(err, None);
+        self.closing = true;
     }
 
     pub fn set_interest(&mut self, interest: Interest) {
@@ -77,8 +158,17 @@ impl ClientStream {
         self.interest
     }
 
+    // Writes until the socket is full, we're edge triggered
     pub fn write(&mut self) -> io::Result<()> {
-        let bytes_written = self.stream.write(&self.outbuffer)?;
+        let mut bytes_written = 0;
+        while bytes_written < self.outbuffer.len() {
+            match self.stream.write(&self.outbuffer[bytes_written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => bytes_written += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
         self.stream.flush()?;
         let bytes_remaining = self.outbuffer.len() - bytes_written;
 
@@ -91,16 +181,43 @@ impl ClientStream {
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<()> {
-        let bytes_read = self
-            .stream
-            .read(buf)
-            .map_err(|e| io_error("failed to read from tcp stream", e))?;
+        // Drain the socket, we're edge triggered
+        loop {
+            match self.stream.read(buf) {
+                Ok(0) => return Err(Error::StreamClosed),
+                // Nothing more is processed once we're closing
+                Ok(_) if self.closing => {}
+                Ok(bytes_read) => self.inbuffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(io_error("failed to read from tcp stream", err)),
+            }
+        }
 
-        if bytes_read == 0 {
-            return Err(Error::StreamClosed);
+        if self.closing {
+            return Ok(());
         }
 
-        self.inbuffer.extend(&buf[..bytes_read]);
+        if let Some(hello_header) = self.hello_header.take() {
+            let msg_end = hello_header.msg_len();
+            if self.inbuffer.len() < msg_end {
+                self.hello_header = Some(hello_header);
+                return Ok(());
+            }
+
+            let res = protocol::read_req(hello_header, &self.inbuffer[..msg_end]).and_then(
+                |client_hello| match client_hello {
+                    protocol::RequestMessage::Hello(client_hello) => self.hello(client_hello),
+                    _ => Ok(()),
+                },
+            );
+
+            if let Err(err) = res {
+                self.close_with_error(err);
+                return Ok(());
+            }
+
+            self.consume(msg_end);
+        }
 
         while self.inbuffer.len() >= protocol::REQUEST_HEADER_SIZE {
             let mut header = [0; protocol::REQUEST_HEADER_SIZE];
@@ -108,29 +225,69 @@ impl ClientStream {
                 *h = *b;
             }
 
-            let req_header = protocol::RequestMessageHeader::from_buf(header)?;
-
             // Do we have enough bytes?
-            if req_header.msg_len() + protocol::REQUEST_HEADER_SIZE > self.inbuffer.len() {
+            let msg_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            let msg_end = msg_len as usize + protocol::REQUEST_HEADER_SIZE;
+            if msg_end > self.inbuffer.len() {
                 break;
             }
 
-            let msg_end = req_header.msg_len() + protocol::REQUEST_HEADER_SIZE;
-
             let msg_body = &self.inbuffer[protocol::REQUEST_HEADER_SIZE..msg_end];
-            let msg = protocol::read_req(req_header, msg_body)?;
-
-            self.req_msgs.push_back(msg);
-
-            let bytes_remaining = self.inbuffer.len() - msg_end;
-            for n in 0..bytes_remaining {
-                self.inbuffer[n] = self.inbuffer[n + msg_end];
+            let future_id = protocol::mainstream::peek_future_id(msg_body);
+
+            let res = protocol::RequestMessageHeader::from_buf(header)
+                .and_then(|req_header| protocol::read_req(req_header, msg_body));
+
+            match res {
+                Ok(protocol::RequestMessage::Hello(_)) => {
+                    // Hello is only valid as the first message
+                    let err = protocol::Error::UnexpectedMessageType(protocol::MessageType::Hello);
+                    self.send_error(err, None);
+                }
+                Ok(msg) => {
+                    // Requests using a feature the hello didn't enable are refused
+                    let features = msg.features();
+                    match features.iter().find(|f| !self.features.contains(f)) {
+                        Some(feature) => {
+                            let err = protocol::Error::FeatureNotNegotiated(*feature);
+                            self.send_error(err, future_id);
+                        }
+                        None => self.req_msgs.push_back(msg),
+                    }
+                }
+                Err(err) => {
+                    let fatal = err.is_fatal();
+                    self.send_error(err, future_id);
+                    if fatal {
+                        return Ok(());
+                    }
+                }
             }
-            self.inbuffer.truncate(bytes_remaining);
+
+            self.consume(msg_end);
         }
         Ok(())
     }
 
+    fn consume(&mut self, msg_end: usize) {
+        let bytes_remaining = self.inbuffer.len() - msg_end;
+        for n in 0..bytes_remaining {
+            self.inbuffer[n] = self.inbuffer[n + msg_end];
+        }
+        self.inbuffer.truncate(bytes_remaining);
+    }
+
+    // True once a fatal error response has been written
+    pub fn is_closed(&self) -> bool {
+        self.closing && self.outbuffer.is_empty()
+    }
+
+    // The master still holds a copy of our fd so dropping
+    // the stream alone won't close the connection
+    pub fn shutdown(&mut self) {
+        self.stream.shutdown(std::net::Shutdown::Both).unwrap_or(());
+    }
+
     pub fn has_out_data(&self) -> bool {
         !self.outbuffer.is_empty()
     }
@@ -139,6 +296,17 @@ impl ClientStream {
         self.req_msgs.pop_front()
     }
 
+    pub fn queue_response<T: serde::Serialize>(&mut self, msg_type: protocol::MessageType, msg: T) {
+        self.seq_num += 1;
+        self.outbuffer.extend(&protocol::new_response(
+            self.version,
+            msg_type,
+            0,
+            self.seq_num,
+            msg,
+        ));
+    }
+
     pub fn session_id(&self) -> &str {
         &self.session_id[..]
     }
@@ -167,3 +335,137 @@ impl Source for ClientStream {
         Source::deregister(&mut self.stream, registry)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net;
+
+    use super::*;
+
+    // A session whose client asked for features, and the client's end
+    fn open(features: &[protocol::Feature]) -> (ClientStream, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut ours, _) = listener.accept().unwrap();
+
+        let hello = protocol::RequestClientHello::new(features);
+        client
+            .write_all(&protocol::new_req(protocol::MessageType::Hello, 0, hello))
+            .unwrap();
+
+        // The master reads the header before handing the stream over
+        let mut header = [0; protocol::REQUEST_HEADER_SIZE];
+        ours.read_exact(&mut header).unwrap();
+        ours.set_nonblocking(true).unwrap();
+
+        let mut stream = ClientStream::new(
+            &Config::default(),
+            header,
+            TcpStream::from_std(ours),
+            Interest::READABLE,
+        );
+        stream.read(&mut [0; 4096]).unwrap();
+        assert!(matches!(
+            response(&mut stream, &mut client),
+            protocol::ResponseMessage::Hello(_)
+        ));
+
+        (stream, client)
+    }
+
+    fn response(
+        stream: &mut ClientStream,
+        client: &mut net::TcpStream,
+    ) -> protocol::ResponseMessage {
+        stream.write().unwrap();
+
+        let mut raw = [0; protocol::RESPONSE_HEADER_SIZE];
+        client.read_exact(&mut raw).unwrap();
+        let header = protocol::ResponseMessageHeader::from_buf(raw).unwrap();
+        let mut body = vec![0; header.msg_len()];
+        client.read_exact(&mut body).unwrap();
+        protocol::read_response(header, &body).unwrap()
+    }
+
+    fn code(namespace: protocol::Namespace, return_locals: protocol::ReturnLocals) -> Vec<u8> {
+        let msg = protocol::CodeString {
+            future_id: String::from("f1"),
+            mode: protocol::EvalMode::Exec,
+            namespace,
+            return_locals,
+            timeout_ms: None,
+            code: String::from("pass"),
+            locals: vec![],
+            globals: vec![],
+        };
+        protocol::new_req(protocol::MessageType::CodeString, 0, msg)
+    }
+
+    fn refused(stream: &mut ClientStream, client: &mut net::TcpStream, req: &[u8]) -> String {
+        client.write_all(req).unwrap();
+        stream.read(&mut [0; 4096]).unwrap();
+        assert!(stream.next_req_msg().is_none());
+
+        match response(stream, client) {
+            protocol::ResponseMessage::Error(err) => {
+                assert_eq!(err.code(), Some(protocol::ErrorCode::UnexpectedMessageType));
+                assert_eq!(err.future_id.as_deref(), Some("f1"));
+                err.reason
+            }
+            _ => panic!("expected an error response"),
+        }
+    }
+
+    #[test]
+    fn requests_need_their_features_negotiated() {
+        let (mut stream, mut client) = open(&[]);
+
+        let reason = refused(
+            &mut stream,
+            &mut client,
+            &code(
+                protocol::Namespace::SessionMerge,
+                protocol::ReturnLocals::Nothing,
+            ),
+        );
+        assert!(reason.contains("stateful-sessions"));
+
+        let reason = refused(
+            &mut stream,
+            &mut client,
+            &code(protocol::Namespace::Atom, protocol::ReturnLocals::All),
+        );
+        assert!(reason.contains("return-locals"));
+
+        let cancel = protocol::Cancel {
+            future_id: String::from("f1"),
+        };
+        let reason = refused(
+            &mut stream,
+            &mut client,
+            &protocol::new_req(protocol::MessageType::Cancel, 0, cancel),
+        );
+        assert!(reason.contains("cancel"));
+
+        // The session carries on
+        assert!(!stream.is_closed());
+    }
+
+    #[test]
+    fn negotiated_features_are_accepted() {
+        let (mut stream, mut client) = open(&SERVER_FEATURES);
+        client
+            .write_all(&code(
+                protocol::Namespace::SessionReplace,
+                protocol::ReturnLocals::Names(vec![String::from("x")]),
+            ))
+            .unwrap();
+        stream.read(&mut [0; 4096]).unwrap();
+
+        assert!(matches!(
+            stream.next_req_msg(),
+            Some(protocol::RequestMessage::CodeString(_))
+        ));
+        assert!(!stream.has_out_data());
+    }
+}
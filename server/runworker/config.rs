@@ -1,12 +1,41 @@
 use std::env;
 use std::error;
 use std::net;
+use std::num;
+use std::path;
+use std::time;
 
 pub struct Config {
     pub output_addr: net::SocketAddr,
+    // Applied to atoms which don't ask for a timeout, None runs them unbounded
+    pub default_timeout_ms: Option<u64>,
+    // Upper bound on any atom's timeout
+    pub max_timeout_ms: Option<u64>,
+    // Past either limit the worker is recycled, once its sessions have closed
+    pub max_atoms_per_worker: Option<u64>,
+    pub max_rss_bytes: Option<u64>,
+    // Forked for one session, recycled as soon as it opens it
+    pub single_session: bool,
+    // Python environment set up before taking sessions, see warmup
+    pub preload_modules: Vec<String>,
+    pub warmup_script: Option<path::PathBuf>,
+    pub python_path: Vec<path::PathBuf>,
+    // Threads running atoms, each session's atoms still run one at a time and in order.
+    // Only with one are fds 1 and 2 captured per atom, see capture.
+    pub exec_threads: usize,
+    // Atoms a session may have waiting, more get a busy error
+    pub max_queued_atoms: Option<usize>,
+    // Interrupt a session's running atom when its client disconnects
+    pub interrupt_on_disconnect: bool,
+    // Output with no newline is sent as a chunk once it has waited this long
+    pub output_flush_delay: time::Duration,
+    // Longer lines are sent as chunks of at most this many bytes
+    pub max_line_bytes: usize,
 }
 
+// Fields are read through Debug, when main returns the error
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct EnvError {
     pub env_var: String,
     pub env_val: String,
@@ -14,6 +43,7 @@ pub struct EnvError {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Error {
     pub errors: Vec<EnvError>,
 }
@@ -23,12 +53,62 @@ impl Default for Config {
         let bind = net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0));
         Self {
             output_addr: net::SocketAddr::new(bind, 9001),
+            default_timeout_ms: None,
+            max_timeout_ms: None,
+            max_atoms_per_worker: None,
+            max_rss_bytes: None,
+            single_session: false,
+            preload_modules: vec![],
+            warmup_script: None,
+            python_path: vec![],
+            exec_threads: 1,
+            max_queued_atoms: None,
+            interrupt_on_disconnect: false,
+            output_flush_delay: time::Duration::from_millis(100),
+            max_line_bytes: 64 * 1024,
         }
     }
 }
 
+impl Config {
+    // Why the worker should be recycled, if it should
+    pub fn recycle_reason(
+        &self,
+        atoms_done: u64,
+        sessions_opened: u64,
+        rss_bytes: Option<u64>,
+    ) -> Option<String> {
+        if self.single_session && sessions_opened > 0 {
+            return Some(String::from("forked for a single session"));
+        }
+
+        if let Some(max) = self.max_atoms_per_worker {
+            if atoms_done >= max {
+                return Some(format!("ran {} atoms, limit is {}", atoms_done, max));
+            }
+        }
+
+        match (rss_bytes, self.max_rss_bytes) {
+            (Some(rss), Some(max)) if rss > max => {
+                Some(format!("rss is {} bytes, limit is {}", rss, max))
+            }
+            _ => None,
+        }
+    }
+
+    // Timeout an atom runs with, given the one it asked for
+    pub fn atom_timeout(&self, timeout_ms: Option<u64>) -> Option<time::Duration> {
+        let timeout_ms = match (timeout_ms.or(self.default_timeout_ms), self.max_timeout_ms) {
+            (Some(t), Some(max)) => Some(t.min(max)),
+            (None, max) => max,
+            (t, None) => t,
+        };
+
+        timeout_ms.map(time::Duration::from_millis)
+    }
+}
+
 pub fn from_env() -> Result<Config, Error> {
-    let mut slf = Config::default();
     let mut errors = vec![];
     let mut cfg = Config::default();
 
@@ -44,6 +124,117 @@ pub fn from_env() -> Result<Config, Error> {
                     cfg.output_addr = output_addr;
                 }
             },
+            "PYPROXY_DEFAULT_TIMEOUT_MS" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(timeout_ms) => {
+                    cfg.default_timeout_ms = Some(timeout_ms);
+                }
+            },
+            "PYPROXY_MAX_TIMEOUT_MS" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(timeout_ms) => {
+                    cfg.max_timeout_ms = Some(timeout_ms);
+                }
+            },
+            "PYPROXY_MAX_ATOMS_PER_WORKER" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(max_atoms) => {
+                    cfg.max_atoms_per_worker = Some(max_atoms);
+                }
+            },
+            "PYPROXY_MAX_RSS_BYTES" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(max_rss) => {
+                    cfg.max_rss_bytes = Some(max_rss);
+                }
+            },
+            "PYPROXY_EXEC_THREADS" => match val.parse::<num::NonZeroUsize>() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(exec_threads) => {
+                    cfg.exec_threads = usize::from(exec_threads);
+                }
+            },
+            "PYPROXY_MAX_QUEUED_ATOMS" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(max_queued) => {
+                    cfg.max_queued_atoms = Some(max_queued);
+                }
+            },
+            "PYPROXY_INTERRUPT_ON_DISCONNECT" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(interrupt) => {
+                    cfg.interrupt_on_disconnect = interrupt;
+                }
+            },
+            "PYPROXY_OUTPUT_FLUSH_MS" => match val.parse() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(flush_ms) => {
+                    cfg.output_flush_delay = time::Duration::from_millis(flush_ms);
+                }
+            },
+            "PYPROXY_MAX_LINE_BYTES" => match val.parse::<num::NonZeroUsize>() {
+                Err(err) => errors.push(EnvError {
+                    env_var: key.to_owned(),
+                    env_val: val.to_owned(),
+                    error: Box::new(err),
+                }),
+                Ok(max_line) => {
+                    cfg.max_line_bytes = usize::from(max_line);
+                }
+            },
+            // The master has already checked it
+            "PYPROXY_WORKER_MODE" => {
+                cfg.single_session = val == "fork-per-session";
+            }
+            "PYPROXY_PRELOAD_MODULES" => {
+                cfg.preload_modules = val
+                    .split(',')
+                    .map(|module| module.trim())
+                    .filter(|module| !module.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            // The master passes paths down absolute
+            "PYPROXY_WARMUP_SCRIPT" => {
+                cfg.warmup_script = Some(path::PathBuf::from(val));
+            }
+            "PYPROXY_PYTHON_PATH" => {
+                cfg.python_path = env::split_paths(&val)
+                    .filter(|entry| !entry.as_os_str().is_empty())
+                    .collect();
+            }
             _ => {}
         }
     }
@@ -54,3 +245,58 @@ pub fn from_env() -> Result<Config, Error> {
         Err(Error { errors })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycles_past_atom_limit() {
+        let cfg = Config {
+            max_atoms_per_worker: Some(3),
+            ..Config::default()
+        };
+
+        assert_eq!(cfg.recycle_reason(2, 1, None), None);
+        assert_eq!(
+            cfg.recycle_reason(3, 1, None).as_deref(),
+            Some("ran 3 atoms, limit is 3")
+        );
+    }
+
+    #[test]
+    fn recycles_past_rss_limit() {
+        let cfg = Config {
+            max_rss_bytes: Some(1024),
+            ..Config::default()
+        };
+
+        assert_eq!(cfg.recycle_reason(100, 1, Some(1024)), None);
+        assert_eq!(
+            cfg.recycle_reason(100, 1, Some(1025)).as_deref(),
+            Some("rss is 1025 bytes, limit is 1024")
+        );
+        // RSS couldn't be read
+        assert_eq!(cfg.recycle_reason(100, 1, None), None);
+    }
+
+    #[test]
+    fn recycles_single_session_worker_once_used() {
+        let cfg = Config {
+            single_session: true,
+            ..Config::default()
+        };
+
+        assert_eq!(cfg.recycle_reason(0, 0, None), None);
+        assert_eq!(
+            cfg.recycle_reason(0, 1, None).as_deref(),
+            Some("forked for a single session")
+        );
+    }
+
+    #[test]
+    fn never_recycles_without_limits() {
+        let cfg = Config::default();
+        assert_eq!(cfg.recycle_reason(u64::MAX, 1, Some(u64::MAX)), None);
+    }
+}
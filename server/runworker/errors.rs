@@ -3,13 +3,16 @@ use std::result;
 
 use super::config;
 
+// Fields are read through Debug, when main returns the error
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct IoError {
     pub action: &'static str,
     pub error: io::Error,
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum Error {
     Io(IoError),
     Config(config::Error),
@@ -0,0 +1,188 @@
+// The template process of the fork server worker modes. It warms up once, then
+// forks a worker each time the master asks for one.
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process;
+
+use fd_queue::mio::UnixStream;
+use fd_queue::DequeueFd;
+use ndjsonlogger::{error, info};
+use pyo3::{ffi, Python};
+
+use crate::signals;
+
+use super::config::Config;
+use super::errors::{fatal_io_err, io_error, Result};
+use super::warmup;
+
+// See runmaster::forkserver
+const READY: u8 = b'r';
+const FORK: u8 = b'f';
+
+// Only returns in the forked workers, the template exits once the master closes its stream
+pub fn run(cfg: &Config) -> Result<()> {
+    info!("fork server template started");
+
+    if let Err(err) = warmup::run(cfg) {
+        error!("fork server template warm-up failed, exiting", {
+            error = &err[..]
+        });
+        process::exit(1);
+    }
+
+    // Anything left buffered would be written again by every worker
+    Python::with_gil(|py| {
+        for name in ["stdout", "stderr"] {
+            if let Ok(stream) = py.import("sys").and_then(|sys| sys.getattr(name)) {
+                stream.call_method0("flush").ok();
+            }
+        }
+    });
+
+    info!("fork server template warmed up", {
+        modules: usize = cfg.preload_modules.len()
+    });
+
+    // The master gives us our end of a socket pair as stdin
+    let std_stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(0) };
+    let mut stream = UnixStream::from_std(std_stream);
+    fatal_io_err(
+        "fork server template couldn't tell the master it's ready",
+        stream.write_all(&[READY]),
+    )?;
+
+    let mut msg = [0; 1];
+    loop {
+        match stream.read(&mut msg) {
+            Ok(0) => {
+                info!("master closed fork server stream, template exiting");
+                process::exit(0);
+            }
+            Ok(_) if msg[0] == FORK => {}
+            Ok(_) => {
+                error!("fork server template received unrecognised message", {
+                    msg: u8 = msg[0]
+                });
+                continue;
+            }
+            Err(io_err) if io_err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(io_err) => {
+                return Err(io_error(
+                    "fork server template failed to read from master",
+                    io_err,
+                ));
+            }
+        }
+
+        // The worker's stdout and stderr come with the request, they're ours to close
+        let fds = (stream.dequeue(), stream.dequeue());
+        let pid = match fds {
+            (Some(stdout), Some(stderr)) => {
+                let stdout = unsafe { OwnedFd::from_raw_fd(stdout) };
+                let stderr = unsafe { OwnedFd::from_raw_fd(stderr) };
+
+                match fork_worker(stdout.as_raw_fd(), stderr.as_raw_fd()) {
+                    Ok(Some(pid)) => pid,
+                    Ok(None) => {
+                        // We're the worker, stdin is no longer the master's stream
+                        drop(stream);
+                        let devnull = fatal_io_err(
+                            "worker couldn't open /dev/null",
+                            fs::File::open("/dev/null"),
+                        )?;
+                        unsafe {
+                            libc::dup2(devnull.as_raw_fd(), 0);
+                        }
+                        return Ok(());
+                    }
+                    Err(io_err) => {
+                        error!("fork server template failed to fork worker", {
+                            error = &format!("{}", io_err)
+                        });
+                        0
+                    }
+                }
+            }
+            _ => {
+                error!("fork server template wasn't sent the worker's stdout and stderr");
+                0
+            }
+        };
+
+        fatal_io_err(
+            "fork server template couldn't reply to master",
+            stream.write_all(&pid.to_be_bytes()),
+        )?;
+    }
+}
+
+// Fork twice so the worker is orphaned, and re-parented to the master.
+// Returns the worker's pid, or None in the worker itself.
+fn fork_worker(stdout: RawFd, stderr: RawFd) -> io::Result<Option<u32>> {
+    // The intermediate process tells us the worker's pid through this
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut pid_read = unsafe { fs::File::from_raw_fd(fds[0]) };
+    let pid_write = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+
+    // Anything left buffered would be written again by the worker
+    io::stdout().flush().unwrap_or(());
+
+    let in_template = Python::with_gil(|_py| unsafe {
+        ffi::PyOS_BeforeFork();
+        match libc::fork() {
+            -1 => {
+                let err = io::Error::last_os_error();
+                ffi::PyOS_AfterFork_Parent();
+                Err(err)
+            }
+            0 => {
+                let pid = libc::fork();
+                if pid != 0 {
+                    // A failed fork is reported as pid 0
+                    let pid = (pid.max(0) as u32).to_be_bytes();
+                    libc::write(
+                        pid_write.as_raw_fd(),
+                        pid.as_ptr() as *const libc::c_void,
+                        pid.len(),
+                    );
+                    libc::_exit(0);
+                }
+
+                ffi::PyOS_AfterFork_Child();
+                if libc::dup2(stdout, 1) < 0 || libc::dup2(stderr, 2) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Whatever mask the template was started with, the worker blocks
+                // the shutdown signals only on its event loop thread
+                signals::unblock(&signals::SHUTDOWN_SIGNALS)?;
+                Ok(false)
+            }
+            intermediate => {
+                ffi::PyOS_AfterFork_Parent();
+                let mut status = 0;
+                libc::waitpid(intermediate, &mut status, 0);
+                Ok(true)
+            }
+        }
+    })?;
+
+    if !in_template {
+        return Ok(None);
+    }
+
+    // Read fails if the intermediate process exited without writing
+    drop(pid_write);
+    let mut pid = [0; 4];
+    pid_read.read_exact(&mut pid)?;
+
+    match u32::from_be_bytes(pid) {
+        0 => Err(io::Error::other(
+            "intermediate process couldn't fork worker",
+        )),
+        pid => Ok(Some(pid)),
+    }
+}
@@ -1,26 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
 use std::os::fd::FromRawFd;
-use std::sync::Arc;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time;
 
 use fd_queue::mio::UnixStream;
 use mio::net::TcpStream;
-use mio::{Events, Interest, Poll, Token};
-use ndjsonlogger::info;
+use mio::{Events, Interest, Poll, Token, Waker};
+use ndjsonlogger::{error, info};
 
-use crate::messages::LogValue;
+use crate::messages::{LoadMessage, LogValue};
+use crate::signals::{self, SignalFd};
 
 mod errors;
 pub use errors::{fatal_io_err, Error, Result};
+mod capture;
 mod clientstream;
 pub mod config;
+pub mod forkserver;
 mod pythread;
+pub mod warmup;
 mod workerstream;
 
 const RO: Interest = Interest::READABLE;
 const WORKER_STREAM_TK: Token = Token(0);
-const TOKEN_START: usize = 1;
-const POLL_TIME: time::Duration = time::Duration::from_millis(100);
+const SIGNAL_TK: Token = Token(1);
+const WAKER_TK: Token = Token(2);
+const TOKEN_START: usize = 3;
 
 pub fn run_forever(
     cfg: Arc<config::Config>,
@@ -34,11 +46,78 @@ pub fn run_forever(
     )?;
 
     let unix_stream = UnixStream::from_std(unix_stream);
-    let mut worker_stream = workerstream::WorkerStream::new(unix_stream);
+    let worker_stream = workerstream::WorkerStream::new(unix_stream);
     let logger = worker_stream.new_logger();
-    let (thread_sender, _) = pythread::start(logger.clone())?;
 
-    let mut poll = fatal_io_err("worker couldn't create mio poll instance", Poll::new())?;
+    let poll = fatal_io_err("worker couldn't create mio poll instance", Poll::new())?;
+    let waker = fatal_io_err(
+        "worker couldn't create mio waker",
+        Waker::new(poll.registry(), WAKER_TK),
+    )?;
+    let waker = Arc::new(waker);
+    let (thread_sender, thread_recv, capture_recv, atoms, executor) =
+        pythread::new(logger.clone(), cfg.exec_threads, waker.clone());
+
+    // Atoms run on the main thread, python only interrupts blocking calls there
+    let event_loop = thread::Builder::new()
+        .name(String::from("event loop"))
+        .spawn(move || {
+            // Closing the waker drops any wake we haven't polled yet,
+            // so the exec threads' last one mustn't close it
+            let _waker = waker;
+
+            let res = event_loop(
+                cfg,
+                poll,
+                worker_stream,
+                logger,
+                thread_sender,
+                thread_recv,
+                capture_recv,
+                atoms,
+            );
+
+            // Don't wait for queued atoms nobody will get the results of
+            if let Err(err) = res {
+                error!("worker event loop failed, exiting", {
+                    error = &format!("{:?}", err)
+                });
+                process::exit(1);
+            }
+        });
+    let event_loop = fatal_io_err("worker failed to spawn its event loop", event_loop)?;
+
+    executor.run()?;
+    event_loop.join().unwrap_or(());
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn event_loop(
+    cfg: Arc<config::Config>,
+    mut poll: Poll,
+    mut worker_stream: workerstream::WorkerStream,
+    logger: workerstream::Logger,
+    thread_sender: pythread::Sender,
+    thread_recv: mpsc::Receiver<pythread::ResponseMessage>,
+    capture_recv: mpsc::Receiver<capture::Capture>,
+    atoms: pythread::Atoms,
+) -> Result<()> {
+    // Dropped once we start draining, the exec threads exit after running what they were sent
+    let mut thread_sender = Some(thread_sender);
+    let mut pythread_done = false;
+
+    // Only this thread blocks the signals, the exec threads and anything an atom
+    // starts keep a clean mask so they can be signalled as usual
+    let mut signal_fd = fatal_io_err(
+        "worker failed to create signal fd",
+        SignalFd::new(&signals::SHUTDOWN_SIGNALS),
+    )?;
+    fatal_io_err(
+        "worker failed to forward signals to its event loop",
+        forward_signals(&signals::SHUTDOWN_SIGNALS),
+    )?;
 
     let mut ws_interest = RO;
 
@@ -48,26 +127,83 @@ pub fn run_forever(
             .register(&mut worker_stream, WORKER_STREAM_TK, ws_interest),
     )?;
 
+    fatal_io_err(
+        "worker couldn't register signal fd with mio poll",
+        poll.registry().register(&mut signal_fd, SIGNAL_TK, RO),
+    )?;
+
     let mut events = Events::with_capacity(1024);
     let mut buffer = vec![0; 4096];
     let mut token_io = TOKEN_START;
-    let mut client_streams = HashMap::new();
+    let mut client_streams: HashMap<Token, clientstream::ClientStream> = HashMap::new();
+    let mut captures: HashMap<Token, capture::Capture> = HashMap::new();
+    let mut session_tokens = HashMap::new();
+    // Sessions whose output the master can't keep up with, their captures go unread
+    // so their atoms block on their next write until the master resumes them
+    let mut paused_output = HashSet::new();
     let mut to_remove = vec![];
+    let mut last_load = LoadMessage::default();
+    let mut atoms_done = 0;
+    let mut sessions_opened = 0;
+    let mut sessions_received = 0;
+    let mut recycling = false;
 
     loop {
+        for tk in to_remove.drain(..) {
+            if let Some(client_stream) = client_streams.remove(&tk) {
+                let session_id = client_stream.session_id().to_owned();
+                session_tokens.remove(&session_id);
+                worker_stream.session_closed(&session_id);
+
+                // Nobody is left to answer, queued atoms are skipped even while draining
+                let discarded = atoms.session_closed(&session_id, cfg.interrupt_on_disconnect);
+                if discarded > 0 {
+                    logger.info(
+                        "discarded queued atoms of closed session",
+                        vec![
+                            ("session_id", LogValue::String(session_id.clone())),
+                            ("discarded", LogValue::Int(discarded as i64)),
+                        ],
+                    );
+                }
+
+                // Whatever is left of its output is lost anyway, don't leave its atoms blocked
+                if paused_output.remove(&session_id) {
+                    read_captures(
+                        &poll,
+                        &mut captures,
+                        &session_id,
+                        &mut buffer,
+                        &logger,
+                        &cfg,
+                    );
+                }
+
+                if let Some(thread_sender) = &thread_sender {
+                    thread_sender.send(pythread::Command::SessionClosed(session_id));
+                }
+            }
+        }
+
         // Take any new streams
         while let Some((header, fd)) = worker_stream.next_msg() {
             let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
-            stream.set_nonblocking(true);
+            let nth = sessions_received;
+            sessions_received += 1;
+
+            // Draining, nothing has been read from it so another worker can take it.
+            // Dropping it closes our copy of the fd, the master's keeps it open.
+            if thread_sender.is_none() {
+                worker_stream.session_returned(nth);
+                continue;
+            }
+
+            // Dropped, as it is when it can't be registered
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
             let stream = TcpStream::from_std(stream);
-            let mut client_stream = match clientstream::ClientStream::new(&cfg, header, stream, RO)
-            {
-                Ok(cs) => cs,
-                Err(_) => {
-                    // Just drop bad client
-                    continue;
-                }
-            };
+            let mut client_stream = clientstream::ClientStream::new(&cfg, header, stream, RO);
             if poll
                 .registry()
                 .register(&mut client_stream, Token(token_io), RO)
@@ -80,12 +216,138 @@ pub fn run_forever(
                         LogValue::String(client_stream.session_id().to_owned()),
                     )],
                 );
+                worker_stream.session_opened(client_stream.session_id());
+                sessions_opened += 1;
+                session_tokens.insert(client_stream.session_id().to_owned(), Token(token_io));
                 client_streams.insert(Token(token_io), client_stream);
             }
 
             token_io += 1;
         }
 
+        while let Some((session_id, pause)) = worker_stream.next_output_pause() {
+            if pause {
+                paused_output.insert(session_id);
+            } else if paused_output.remove(&session_id) {
+                // Edge triggered, what was written while paused won't wake us
+                read_captures(
+                    &poll,
+                    &mut captures,
+                    &session_id,
+                    &mut buffer,
+                    &logger,
+                    &cfg,
+                );
+            }
+        }
+
+        take_captures(&poll, &capture_recv, &mut captures, &mut token_io);
+
+        // Take any responses from the exec threads
+        loop {
+            let resp_msg = match thread_recv.try_recv() {
+                Ok(resp_msg) => resp_msg,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    pythread_done = true;
+                    break;
+                }
+            };
+            atoms_done += 1;
+
+            // Send what the atom wrote ahead of its result, it has closed its pipes
+            take_captures(&poll, &capture_recv, &mut captures, &mut token_io);
+            if !paused_output.contains(resp_msg.session_id()) {
+                read_captures(
+                    &poll,
+                    &mut captures,
+                    resp_msg.session_id(),
+                    &mut buffer,
+                    &logger,
+                    &cfg,
+                );
+            }
+
+            let client_stream = match session_tokens
+                .get(resp_msg.session_id())
+                .and_then(|tk| client_streams.get_mut(tk))
+            {
+                Some(cs) => cs,
+                None => {
+                    // Client has since disconnected - drop the response
+                    continue;
+                }
+            };
+
+            match resp_msg {
+                pythread::ResponseMessage::CodeString(_, resp) => {
+                    client_stream.queue_response(protocol::MessageType::CodeString, resp);
+                }
+                pythread::ResponseMessage::CodePickle(_, resp) => {
+                    client_stream.queue_response(protocol::MessageType::CodePickle, resp);
+                }
+                pythread::ResponseMessage::Error(_, resp) => {
+                    client_stream.queue_error(resp);
+                }
+            }
+        }
+
+        // Past our limits, take no new sessions and exit once the current ones close
+        if !recycling {
+            let rss = cfg.max_rss_bytes.and_then(|_| rss_bytes());
+            if let Some(reason) = cfg.recycle_reason(atoms_done, sessions_opened, rss) {
+                logger.info(
+                    "worker recycling once its sessions close",
+                    vec![("reason", LogValue::String(reason.clone()))],
+                );
+                worker_stream.recycle(reason);
+                recycling = true;
+            }
+        }
+        if recycling && client_streams.is_empty() {
+            thread_sender.take();
+        }
+
+        // Report load changes, the master uses them to place new sessions
+        let load = LoadMessage {
+            sessions: client_streams.len() as u32,
+            queued_atoms: atoms.pending() as u32,
+        };
+        if load != last_load {
+            worker_stream.report_load(load);
+            last_load = load;
+        }
+
+        // Drained, whatever atoms left running in the background write is lost
+        if thread_sender.is_none() && pythread_done {
+            for (_, mut capture) in captures.drain() {
+                capture
+                    .read(&mut buffer, &logger, cfg.max_line_bytes)
+                    .unwrap_or(false);
+                capture.flush(&logger);
+                poll.registry().deregister(&mut capture).unwrap_or(());
+            }
+        }
+
+        // Drained, exit once every response and log has been written. Recycling we
+        // wait for the master to stop dispatching, sessions it sent are returned.
+        if thread_sender.is_none()
+            && pythread_done
+            && (!recycling || worker_stream.dispatch_stopped())
+            && !worker_stream.has_data()
+            && client_streams.values().all(|cs| !cs.has_out_data())
+        {
+            return Ok(());
+        }
+
+        // Send output which has waited long enough for a newline
+        let mut flush_due: Option<time::Duration> = None;
+        for capture in captures.values_mut() {
+            if let Some(remaining) = capture.flush_after(cfg.output_flush_delay, &logger) {
+                flush_due = Some(flush_due.map_or(remaining, |due| due.min(remaining)));
+            }
+        }
+
         // Reregister our worker stream RO/RW as needed
         if ws_interest == RO && worker_stream.has_data() {
             ws_interest = Interest::READABLE | Interest::WRITABLE;
@@ -101,11 +363,52 @@ pub fn run_forever(
                 poll.registry()
                     .reregister(&mut worker_stream, WORKER_STREAM_TK, ws_interest),
             )?;
+        } else if ws_interest.is_writable() {
+            // Queued since our last write, we won't get another writable event for it
+            fatal_io_err(
+                "worker failed to write on worker stream",
+                worker_stream.write(),
+            )?;
         }
 
         // Take any request messages from TcpStreams
         for (tk, client_stream) in client_streams.iter_mut() {
             while let Some(req_msg) = client_stream.next_req_msg() {
+                if let protocol::RequestMessage::Cancel(cancel) = req_msg {
+                    cancel_atom(&logger, &atoms, client_stream, cancel);
+                    continue;
+                }
+
+                let thread_sender = match &thread_sender {
+                    Some(thread_sender) => thread_sender,
+                    None => {
+                        let err = protocol::ErrorResponse::new(
+                            protocol::ErrorCode::ShuttingDown,
+                            String::from("worker is shutting down"),
+                            req_msg.future_id().map(|id| id.to_owned()),
+                        );
+                        client_stream.queue_error(err);
+                        continue;
+                    }
+                };
+
+                if let Some(max) = cfg.max_queued_atoms {
+                    if thread_sender.queued(client_stream.session_id()) >= max {
+                        let err = protocol::ErrorResponse::new(
+                            protocol::ErrorCode::Busy,
+                            format!("session already has {} atoms queued", max),
+                            req_msg.future_id().map(|id| id.to_owned()),
+                        );
+                        client_stream.queue_error(err);
+                        continue;
+                    }
+                }
+
+                atoms.queue(
+                    client_stream.session_id(),
+                    req_msg.future_id().unwrap_or("0000"),
+                    cfg.atom_timeout(req_msg.timeout_ms()),
+                );
                 logger.info(
                     "queueing new pyproxy atom processing",
                     vec![
@@ -119,7 +422,18 @@ pub fn run_forever(
                         ),
                     ],
                 );
-                thread_sender.send((client_stream.session_id().to_owned(), req_msg));
+                thread_sender.send(pythread::Command::Atom(
+                    client_stream.session_id().to_owned(),
+                    req_msg,
+                ));
+            }
+
+            // Error response has been sent - close the stream
+            if client_stream.is_closed() {
+                poll.registry().deregister(client_stream).unwrap_or(());
+                client_stream.shutdown();
+                to_remove.push(*tk);
+                continue;
             }
 
             // Reregister client stream RO or RW
@@ -134,13 +448,22 @@ pub fn run_forever(
                 if poll.registry().reregister(client_stream, *tk, RO).is_err() {
                     to_remove.push(*tk);
                 }
+            } else if client_stream.interest().is_writable() && client_stream.write().is_err() {
+                // Queued since our last write, we won't get another writable event for it
+                poll.registry().deregister(client_stream).unwrap_or(());
+                to_remove.push(*tk);
             }
         }
 
-        fatal_io_err(
-            "worker couldn't call mio poll",
-            poll.poll(&mut events, None),
-        )?;
+        // Wake up in time to interrupt an atom at its deadline, or to flush output.
+        // The exec threads wake us for anything else they do.
+        let poll_time = atoms.expire().into_iter().chain(flush_due).min();
+
+        // Atoms' subprocesses exiting can interrupt us
+        match poll.poll(&mut events, poll_time) {
+            Err(io_err) if io_err.kind() == io::ErrorKind::Interrupted => continue,
+            res => fatal_io_err("worker couldn't call mio poll", res)?,
+        }
 
         for ev in &events {
             if ev.token() == WORKER_STREAM_TK {
@@ -162,23 +485,183 @@ pub fn run_forever(
                 continue;
             }
 
-            if let Some(client_stream) = client_streams.get_mut(&ev.token()) {
-                if ev.is_readable() {
-                    if client_stream.read(&mut buffer).is_err() {
-                        poll.registry().deregister(client_stream).unwrap_or(());
-                        to_remove.push(ev.token());
+            // The exec threads have sent something, it's taken at the top of the loop
+            if ev.token() == WAKER_TK {
+                continue;
+            }
+
+            if ev.token() == SIGNAL_TK {
+                while let Ok(Some(sig)) = signal_fd.read() {
+                    if thread_sender.take().is_some() {
+                        logger.info(
+                            "worker draining in-flight atoms",
+                            vec![("signal", LogValue::Int(sig as i64))],
+                        );
                     }
                 }
 
-                if ev.is_writable() {
-                    // Send response to client
-                    if client_stream.write().is_err() {
-                        poll.registry().deregister(client_stream).unwrap_or(());
-                        to_remove.push(ev.token());
-                    } else {
-                    }
+                continue;
+            }
+
+            if let Some(capture) = captures.get_mut(&ev.token()) {
+                if paused_output.contains(&capture.session_id) {
+                    continue;
+                }
+
+                // Closed once the atom and any subprocesses it started are done with it
+                if capture
+                    .read(&mut buffer, &logger, cfg.max_line_bytes)
+                    .unwrap_or(true)
+                {
+                    poll.registry().deregister(capture).unwrap_or(());
+                    captures.remove(&ev.token());
+                }
+
+                continue;
+            }
+
+            if let Some(client_stream) = client_streams.get_mut(&ev.token()) {
+                if ev.is_readable() && client_stream.read(&mut buffer).is_err() {
+                    poll.registry().deregister(client_stream).unwrap_or(());
+                    to_remove.push(ev.token());
+                }
+
+                // Send response to client
+                if ev.is_writable() && client_stream.write().is_err() {
+                    poll.registry().deregister(client_stream).unwrap_or(());
+                    to_remove.push(ev.token());
                 }
             }
         }
     }
 }
+
+// Register the output pipes of atoms which have started
+fn take_captures(
+    poll: &Poll,
+    capture_recv: &mpsc::Receiver<capture::Capture>,
+    captures: &mut HashMap<Token, capture::Capture>,
+    token_io: &mut usize,
+) {
+    while let Ok(mut capture) = capture_recv.try_recv() {
+        if poll
+            .registry()
+            .register(&mut capture, Token(*token_io), RO)
+            .is_ok()
+        {
+            captures.insert(Token(*token_io), capture);
+        }
+
+        *token_io += 1;
+    }
+}
+
+// Read what a session's atoms have written, dropping the captures they've closed
+fn read_captures(
+    poll: &Poll,
+    captures: &mut HashMap<Token, capture::Capture>,
+    session_id: &str,
+    buffer: &mut [u8],
+    logger: &workerstream::Logger,
+    cfg: &config::Config,
+) {
+    captures.retain(|_, capture| {
+        if capture.session_id != session_id {
+            return true;
+        }
+
+        let closed = capture
+            .read(buffer, logger, cfg.max_line_bytes)
+            .unwrap_or(true);
+        if closed {
+            poll.registry().deregister(capture).unwrap_or(());
+        }
+        !closed
+    });
+}
+
+// Where forward_signal sends signals on to, and the process that thread is in
+static FORWARD_THREAD: AtomicU64 = AtomicU64::new(0);
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+
+// The kernel hands a signal sent to the worker to any thread not blocking it,
+// those caught on another thread are sent on to this one for its signal fd
+fn forward_signals(signals: &[libc::c_int]) -> io::Result<()> {
+    FORWARD_THREAD.store(unsafe { libc::pthread_self() } as u64, Ordering::SeqCst);
+    FORWARD_PID.store(unsafe { libc::getpid() }, Ordering::SeqCst);
+
+    for sig in signals {
+        let res = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as usize;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(*sig, &action, ptr::null_mut())
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    unsafe {
+        if libc::getpid() == FORWARD_PID.load(Ordering::SeqCst) {
+            libc::pthread_kill(
+                FORWARD_THREAD.load(Ordering::SeqCst) as libc::pthread_t,
+                sig,
+            );
+        } else {
+            // An atom forked without exec, there's no event loop to send it to
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+// Resident set size, None if /proc can't tell us
+fn rss_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+fn cancel_atom(
+    logger: &workerstream::Logger,
+    atoms: &pythread::Atoms,
+    client_stream: &mut clientstream::ClientStream,
+    cancel: protocol::Cancel,
+) {
+    let outcome = atoms.cancel(client_stream.session_id(), &cancel.future_id);
+
+    logger.info(
+        "cancelled pyproxy atom",
+        vec![
+            (
+                "session_id",
+                LogValue::String(client_stream.session_id().to_owned()),
+            ),
+            ("future_id", LogValue::String(cancel.future_id.clone())),
+            ("outcome", LogValue::String(outcome.name().to_owned())),
+        ],
+    );
+
+    // The atom will never run, so fail its future here
+    if outcome == protocol::CancelOutcome::BeforeExecution {
+        let err = protocol::ErrorResponse::new(
+            protocol::ErrorCode::Cancelled,
+            String::from("atom cancelled before execution"),
+            Some(cancel.future_id.clone()),
+        );
+        client_stream.queue_error(err);
+    }
+
+    let resp = protocol::ResponseCancel {
+        future_id: cancel.future_id,
+        outcome,
+    };
+    client_stream.queue_response(protocol::MessageType::Cancel, resp);
+}
@@ -1,57 +1,734 @@
-use std::sync::mpsc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::raw::{c_int, c_long};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
+use std::time;
 
+use mio::Waker;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::ffi;
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyDict};
+use pyo3::types::{PyBytes, PyDict, PyList};
 
-use protocol::RequestMessage;
+use protocol::mainstream::PythonResult;
+use protocol::{CancelOutcome, RequestMessage};
 
-use crate::messages::{LogValue, NEW_REQUEST_END, NEW_REQUEST_START};
+use crate::messages::LogValue;
 
+use super::capture::{Capture, Redirector, Routers};
 use super::errors::{io_error, Result};
 use super::workerstream::Logger;
 
-pub enum ResponseMessage {}
+// Filename reported in tracebacks of code sent as a string
+const CODE_FILENAME: &str = "<pyproxy>";
 
-pub fn start(
-    logger: Logger,
-) -> Result<(
-    mpsc::Sender<(String, RequestMessage)>,
-    mpsc::Receiver<ResponseMessage>,
-)> {
-    let (req_send, req_recv) = mpsc::channel();
-    let (exec_send, exec_recv) = mpsc::channel();
+// Sent to an exec thread to break it out of blocking calls when interrupting an atom
+const INTERRUPT_SIGNAL: c_int = libc::SIGUSR1;
+
+// Holds a value already pickled on its own, which pickles as a call unpickling it
+const PICKLED_CLASS: &str = "
+import pickle
+
+class Pickled:
+    __slots__ = ('data',)
 
-    thread::Builder::new()
-        .name(String::from("pythread"))
-        .spawn(move || Python::with_gil(|py| run_forever(py, logger, req_recv, exec_send)))
-        .map_err(|e| io_error("failed to spawn pythread", e))?;
+    def __init__(self, data):
+        self.data = data
 
-    Ok((req_send, exec_recv))
+    def __reduce__(self):
+        return (pickle.loads, (self.data,))
+";
+
+pub enum ResponseMessage {
+    CodeString(String, protocol::ResponseCodeString),
+    CodePickle(String, protocol::ResponseCodePickle),
+    Error(String, protocol::ErrorResponse),
 }
 
-fn run_forever(
-    py: Python,
+impl ResponseMessage {
+    pub fn session_id(&self) -> &str {
+        match self {
+            ResponseMessage::CodeString(session_id, _) => session_id,
+            ResponseMessage::CodePickle(session_id, _) => session_id,
+            ResponseMessage::Error(session_id, _) => session_id,
+        }
+    }
+}
+
+// Messages from the worker event loop to the exec threads
+pub enum Command {
+    Atom(String, RequestMessage),
+    SessionClosed(String),
+}
+
+impl Command {
+    fn session_id(&self) -> &str {
+        match self {
+            Command::Atom(session_id, _) => session_id,
+            Command::SessionClosed(session_id) => session_id,
+        }
+    }
+}
+
+// (session_id, future_id) of an atom
+type AtomId = (String, String);
+
+struct RunningAtom {
+    id: AtomId,
+    // Python thread ident of the exec thread running it, async exceptions are raised there
+    thread_id: c_long,
+    // Set if we can signal the exec thread out of blocking calls
+    pthread: Option<libc::pthread_t>,
+    timeout: Option<time::Duration>,
+    deadline: Option<time::Instant>,
+    // Past its deadline, sent to the interrupter
+    expired: bool,
+    // Interrupted for running past its deadline
+    timed_out: bool,
+}
+
+#[derive(Default)]
+struct AtomState {
+    // Queued atoms and the timeout each will run with
+    queued: HashMap<AtomId, Option<time::Duration>>,
+    // At most one per exec thread
+    running: Vec<RunningAtom>,
+}
+
+// Running atoms the event loop wants interrupted
+enum Interrupt {
+    // Cancelled, or its session closed
+    Cancel(AtomId),
+    // Ran past its timeout
+    Timeout(AtomId),
+}
+
+// Atoms sent to the exec threads which haven't finished yet,
+// shared with the worker event loop so it can cancel them.
+// Exec threads and the interrupter only take the lock while holding the GIL,
+// so we must never take the GIL while holding the lock.
+// The event loop never takes the GIL, an atom may hold it for as long as it likes.
+#[derive(Clone)]
+pub struct Atoms {
+    state: Arc<Mutex<AtomState>>,
+    interrupts: mpsc::Sender<Interrupt>,
+}
+
+impl Atoms {
+    fn new() -> (Self, mpsc::Receiver<Interrupt>) {
+        let (interrupts, recv) = mpsc::channel();
+        let atoms = Self {
+            state: Arc::new(Mutex::new(AtomState::default())),
+            interrupts,
+        };
+        (atoms, recv)
+    }
+
+    pub fn queue(&self, session_id: &str, future_id: &str, timeout: Option<time::Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .queued
+            .insert((session_id.to_owned(), future_id.to_owned()), timeout);
+    }
+
+    // Atoms queued or running
+    pub fn pending(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.queued.len() + state.running.len()
+    }
+
+    // Queued atoms are dropped, a running atom gets a KeyboardInterrupt
+    pub fn cancel(&self, session_id: &str, future_id: &str) -> CancelOutcome {
+        let id = (session_id.to_owned(), future_id.to_owned());
+
+        let mut state = self.state.lock().unwrap();
+        if state.queued.remove(&id).is_some() {
+            return CancelOutcome::BeforeExecution;
+        }
+
+        if state.running.iter().any(|r| r.id == id) {
+            self.interrupts.send(Interrupt::Cancel(id)).unwrap_or(());
+            CancelOutcome::DuringExecution
+        } else {
+            CancelOutcome::NotFound
+        }
+    }
+
+    // Drops the closed session's queued atoms, returning how many,
+    // and interrupts its running atom if asked to
+    pub fn session_closed(&self, session_id: &str, interrupt_running: bool) -> usize {
+        let mut state = self.state.lock().unwrap();
+        if interrupt_running {
+            if let Some(running) = state.running.iter().find(|r| r.id.0 == session_id) {
+                let id = running.id.clone();
+                self.interrupts.send(Interrupt::Cancel(id)).unwrap_or(());
+            }
+        }
+
+        let queued = state.queued.len();
+        state.queued.retain(|(id, _), _| id != session_id);
+        queued - state.queued.len()
+    }
+
+    // Interrupt running atoms once they pass their deadline,
+    // returns how long until the next one does
+    pub fn expire(&self) -> Option<time::Duration> {
+        let now = time::Instant::now();
+        let mut next: Option<time::Duration> = None;
+
+        let mut state = self.state.lock().unwrap();
+        for running in state.running.iter_mut().filter(|r| !r.expired) {
+            match running.deadline {
+                Some(deadline) if deadline <= now => {
+                    running.expired = true;
+                    let id = running.id.clone();
+                    self.interrupts.send(Interrupt::Timeout(id)).unwrap_or(());
+                }
+                Some(deadline) => {
+                    let remaining = deadline - now;
+                    next = Some(next.map_or(remaining, |next| next.min(remaining)));
+                }
+                None => {}
+            }
+        }
+
+        next
+    }
+
+    // False if the atom was cancelled while it was queued
+    fn start(&self, _py: Python, id: AtomId, thread: &ExecThreadState) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let timeout = match state.queued.remove(&id) {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+
+        state.running.push(RunningAtom {
+            id,
+            thread_id: thread.thread_id,
+            pthread: thread.pthread,
+            timeout,
+            deadline: timeout.map(|t| time::Instant::now() + t),
+            expired: false,
+            timed_out: false,
+        });
+        true
+    }
+
+    // Returns the atom's timeout if it was interrupted for running past it
+    fn finish(&self, _py: Python, thread: &ExecThreadState) -> Option<time::Duration> {
+        let mut state = self.state.lock().unwrap();
+        let n = state
+            .running
+            .iter()
+            .position(|r| r.thread_id == thread.thread_id);
+        let running = n.map(|n| state.running.remove(n));
+
+        // A cancel may have landed after the atom's last bytecode,
+        // don't let it fire in the next atom
+        unsafe {
+            ffi::PyThreadState_SetAsyncExc(thread.thread_id, std::ptr::null_mut());
+        }
+
+        running.filter(|r| r.timed_out).and_then(|r| r.timeout)
+    }
+}
+
+// Runs on its own thread, taking the GIL to interrupt atoms for the event loop.
+// Exits once every Atoms has gone.
+fn interrupter(state: Arc<Mutex<AtomState>>, interrupts: mpsc::Receiver<Interrupt>) {
+    for req in interrupts {
+        Python::with_gil(|_py| {
+            let mut state = state.lock().unwrap();
+            let (id, timeout) = match &req {
+                Interrupt::Cancel(id) => (id, false),
+                Interrupt::Timeout(id) => (id, true),
+            };
+
+            // It may have finished while we took the GIL
+            if let Some(running) = state.running.iter_mut().find(|r| r.id == *id) {
+                interrupt(running.thread_id, running.pthread);
+                running.timed_out |= timeout;
+            }
+        });
+    }
+}
+
+// Raise KeyboardInterrupt in an exec thread, the GIL must be held.
+// Async exceptions only fire between bytecodes, so we also signal the
+// exec thread out of any blocking call, its python handler then raises it.
+fn interrupt(thread_id: c_long, pthread: Option<libc::pthread_t>) {
+    unsafe {
+        ffi::PyThreadState_SetAsyncExc(thread_id, ffi::PyExc_KeyboardInterrupt);
+        if let Some(pthread) = pthread {
+            libc::pthread_kill(pthread, INTERRUPT_SIGNAL);
+        }
+    }
+}
+
+// Python only runs signal handlers on its main thread, so this
+// fails on every exec thread but the worker's main thread
+fn install_interrupt_handler(py: Python) -> PyResult<()> {
+    let handler = py.eval("lambda signum, frame: None", None, None)?;
+    PyModule::import(py, "signal")?
+        .getattr("signal")?
+        .call1((INTERRUPT_SIGNAL, handler))?;
+    Ok(())
+}
+
+// Commands waiting for an exec thread. A session's commands run one at a time
+// in the order they were sent, atoms of different sessions may run at once.
+// Sessions take turns, so one pipelining many atoms can't starve the rest.
+// Turns are round robin, sessions aren't weighted.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    // Each session's waiting commands, only sessions with some are kept
+    sessions: HashMap<String, VecDeque<Command>>,
+    // Sessions with waiting commands, the first idle one goes next then moves to the back
+    turns: VecDeque<String>,
+    // Sessions with a command on an exec thread
+    busy: HashSet<String>,
+    // The event loop has stopped sending, exec threads exit once the queue is empty
+    closed: bool,
+}
+
+impl Queue {
+    // Blocks until there's a command for an idle session, None once closed and drained
+    fn next(&self) -> Option<Command> {
+        let mut guard = self.state.lock().unwrap();
+        loop {
+            let state = &mut *guard;
+            let turn = state
+                .turns
+                .iter()
+                .position(|session_id| !state.busy.contains(session_id));
+
+            if let Some(session_id) = turn.and_then(|n| state.turns.remove(n)) {
+                let commands = state.sessions.get_mut(&session_id).unwrap();
+                let cmd = commands.pop_front().unwrap();
+                if commands.is_empty() {
+                    state.sessions.remove(&session_id);
+                } else {
+                    state.turns.push_back(session_id.clone());
+                }
+
+                state.busy.insert(session_id);
+                return Some(cmd);
+            }
+
+            if state.closed && state.turns.is_empty() {
+                return None;
+            }
+
+            guard = self.ready.wait(guard).unwrap();
+        }
+    }
+
+    // The session's command has finished, its next one may run
+    fn done(&self, session_id: &str) {
+        self.state.lock().unwrap().busy.remove(session_id);
+        self.ready.notify_all();
+    }
+}
+
+// The event loop's end of the queue, dropping it lets the exec threads drain and exit
+pub struct Sender {
+    queue: Arc<Queue>,
+}
+
+impl Sender {
+    // A closed session's waiting commands are dropped, its atoms will never be answered
+    pub fn send(&self, cmd: Command) {
+        let mut guard = self.queue.state.lock().unwrap();
+        let state = &mut *guard;
+        match state.sessions.get_mut(cmd.session_id()) {
+            Some(commands) => {
+                if let Command::SessionClosed(_) = cmd {
+                    commands.clear();
+                }
+                commands.push_back(cmd);
+            }
+            None => {
+                let session_id = cmd.session_id().to_owned();
+                state.turns.push_back(session_id.clone());
+                state.sessions.insert(session_id, VecDeque::from([cmd]));
+            }
+        }
+        drop(guard);
+
+        self.queue.ready.notify_one();
+    }
+
+    // Commands the session has waiting, not counting one running
+    pub fn queued(&self, session_id: &str) -> usize {
+        let state = self.queue.state.lock().unwrap();
+        state
+            .sessions
+            .get(session_id)
+            .map_or(0, |commands| commands.len())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.ready.notify_all();
+    }
+}
+
+// Runs atoms on the thread which calls run, plus exec_threads - 1 more
+pub struct Executor {
+    exec: ExecThread,
+    exec_threads: usize,
+    interrupts: mpsc::Receiver<Interrupt>,
+}
+
+#[derive(Clone)]
+struct ExecThread {
+    queue: Arc<Queue>,
     logger: Logger,
-    recv: mpsc::Receiver<(String, RequestMessage)>,
     sender: mpsc::Sender<ResponseMessage>,
+    // Read ends of each atom's output pipes, for the event loop
+    captures: mpsc::Sender<Capture>,
+    // The event loop only polls its fds, woken to take what we've sent
+    waker: Arc<Waker>,
+    capture_mode: CaptureMode,
+    atoms: Atoms,
+    // Namespaces of stateful sessions, keyed by session_id.
+    // Sessions may run on any exec thread, which lock it while holding the GIL.
+    namespaces: Arc<Mutex<HashMap<String, Py<PyDict>>>>,
+}
+
+// How exec threads point atoms' output at their pipes
+#[derive(Clone)]
+enum CaptureMode {
+    // Only with a single exec thread
+    Fds,
+    Python(Routers),
+    Off,
+}
+
+// What an exec thread fetches once, with the GIL
+struct ExecThreadState {
+    loads: PyObject,
+    dumps: PyObject,
+    marshal_loads: PyObject,
+    // See PICKLED_CLASS
+    pickled: PyObject,
+    thread_id: c_long,
+    pthread: Option<libc::pthread_t>,
+    // None if atoms' output can't be captured
+    redirector: Option<Redirector>,
+}
+
+pub fn new(
+    logger: Logger,
+    exec_threads: usize,
+    waker: Arc<Waker>,
+) -> (
+    Sender,
+    mpsc::Receiver<ResponseMessage>,
+    mpsc::Receiver<Capture>,
+    Atoms,
+    Executor,
 ) {
-    let loads = get_pickle_loads(py).unwrap();
-    let dumps = get_pickle_dumps(py).unwrap();
+    let queue = Arc::new(Queue::default());
+    let (exec_send, exec_recv) = mpsc::channel();
+    let (capture_send, capture_recv) = mpsc::channel();
+    let (atoms, interrupts) = Atoms::new();
+
+    let executor = Executor {
+        exec: ExecThread {
+            queue: queue.clone(),
+            logger,
+            sender: exec_send,
+            captures: capture_send,
+            waker,
+            capture_mode: CaptureMode::Fds,
+            atoms: atoms.clone(),
+            namespaces: Arc::new(Mutex::new(HashMap::new())),
+        },
+        exec_threads,
+        interrupts,
+    };
+
+    (Sender { queue }, exec_recv, capture_recv, atoms, executor)
+}
+
+impl Executor {
+    // Returns once the Sender is dropped and everything sent has run.
+    // Blocking calls are only interrupted on the worker's main thread,
+    // which must be the caller, elsewhere they're interrupted once they return.
+    pub fn run(mut self) -> Result<()> {
+        // Fds 1 and 2 can only point at one atom's pipes at a time
+        if self.exec_threads > 1 {
+            self.exec.capture_mode = Python::with_gil(|py| match Routers::install(py) {
+                Ok(routers) => CaptureMode::Python(routers),
+                Err(err) => {
+                    self.exec.logger.error(
+                        "couldn't install output routers, atoms' output won't be captured",
+                        vec![("error", LogValue::String(format!("{}", err)))],
+                    );
+                    CaptureMode::Off
+                }
+            });
+        }
+
+        // Not joined, it exits once the event loop and exec threads have dropped their Atoms
+        let state = self.exec.atoms.state.clone();
+        let interrupts = self.interrupts;
+        thread::Builder::new()
+            .name(String::from("interrupter"))
+            .spawn(move || interrupter(state, interrupts))
+            .map_err(|e| io_error("failed to spawn interrupter thread", e))?;
+
+        let mut threads = Vec::with_capacity(self.exec_threads);
+        for n in 1..self.exec_threads {
+            let exec = self.exec.clone();
+            let thread = thread::Builder::new()
+                .name(format!("exec{}", n))
+                .spawn(move || exec.run())
+                .map_err(|e| io_error("failed to spawn exec thread", e))?;
+            threads.push(thread);
+        }
+
+        self.exec.run();
+        for thread in threads {
+            thread.join().unwrap_or(());
+        }
+
+        Ok(())
+    }
+}
+
+impl ExecThread {
+    fn run(self) {
+        let state = Python::with_gil(|py| self.thread_state(py));
+
+        while let Some(cmd) = self.queue.next() {
+            let session_id = cmd.session_id().to_owned();
+
+            // Only hold the GIL while running, so threads started by atoms run in between
+            let resp = Python::with_gil(|py| self.run_command(py, &state, cmd));
+
+            // Worker event loop has gone - nobody to respond to
+            let sent = resp.is_none_or(|resp| self.sender.send(resp).is_ok());
+            self.queue.done(&session_id);
+            self.wake();
+            if !sent {
+                break;
+            }
+        }
+
+        // Once every exec thread's sender has gone the event loop sees we're done
+        let waker = self.waker.clone();
+        drop(self);
+        waker.wake().unwrap_or(());
+    }
+
+    fn wake(&self) {
+        if let Err(err) = self.waker.wake() {
+            self.logger.error(
+                "exec thread couldn't wake the event loop",
+                vec![("error", LogValue::String(format!("{}", err)))],
+            );
+        }
+    }
+
+    fn thread_state(&self, py: Python) -> ExecThreadState {
+        let main_thread = thread::current().name() == Some("main");
+        let pthread = match install_interrupt_handler(py) {
+            Ok(()) => Some(unsafe { libc::pthread_self() }),
+            Err(err) if main_thread => {
+                self.logger.error(
+                    "couldn't install interrupt handler, blocking calls won't be interrupted",
+                    vec![("error", LogValue::String(format!("{}", err)))],
+                );
+                None
+            }
+            Err(_) => None,
+        };
+
+        let redirector = match &self.capture_mode {
+            CaptureMode::Off => None,
+            CaptureMode::Python(routers) => Some(Redirector::Python(routers.clone())),
+            CaptureMode::Fds => match Redirector::fds(py) {
+                Ok(redirector) => Some(redirector),
+                Err(err) => {
+                    self.logger.error(
+                        "couldn't save stdout and stderr, atoms' output won't be captured",
+                        vec![("error", LogValue::String(format!("{}", err)))],
+                    );
+                    None
+                }
+            },
+        };
+
+        ExecThreadState {
+            loads: get_pickle_loads(py).unwrap(),
+            dumps: get_pickle_dumps(py).unwrap(),
+            marshal_loads: get_marshal_loads(py).unwrap(),
+            pickled: get_pickled_class(py).unwrap(),
+            thread_id: get_thread_ident(py).unwrap(),
+            pthread,
+            redirector,
+        }
+    }
+
+    fn run_command(
+        &self,
+        py: Python,
+        state: &ExecThreadState,
+        cmd: Command,
+    ) -> Option<ResponseMessage> {
+        let logger = &self.logger;
+        let (session_id, msg) = match cmd {
+            Command::Atom(session_id, msg) => (session_id, msg),
+            Command::SessionClosed(session_id) => {
+                let namespace = self.namespaces.lock().unwrap().remove(&session_id);
+                if namespace.is_some() {
+                    logger.info(
+                        "freed session namespace",
+                        vec![("session_id", LogValue::String(session_id))],
+                    );
+                }
+                return None;
+            }
+        };
+
+        let atom_id = (
+            session_id.clone(),
+            msg.future_id().unwrap_or("0000").to_owned(),
+        );
+        if !self.atoms.start(py, atom_id, state) {
+            logger.info(
+                "skipping cancelled pyproxy atom",
+                vec![
+                    ("session_id", LogValue::String(session_id)),
+                    (
+                        "future_id",
+                        LogValue::String(msg.future_id().unwrap_or("0000").to_owned()),
+                    ),
+                ],
+            );
+            return None;
+        }
 
-    for (session_id, msg) in recv {
         let mut future_id = String::from("0000");
-        logger.print(format!("{}{}", NEW_REQUEST_START, session_id));
+        let capturing = state.redirector.as_ref().is_some_and(|redirector| {
+            let future_id = msg.future_id().unwrap_or("0000");
+            match redirector.start(py, &session_id, future_id) {
+                Ok((stdout, stderr)) => {
+                    self.captures.send(stdout).unwrap_or(());
+                    self.captures.send(stderr).unwrap_or(());
+                    true
+                }
+                Err(err) => {
+                    logger.error(
+                        "couldn't capture pyproxy atom output",
+                        vec![
+                            ("session_id", LogValue::String(session_id.clone())),
+                            ("error", LogValue::String(format!("{}", err))),
+                        ],
+                    );
+                    false
+                }
+            }
+        });
+
+        // The event loop times the atom from here, and takes its pipes
+        self.wake();
 
-        match msg {
-            RequestMessage::Hello(_) => {}
-            RequestMessage::CodePickle(p) => err_handler(proc_code_pickle(py, p, &loads)),
+        let (loads, dumps) = (&state.loads, &state.dumps);
+        let resp = match msg {
+            RequestMessage::Hello(_) | RequestMessage::Cancel(_) => None,
+            RequestMessage::CodePickle(p) => {
+                future_id = p.future_id.clone();
+                let namespace = self.load_namespace(
+                    py,
+                    &session_id,
+                    p.namespace,
+                    (&p.globals, &p.locals),
+                    loads,
+                );
+                let res = namespace.and_then(|(globals, locals, atom_locals)| {
+                    let ret = proc_code_pickle(
+                        py,
+                        &p,
+                        (globals, locals, atom_locals),
+                        loads,
+                        &state.marshal_loads,
+                    )?;
+                    let locals =
+                        pickle_locals(py, locals, &p.return_locals, dumps, &state.pickled)?;
+                    Ok((ret, locals))
+                });
+                let (py_result, locals) = res_handler(py, logger.clone(), res, dumps);
+                let resp = protocol::ResponseCodePickle {
+                    future_id: p.future_id,
+                    py_result,
+                    locals,
+                };
+
+                Some(ResponseMessage::CodePickle(session_id.clone(), resp))
+            }
             RequestMessage::CodeString(s) => {
                 future_id = s.future_id.clone();
-                res_handler(py, logger.clone(), proc_code_string(py, &s, &loads), &dumps);
+                let namespace = self.load_namespace(
+                    py,
+                    &session_id,
+                    s.namespace,
+                    (&s.globals, &s.locals),
+                    loads,
+                );
+                let res = namespace.and_then(|(globals, locals, _)| {
+                    let ret = proc_code_string(py, &s, globals, locals)?;
+                    let locals =
+                        pickle_locals(py, locals, &s.return_locals, dumps, &state.pickled)?;
+                    Ok((ret, locals))
+                });
+                let (py_result, locals) = res_handler(py, logger.clone(), res, dumps);
+                let resp = protocol::ResponseCodeString {
+                    future_id: s.future_id,
+                    py_result,
+                    locals,
+                };
+
+                Some(ResponseMessage::CodeString(session_id.clone(), resp))
             }
+        };
+
+        if let (true, Some(redirector)) = (capturing, &state.redirector) {
+            redirector.finish(py);
         }
 
+        // Ran past its deadline, whatever it returned the client gets a timeout
+        let resp = match self.atoms.finish(py, state) {
+            None => resp,
+            Some(timeout) => {
+                logger.error(
+                    "pyproxy atom timed out",
+                    vec![
+                        ("session_id", LogValue::String(session_id.clone())),
+                        ("future_id", LogValue::String(future_id.clone())),
+                    ],
+                );
+
+                let err = protocol::ErrorResponse::new(
+                    protocol::ErrorCode::Timeout,
+                    format!("atom exceeded its {}ms timeout", timeout.as_millis()),
+                    Some(future_id.clone()),
+                );
+                Some(ResponseMessage::Error(session_id.clone(), err))
+            }
+        };
+
         logger.info(
             "finished processing pyproxyatom",
             vec![
@@ -59,9 +736,75 @@ fn run_forever(
                 ("future_id", LogValue::String(future_id)),
             ],
         );
+
+        resp
+    }
+
+    // The namespaces lock is never held while running python, which may release the GIL
+    fn load_namespace<'py>(
+        &self,
+        py: Python<'py>,
+        session_id: &str,
+        namespace: protocol::Namespace,
+        pickles: (&[u8], &[u8]),
+        loads: &PyObject,
+    ) -> PyResult<(&'py PyDict, &'py PyDict, &'py PyDict)> {
+        let session_dict = match namespace {
+            protocol::Namespace::Atom => None,
+            protocol::Namespace::SessionMerge | protocol::Namespace::SessionReplace => {
+                let fresh: Py<PyDict> = PyDict::new(py).into();
+                let session_dict = self
+                    .namespaces
+                    .lock()
+                    .unwrap()
+                    .entry(session_id.to_owned())
+                    .or_insert(fresh)
+                    .clone_ref(py);
+                Some(session_dict.into_ref(py))
+            }
+        };
+
+        load_namespace(py, session_dict, namespace, pickles, loads)
     }
 }
 
+// Unpickle the atom's (globals, locals) and resolve them against the session namespace,
+// giving (globals, locals, the atom's own locals).
+// Stateful sessions run with the session namespace as both globals and locals,
+// so assignments made by one atom are visible to the next.
+fn load_namespace<'py>(
+    py: Python<'py>,
+    session_dict: Option<&'py PyDict>,
+    namespace: protocol::Namespace,
+    (globals, locals): (&[u8], &[u8]),
+    loads: &PyObject,
+) -> PyResult<(&'py PyDict, &'py PyDict, &'py PyDict)> {
+    let globals_dict: &PyDict = loads.call1(py, (globals,))?.into_ref(py).downcast()?;
+    let locals_dict: &PyDict = loads.call1(py, (locals,))?.into_ref(py).downcast()?;
+
+    let session_dict = match session_dict {
+        None => return Ok((globals_dict, locals_dict, locals_dict)),
+        Some(session_dict) => session_dict,
+    };
+
+    if let protocol::Namespace::SessionReplace = namespace {
+        session_dict.clear();
+    }
+
+    session_dict.update(globals_dict.as_mapping())?;
+    session_dict.update(locals_dict.as_mapping())?;
+
+    Ok((session_dict, session_dict, locals_dict))
+}
+
+fn get_thread_ident(py: Python) -> PyResult<c_long> {
+    PyModule::import(py, "threading")?
+        .getattr("get_ident")?
+        .call0()?
+        .extract::<u64>()
+        .map(|id| id as c_long)
+}
+
 fn get_pickle_loads(py: Python) -> PyResult<PyObject> {
     PyModule::import(py, "pickle")
         .and_then(|m| m.getattr("loads"))
@@ -74,37 +817,192 @@ fn get_pickle_dumps(py: Python) -> PyResult<PyObject> {
         .map(|f| f.into_py(py))
 }
 
-fn proc_code_pickle(py: Python, msg: protocol::CodePickle, loads: &PyObject) -> PyResult<()> {
-    let locals = loads.call1(py, (msg.locals,))?;
-    let globals = loads.call1(py, (msg.globals,))?;
-    Ok(())
+fn get_marshal_loads(py: Python) -> PyResult<PyObject> {
+    PyModule::import(py, "marshal")
+        .and_then(|m| m.getattr("loads"))
+        .map(|f| f.into_py(py))
+}
+
+fn get_pickled_class(py: Python) -> PyResult<PyObject> {
+    PyModule::from_code(py, PICKLED_CLASS, CODE_FILENAME, "pyproxy_pickled")
+        .and_then(|m| m.getattr("Pickled"))
+        .map(|c| c.into_py(py))
+}
+
+// Pickled callables get the atom's own locals as keyword arguments, in a
+// stateful session the rest of the namespace is only their globals
+fn proc_code_pickle(
+    py: Python,
+    msg: &protocol::CodePickle,
+    (globals_dict, locals_dict, atom_locals): (&PyDict, &PyDict, &PyDict),
+    loads: &PyObject,
+    marshal_loads: &PyObject,
+) -> PyResult<PyObject> {
+    // Neither marshal nor pickled bytecode is portable across interpreter versions,
+    // version 0 clients never sent theirs so we can't check
+    let version = py.version_info();
+    if msg.py_version != (0, 0) && (version.major, version.minor) != msg.py_version {
+        return Err(PyRuntimeError::new_err(format!(
+            "client python version {}.{} does not match pyproxy worker python version {}.{}",
+            msg.py_version.0, msg.py_version.1, version.major, version.minor,
+        )));
+    }
+
+    match msg.format {
+        protocol::PickleFormat::Marshal => {
+            let code = marshal_loads.call1(py, (&msg.pickle[..],))?;
+            let builtins = PyModule::import(py, "builtins")?;
+            builtins
+                .getattr("eval")?
+                .call1((code, globals_dict, locals_dict))
+                .map(|o| o.into_py(py))
+        }
+        protocol::PickleFormat::Pickle => {
+            let mut callable = loads.call1(py, (&msg.pickle[..],))?;
+
+            // Plain python functions see the supplied globals
+            // layered over their own module globals
+            let types = PyModule::import(py, "types")?;
+            let function_type = types.getattr("FunctionType")?;
+            if callable.as_ref(py).is_instance(function_type)? && !globals_dict.is_empty() {
+                let func = callable.as_ref(py);
+                let func_globals = PyDict::new(py);
+                func_globals.update(func.getattr("__globals__")?.downcast()?)?;
+                func_globals.update(globals_dict.as_mapping())?;
+
+                let rebound = function_type.call1((
+                    func.getattr("__code__")?,
+                    func_globals,
+                    func.getattr("__name__")?,
+                    func.getattr("__defaults__")?,
+                    func.getattr("__closure__")?,
+                ))?;
+                rebound.setattr("__kwdefaults__", func.getattr("__kwdefaults__")?)?;
+                callable = rebound.into_py(py);
+            }
+
+            callable.call(py, (), Some(atom_locals))
+        }
+    }
 }
 
 fn proc_code_string(
     py: Python,
     msg: &protocol::CodeString,
-    loads: &PyObject,
+    globals_dict: &PyDict,
+    locals_dict: &PyDict,
 ) -> PyResult<PyObject> {
-    let locals = loads.call1(py, (&msg.locals[..],))?;
-    let globals = loads.call1(py, (&msg.globals[..],))?;
+    let builtins = PyModule::import(py, "builtins")?;
+    let compile = builtins.getattr("compile")?;
+    let eval = builtins.getattr("eval")?;
 
-    let locals_dict: &PyDict = locals.downcast(py)?;
-    let globals_dict: &PyDict = globals.downcast(py)?;
+    let start = match msg.mode {
+        protocol::EvalMode::Eval => "eval",
+        protocol::EvalMode::Exec => "exec",
+        protocol::EvalMode::Single => "single",
+        protocol::EvalMode::Last => return exec_last(py, &msg.code, globals_dict, locals_dict),
+    };
 
-    py.run(&msg.code, Some(&globals_dict), Some(&locals_dict))
+    let code = compile.call1((&msg.code, CODE_FILENAME, start))?;
+    eval.call1((code, globals_dict, locals_dict))
         .map(|o| o.into_py(py))
 }
 
-fn err_handler(res: PyResult<()>) {}
+// Notebook style - exec every statement then return the value
+// of the final statement should it be an expression
+fn exec_last(py: Python, code: &str, globals: &PyDict, locals: &PyDict) -> PyResult<PyObject> {
+    let ast = PyModule::import(py, "ast")?;
+    let builtins = PyModule::import(py, "builtins")?;
+    let compile = builtins.getattr("compile")?;
+    let eval = builtins.getattr("eval")?;
+
+    let tree = ast.call_method1("parse", (code, CODE_FILENAME, "exec"))?;
+    let body: &PyList = tree.getattr("body")?.downcast()?;
+
+    let last_expr = match body.len() {
+        0 => None,
+        n => {
+            let last = body.get_item(n - 1)?;
+            if last.is_instance(ast.getattr("Expr")?)? {
+                body.call_method1("pop", ())?;
+                Some(last)
+            } else {
+                None
+            }
+        }
+    };
+
+    let exec_code = compile.call1((tree, CODE_FILENAME, "exec"))?;
+    eval.call1((exec_code, globals, locals))?;
+
+    match last_expr {
+        None => Ok(py.None()),
+        Some(last) => {
+            let expr = ast
+                .getattr("Expression")?
+                .call1((last.getattr("value")?,))?;
+            let expr_code = compile.call1((expr, CODE_FILENAME, "eval"))?;
+            eval.call1((expr_code, globals, locals))
+                .map(|o| o.into_py(py))
+        }
+    }
+}
+
+// Pickle the locals the client asked for. With ReturnLocals::All each is
+// pickled on its own, so locals sharing an object come back with copies.
+fn pickle_locals(
+    py: Python,
+    locals: &PyDict,
+    return_locals: &protocol::ReturnLocals,
+    dumps: &PyObject,
+    pickled: &PyObject,
+) -> PyResult<Option<Vec<u8>>> {
+    let selected = PyDict::new(py);
+
+    match return_locals {
+        protocol::ReturnLocals::Nothing => return Ok(None),
+        protocol::ReturnLocals::All => {
+            for (k, v) in locals.iter() {
+                if k.eq("__builtins__")? {
+                    continue;
+                }
+
+                // Skip what can't be pickled (modules, open files, ...)
+                // rather than failing the whole atom. What can is kept pickled,
+                // the dict's pickle carries the bytes rather than pickling it again.
+                if let Ok(data) = dumps.call1(py, (v,)) {
+                    selected.set_item(k, pickled.call1(py, (data,))?)?;
+                }
+            }
+        }
+        protocol::ReturnLocals::Names(names) => {
+            for name in names {
+                if let Some(v) = locals.get_item(name) {
+                    selected.set_item(name, v)?;
+                }
+            }
+        }
+    }
+
+    let bytes = dumps.call1(py, (selected,))?;
+    let bytes: &PyBytes = bytes.downcast(py)?;
+    Ok(Some(bytes.as_bytes().to_owned()))
+}
 
-fn res_handler(py: Python, logger: Logger, res: PyResult<PyObject>, dumps: &PyObject) {
+fn res_handler(
+    py: Python,
+    logger: Logger,
+    res: PyResult<(PyObject, Option<Vec<u8>>)>,
+    dumps: &PyObject,
+) -> (PythonResult, Option<Vec<u8>>) {
     // Pickle then return value
-    let res = res.and_then(|o| dumps.call1(py, (o,)).map(|o| o.into_py(py)));
+    let res = res.and_then(|(o, locals)| Ok((dumps.call1(py, (o,))?, locals)));
 
     match res {
-        Ok(bytes) => {
+        Ok((bytes, locals)) => {
             // Res is a bytes instance returned from pickle.dumps
             let bytes: &PyBytes = bytes.downcast(py).unwrap();
+            (PythonResult::Return(bytes.as_bytes().to_owned()), locals)
         }
         Err(py_err) => {
             // Log the pyerror
@@ -112,6 +1010,473 @@ fn res_handler(py: Python, logger: Logger, res: PyResult<PyObject>, dumps: &PyOb
                 "failed to run python code on pyproxy server",
                 vec![("error", LogValue::String(format!("{}", py_err)))],
             );
+
+            (
+                PythonResult::Error(pickle_exception(py, py_err, dumps)),
+                None,
+            )
         }
     }
 }
+
+// A pickled RuntimeError(reason) built without python, as pickle protocol 2
+pub fn runtime_error_pickle(reason: &str) -> Vec<u8> {
+    let mut pickle = b"\x80\x02cbuiltins\nRuntimeError\nX".to_vec();
+    pickle.extend(&(reason.len() as u32).to_le_bytes());
+    pickle.extend(reason.as_bytes());
+    pickle.extend(b"\x85R.");
+    pickle
+}
+
+fn pickle_exception(py: Python, py_err: PyErr, dumps: &PyObject) -> Vec<u8> {
+    // Not every exception can be pickled (e.g. those holding open files),
+    // in which case the client gets a RuntimeError carrying its repr instead
+    let pickled = dumps.call1(py, (py_err.value(py),)).or_else(|_| {
+        let repr = py_err
+            .value(py)
+            .repr()
+            .map(|repr| repr.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| format!("{}", py_err));
+        let fallback = PyRuntimeError::new_err(repr);
+        dumps.call1(py, (fallback.value(py),))
+    });
+
+    // Even a str won't pickle if pickle itself is broken, the client must
+    // still get something it can raise
+    pickled
+        .ok()
+        .and_then(|b| {
+            b.downcast::<PyBytes>(py)
+                .ok()
+                .map(|b| b.as_bytes().to_owned())
+        })
+        .unwrap_or_else(|| {
+            runtime_error_pickle("exception raised on pyproxy server could not be pickled")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use pyo3::types::IntoPyDict;
+
+    use super::*;
+    use crate::runworker::capture::tests::{routing_output, Master};
+    use crate::runworker::workerstream::WorkerStream;
+
+    // An atom running code with empty globals and locals, queued as the event loop does
+    fn send_code(sender: &Sender, atoms: &Atoms, session_id: &str, future_id: &str, code: &str) {
+        let empty = Python::with_gil(|py| {
+            let dumps = get_pickle_dumps(py).unwrap();
+            let pickle = dumps.call1(py, (PyDict::new(py),)).unwrap();
+            pickle.extract::<Vec<u8>>(py).unwrap()
+        });
+        let msg = protocol::CodeString {
+            future_id: future_id.to_owned(),
+            mode: protocol::EvalMode::Exec,
+            namespace: protocol::Namespace::Atom,
+            return_locals: protocol::ReturnLocals::Nothing,
+            timeout_ms: None,
+            code: code.to_owned(),
+            locals: empty.clone(),
+            globals: empty,
+        };
+
+        atoms.queue(session_id, future_id, None);
+        sender.send(Command::Atom(
+            session_id.to_owned(),
+            RequestMessage::CodeString(msg),
+        ));
+    }
+
+    // For exec threads whose wakes nobody waits on
+    fn waker() -> Arc<Waker> {
+        let poll = mio::Poll::new().unwrap();
+        Arc::new(Waker::new(poll.registry(), mio::Token(0)).unwrap())
+    }
+
+    // Runs everything sent, returning (session_id, future_id) as each atom finished
+    fn run_all(
+        sender: Sender,
+        recv: mpsc::Receiver<ResponseMessage>,
+        executor: Executor,
+    ) -> Vec<(String, String)> {
+        drop(sender);
+        executor.run().unwrap();
+
+        recv.try_iter()
+            .map(|resp| match resp {
+                ResponseMessage::CodeString(session_id, resp) => {
+                    assert!(matches!(resp.py_result, PythonResult::Return(_)));
+                    (session_id, resp.future_id)
+                }
+                _ => panic!("expected a code string response"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sessions_run_concurrently() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let (sender, recv, _captures, atoms, executor) = new(logger, 2, waker());
+
+        send_code(&sender, &atoms, "a", "a1", "import time; time.sleep(1)");
+        send_code(&sender, &atoms, "b", "b1", "import time; time.sleep(1)");
+
+        let start = time::Instant::now();
+        let done = run_all(sender, recv, executor);
+        assert_eq!(done.len(), 2);
+        // Sleeping releases the GIL, the two sleeps overlap
+        assert!(start.elapsed() < time::Duration::from_millis(1800));
+    }
+
+    #[test]
+    fn exec_threads_wake_the_event_loop() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let mut poll = mio::Poll::new().unwrap();
+        // Held as the event loop does, the exec thread's last wake mustn't close it
+        let waker = Arc::new(Waker::new(poll.registry(), mio::Token(7)).unwrap());
+        let (sender, recv, _captures, atoms, executor) = new(logger, 1, waker.clone());
+
+        send_code(&sender, &atoms, "a", "a1", "pass");
+        drop(sender);
+        let exec = thread::spawn(move || executor.run().unwrap());
+
+        // Woken for the response, then for the exec thread exiting
+        let mut events = mio::Events::with_capacity(4);
+        let mut done = false;
+        while !done {
+            poll.poll(&mut events, Some(time::Duration::from_secs(5)))
+                .unwrap();
+            assert!(events.iter().any(|ev| ev.token() == mio::Token(7)));
+            loop {
+                match recv.try_recv() {
+                    Ok(resp) => assert_eq!(resp.session_id(), "a"),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        done = true;
+                        break;
+                    }
+                }
+            }
+        }
+        exec.join().unwrap();
+    }
+
+    #[test]
+    fn session_atoms_run_in_order() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let (sender, recv, _captures, atoms, executor) = new(logger, 2, waker());
+
+        // a2 would finish first if it ran alongside a1
+        send_code(&sender, &atoms, "a", "a1", "import time; time.sleep(0.3)");
+        send_code(&sender, &atoms, "a", "a2", "pass");
+
+        let done = run_all(sender, recv, executor);
+        let futures: Vec<&str> = done.iter().map(|(_, f)| f.as_str()).collect();
+        assert_eq!(futures, ["a1", "a2"]);
+    }
+
+    #[test]
+    fn sessions_take_turns() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let (sender, recv, _captures, atoms, executor) = new(logger, 1, waker());
+
+        for future_id in ["a1", "a2", "a3"] {
+            send_code(&sender, &atoms, "a", future_id, "pass");
+        }
+        send_code(&sender, &atoms, "b", "b1", "pass");
+        send_code(&sender, &atoms, "b", "b2", "pass");
+        send_code(&sender, &atoms, "c", "c1", "pass");
+        assert_eq!(sender.queued("a"), 3);
+        assert_eq!(sender.queued("b"), 2);
+        assert_eq!(sender.queued("d"), 0);
+
+        // One exec thread, so sessions get an atom each in turn rather than first come first served
+        let done = run_all(sender, recv, executor);
+        let futures: Vec<&str> = done.iter().map(|(_, f)| f.as_str()).collect();
+        assert_eq!(futures, ["a1", "b1", "c1", "a2", "b2", "a3"]);
+    }
+
+    #[test]
+    fn closed_session_discards_queued_atoms() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let (sender, recv, _captures, atoms, executor) = new(logger, 1, waker());
+
+        send_code(&sender, &atoms, "a", "a1", "pass");
+        send_code(&sender, &atoms, "a", "a2", "pass");
+        send_code(&sender, &atoms, "b", "b1", "pass");
+
+        // As the event loop does once the client has gone
+        assert_eq!(atoms.session_closed("a", false), 2);
+        sender.send(Command::SessionClosed(String::from("a")));
+        assert_eq!(sender.queued("a"), 1);
+
+        let done = run_all(sender, recv, executor);
+        assert_eq!(done, [(String::from("b"), String::from("b1"))]);
+    }
+
+    #[test]
+    fn closed_session_interrupts_running_atom() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let (sender, recv, _captures, atoms, executor) = new(logger, 1, waker());
+
+        send_code(&sender, &atoms, "a", "a1", "while True: pass");
+        let exec = thread::spawn(move || executor.run().unwrap());
+        while atoms.state.lock().unwrap().running.is_empty() {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        assert_eq!(atoms.session_closed("a", true), 0);
+        match recv.recv_timeout(time::Duration::from_secs(5)).unwrap() {
+            ResponseMessage::CodeString(_, resp) => {
+                assert!(matches!(resp.py_result, PythonResult::Error(_)));
+            }
+            _ => panic!("expected a code string response"),
+        }
+
+        drop(sender);
+        exec.join().unwrap();
+    }
+
+    #[test]
+    fn cancel_doesnt_wait_for_the_gil() {
+        let _routing = routing_output();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let logger = WorkerStream::new(fd_queue::mio::UnixStream::from_std(stream)).new_logger();
+        let (sender, recv, _captures, atoms, executor) = new(logger, 1, waker());
+
+        // A single call which holds the GIL throughout, then a loop for the interrupt to land in
+        send_code(
+            &sender,
+            &atoms,
+            "a",
+            "a1",
+            "sum(range(10**8))\nwhile True: pass",
+        );
+        let exec = thread::spawn(move || executor.run().unwrap());
+        while atoms.state.lock().unwrap().running.is_empty() {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        thread::sleep(time::Duration::from_millis(50));
+
+        let start = time::Instant::now();
+        assert_eq!(atoms.cancel("a", "a1"), CancelOutcome::DuringExecution);
+        assert!(start.elapsed() < time::Duration::from_millis(50));
+
+        // The interrupter raises it once the GIL is free
+        match recv.recv_timeout(time::Duration::from_secs(30)).unwrap() {
+            ResponseMessage::CodeString(_, resp) => {
+                assert!(matches!(resp.py_result, PythonResult::Error(_)));
+            }
+            _ => panic!("expected a code string response"),
+        }
+
+        drop(sender);
+        exec.join().unwrap();
+    }
+
+    #[test]
+    fn threads_left_running_dont_write_to_later_atoms() {
+        let _routing = routing_output();
+        let mut master = Master::connect();
+        let (sender, recv, captures, atoms, executor) = new(master.logger.clone(), 1, waker());
+
+        let started = "import sys, threading
+ev = threading.Event()
+def late(ev):
+    ev.wait()
+    print('late')
+t = threading.Thread(target=late, args=(ev,))
+t.start()
+sys.pyproxy_test_late = (ev, t)
+print('first')";
+        let prints = "import sys
+ev, t = sys.pyproxy_test_late
+ev.set()
+t.join()
+print('second')";
+        send_code(&sender, &atoms, "a", "future", started);
+        send_code(&sender, &atoms, "b", "future", prints);
+        assert_eq!(run_all(sender, recv, executor).len(), 2);
+
+        // The thread printed while b ran, that went to the worker's own stdout
+        // Each atom's stdout capture comes ahead of its stderr
+        let mut stdout: Vec<Capture> = captures.try_iter().step_by(2).collect();
+        let mut sent = vec![];
+        for capture in stdout.iter_mut() {
+            assert!(master.read(capture, 1024));
+            sent.push(master.sent());
+        }
+        assert_eq!(
+            sent,
+            [
+                [(String::from("first"), false)],
+                [(String::from("second"), false)]
+            ]
+        );
+    }
+
+    // A CodePickle of the current interpreter's version, unless given one
+    fn code_pickle(
+        py: Python,
+        format: protocol::PickleFormat,
+        py_version: Option<(u8, u8)>,
+        pickle: Vec<u8>,
+    ) -> protocol::CodePickle {
+        let version = py.version_info();
+        protocol::CodePickle {
+            future_id: String::from("future"),
+            format,
+            py_version: py_version.unwrap_or((version.major, version.minor)),
+            namespace: protocol::Namespace::Atom,
+            return_locals: protocol::ReturnLocals::Nothing,
+            timeout_ms: None,
+            pickle,
+            locals: vec![],
+            globals: vec![],
+        }
+    }
+
+    #[test]
+    fn marshalled_code_sees_locals_and_globals() {
+        Python::with_gil(|py| {
+            let code = py
+                .eval(
+                    "__import__('marshal').dumps(compile('x + y', 'test', 'eval'))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .extract::<Vec<u8>>()
+                .unwrap();
+            let msg = code_pickle(py, protocol::PickleFormat::Marshal, None, code);
+            let (globals, locals) = ([("x", 1)].into_py_dict(py), [("y", 2)].into_py_dict(py));
+
+            let loads = get_pickle_loads(py).unwrap();
+            let marshal_loads = get_marshal_loads(py).unwrap();
+            let res = proc_code_pickle(py, &msg, (globals, locals, locals), &loads, &marshal_loads);
+            assert_eq!(res.unwrap().extract::<i32>(py).unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn pickled_callables_get_atom_locals_as_keywords() {
+        Python::with_gil(|py| {
+            let dumps = get_pickle_dumps(py).unwrap();
+            let callable = py.eval("dict", None, None).unwrap();
+            let pickle = dumps.call1(py, (callable,)).unwrap();
+            let msg = code_pickle(
+                py,
+                protocol::PickleFormat::Pickle,
+                None,
+                pickle.extract(py).unwrap(),
+            );
+            let atom_locals = [("a", 1)].into_py_dict(py);
+
+            let loads = get_pickle_loads(py).unwrap();
+            let marshal_loads = get_marshal_loads(py).unwrap();
+            let res = proc_code_pickle(
+                py,
+                &msg,
+                (PyDict::new(py), atom_locals, atom_locals),
+                &loads,
+                &marshal_loads,
+            );
+            let res = res.unwrap();
+            let returned: &PyDict = res.as_ref(py).downcast().unwrap();
+            assert_eq!(returned.len(), 1);
+            assert_eq!(returned.get_item("a").unwrap().extract::<i32>().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn other_python_versions_are_refused() {
+        Python::with_gil(|py| {
+            for format in [
+                protocol::PickleFormat::Marshal,
+                protocol::PickleFormat::Pickle,
+            ] {
+                // Never loaded, the version is checked first
+                let msg = code_pickle(py, format, Some((2, 7)), b"garbage".to_vec());
+                let empty = PyDict::new(py);
+
+                let loads = get_pickle_loads(py).unwrap();
+                let marshal_loads = get_marshal_loads(py).unwrap();
+                let err = proc_code_pickle(py, &msg, (empty, empty, empty), &loads, &marshal_loads)
+                    .unwrap_err();
+                assert!(err.is_instance_of::<PyRuntimeError>(py));
+                assert!(err
+                    .to_string()
+                    .contains("client python version 2.7 does not match"));
+            }
+        });
+    }
+
+    #[test]
+    fn all_locals_are_pickled_once() {
+        Python::with_gil(|py| {
+            let code = "import threading
+class Counted:
+    pickles = 0
+    def __reduce__(self):
+        type(self).pickles += 1
+        return (int, (1,))
+counted = Counted()
+lock = threading.Lock()
+x = 'two'";
+            let locals = PyDict::new(py);
+            py.run(code, None, Some(locals)).unwrap();
+
+            let dumps = get_pickle_dumps(py).unwrap();
+            let pickled = get_pickled_class(py).unwrap();
+            let all = protocol::ReturnLocals::All;
+            let bytes = pickle_locals(py, locals, &all, &dumps, &pickled)
+                .unwrap()
+                .unwrap();
+
+            // The module, lock and class (not importable from here) don't pickle
+            let loads = get_pickle_loads(py).unwrap();
+            let returned = loads.call1(py, (PyBytes::new(py, &bytes),)).unwrap();
+            let returned: &PyDict = returned.as_ref(py).downcast().unwrap();
+            let mut names: Vec<String> = returned.keys().extract().unwrap();
+            names.sort();
+            assert_eq!(names, ["counted", "x"]);
+            assert_eq!(
+                returned
+                    .get_item("counted")
+                    .unwrap()
+                    .extract::<i32>()
+                    .unwrap(),
+                1
+            );
+            assert_eq!(
+                returned.get_item("x").unwrap().extract::<String>().unwrap(),
+                "two"
+            );
+
+            let counted = locals.get_item("Counted").unwrap();
+            assert_eq!(
+                counted
+                    .getattr("pickles")
+                    .unwrap()
+                    .extract::<i32>()
+                    .unwrap(),
+                1
+            );
+        });
+    }
+}
@@ -0,0 +1,131 @@
+// Sets up python before a worker takes sessions. Under the fork server the
+// template warms up once and every worker it forks inherits the result.
+use std::fs;
+use std::path;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+use super::config::Config;
+
+// Err says which step failed and why
+pub fn run(cfg: &Config) -> Result<(), String> {
+    Python::with_gil(|py| {
+        extend_sys_path(py, &cfg.python_path)
+            .map_err(|err| format!("couldn't extend sys.path: {}", err))?;
+
+        for module in &cfg.preload_modules {
+            PyModule::import(py, module.as_str())
+                .map_err(|err| format!("couldn't import preload module {}: {}", module, err))?;
+        }
+
+        if let Some(script) = &cfg.warmup_script {
+            let source = fs::read_to_string(script).map_err(|err| {
+                format!("couldn't read warm-up script {}: {}", script.display(), err)
+            })?;
+
+            run_script(py, script, &source)
+                .map_err(|err| format!("warm-up script {} failed: {}", script.display(), err))?;
+        }
+
+        Ok(())
+    })
+}
+
+fn extend_sys_path(py: Python, entries: &[path::PathBuf]) -> PyResult<()> {
+    let sys_path: &PyList = PyModule::import(py, "sys")?.getattr("path")?.downcast()?;
+    for (n, entry) in entries.iter().enumerate() {
+        sys_path.insert(n, entry.to_string_lossy())?;
+    }
+
+    Ok(())
+}
+
+// Its globals are thrown away, it's run for what it imports and caches
+fn run_script(py: Python, script: &path::Path, source: &str) -> PyResult<()> {
+    let builtins = PyModule::import(py, "builtins")?;
+    let filename = script.to_string_lossy();
+    let code = builtins
+        .getattr("compile")?
+        .call1((source, &filename[..], "exec"))?;
+
+    let globals = PyDict::new(py);
+    globals.set_item("__name__", "__pyproxy_warmup__")?;
+    globals.set_item("__file__", &filename[..])?;
+    builtins.getattr("exec")?.call1((code, globals))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    // A fresh directory per test, they share one interpreter
+    fn temp_dir(name: &str) -> path::PathBuf {
+        let dir = env::temp_dir().join(format!("pyproxy-warmup-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn preloads_from_python_path_then_runs_script() {
+        let dir = temp_dir("preload");
+        fs::write(dir.join("warmup_preloaded.py"), "CALLS = []\n").unwrap();
+        let script = dir.join("warmup.py");
+        fs::write(
+            &script,
+            "import warmup_preloaded\nwarmup_preloaded.CALLS.append(__name__)\n",
+        )
+        .unwrap();
+
+        let cfg = Config {
+            python_path: vec![dir.clone()],
+            preload_modules: vec![String::from("warmup_preloaded")],
+            warmup_script: Some(script),
+            ..Config::default()
+        };
+        run(&cfg).unwrap();
+
+        Python::with_gil(|py| {
+            let calls: Vec<String> = PyModule::import(py, "warmup_preloaded")
+                .unwrap()
+                .getattr("CALLS")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(calls, ["__pyproxy_warmup__"]);
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_preload_module() {
+        let cfg = Config {
+            preload_modules: vec![String::from("warmup_no_such_module")],
+            ..Config::default()
+        };
+
+        let err = run(&cfg).unwrap_err();
+        assert!(err.starts_with("couldn't import preload module warmup_no_such_module"));
+    }
+
+    #[test]
+    fn failing_script() {
+        let dir = temp_dir("failing");
+        let script = dir.join("warmup.py");
+        fs::write(&script, "raise ValueError('not warm')\n").unwrap();
+
+        let cfg = Config {
+            warmup_script: Some(script),
+            ..Config::default()
+        };
+
+        let err = run(&cfg).unwrap_err();
+        assert!(err.contains("failed: ValueError: not warm"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
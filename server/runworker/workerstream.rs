@@ -8,7 +8,10 @@ use fd_queue::DequeueFd;
 use mio::event::Source;
 use mio::{Interest, Registry, Token};
 
-use crate::messages::{self, LogLevel, LogMessage, PrintMessage};
+use crate::messages::{
+    self, LoadMessage, LogLevel, LogMessage, OutputKind, OutputMessage, RecycleMessage,
+    ReturnedMessage, SessionMessage,
+};
 
 #[derive(Clone)]
 pub struct WorkerStream {
@@ -28,6 +31,8 @@ impl WorkerStream {
                 inbuffer: Vec::with_capacity(4096),
                 outbuffer: Vec::with_capacity(4096),
                 new_msgs: VecDeque::with_capacity(64),
+                output_pauses: VecDeque::new(),
+                dispatch_stopped: false,
             })),
         }
     }
@@ -50,9 +55,66 @@ impl WorkerStream {
         self.inner.lock().unwrap().write()
     }
 
+    pub fn session_opened(&self, session_id: &str) {
+        self.session_msg(messages::SESSION_OPENED, session_id);
+    }
+
+    pub fn session_closed(&self, session_id: &str) {
+        self.session_msg(messages::SESSION_CLOSED, session_id);
+    }
+
+    fn session_msg(&self, msg_type: u8, session_id: &str) {
+        let msg = bincode::serialize(&SessionMessage {
+            session_id: session_id.to_owned(),
+        })
+        .expect("couldn't serialize SessionMessage");
+        let msg_len = (msg.len() as u32).to_be_bytes();
+        self.inner.lock().unwrap().new_msg(msg_type, msg_len, &msg);
+    }
+
+    pub fn report_load(&self, load: LoadMessage) {
+        let msg = bincode::serialize(&load).expect("couldn't serialize LoadMessage");
+        let msg_len = (msg.len() as u32).to_be_bytes();
+        self.inner
+            .lock()
+            .unwrap()
+            .new_msg(messages::LOAD_MESSAGE, msg_len, &msg);
+    }
+
+    pub fn recycle(&self, reason: String) {
+        let msg = bincode::serialize(&RecycleMessage { reason })
+            .expect("couldn't serialize RecycleMessage");
+        let msg_len = (msg.len() as u32).to_be_bytes();
+        self.inner
+            .lock()
+            .unwrap()
+            .new_msg(messages::RECYCLE_MESSAGE, msg_len, &msg);
+    }
+
+    // Hand a session we were sent while draining back to the master
+    pub fn session_returned(&self, nth: u64) {
+        let msg = bincode::serialize(&ReturnedMessage { nth })
+            .expect("couldn't serialize ReturnedMessage");
+        let msg_len = (msg.len() as u32).to_be_bytes();
+        self.inner
+            .lock()
+            .unwrap()
+            .new_msg(messages::SESSION_RETURNED, msg_len, &msg);
+    }
+
+    // Whether the master has stopped sending us sessions, past that none are in flight
+    pub fn dispatch_stopped(&self) -> bool {
+        self.inner.lock().unwrap().dispatch_stopped
+    }
+
     pub fn next_msg(&self) -> Option<([u8; protocol::REQUEST_HEADER_SIZE], RawFd)> {
         self.inner.lock().unwrap().new_msgs.pop_front()
     }
+
+    // A session whose output the master wants paused (true) or resumed
+    pub fn next_output_pause(&self) -> Option<(String, bool)> {
+        self.inner.lock().unwrap().output_pauses.pop_front()
+    }
 }
 
 impl Logger {
@@ -79,14 +141,28 @@ impl Logger {
         self.inner.lock().unwrap().new_msg(msg_type, msg_len, &msg);
     }
 
-    pub fn print(&self, message: String) {
-        let msg =
-            bincode::serialize(&PrintMessage { message }).expect("couldn't serialize PrintMessage");
+    pub fn output(
+        &self,
+        session_id: &str,
+        future_id: &str,
+        kind: OutputKind,
+        chunk: bool,
+        line: &[u8],
+    ) {
+        let msg = bincode::serialize(&OutputMessage {
+            session_id: session_id.to_owned(),
+            future_id: future_id.to_owned(),
+            kind,
+            chunk,
+            ts: messages::timestamp(),
+            line: line.to_vec(),
+        })
+        .expect("couldn't serialize OutputMessage");
         let msg_len = (msg.len() as u32).to_be_bytes();
         self.inner
             .lock()
             .unwrap()
-            .new_msg(messages::PRINT_MESSAGE, msg_len, &msg);
+            .new_msg(messages::OUTPUT_MESSAGE, msg_len, &msg);
     }
 }
 
@@ -95,6 +171,8 @@ struct Inner {
     inbuffer: Vec<u8>,
     outbuffer: Vec<u8>,
     new_msgs: VecDeque<([u8; protocol::REQUEST_HEADER_SIZE], RawFd)>,
+    output_pauses: VecDeque<(String, bool)>,
+    dispatch_stopped: bool,
 }
 
 impl Inner {
@@ -105,38 +183,96 @@ impl Inner {
     }
 
     fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        let bytes_read = self.stream.read(buf)?;
-        if bytes_read == 0 {
-            Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "worker stream closed",
-            ))?;
-        }
-        self.inbuffer.extend(&buf[..bytes_read]);
+        // Drain the stream, we're edge triggered
+        let closed = loop {
+            match self.stream.read(buf) {
+                Ok(0) => break true,
+                Ok(bytes_read) => self.inbuffer.extend(&buf[..bytes_read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(err) => return Err(err),
+            }
+        };
 
-        while self.inbuffer.len() >= protocol::REQUEST_HEADER_SIZE {
-            if let Some(fd) = self.stream.dequeue() {
-                let mut header = [0; protocol::REQUEST_HEADER_SIZE];
-                for (h, b) in header.iter_mut().zip(self.inbuffer.iter()) {
-                    *h = *b;
-                }
+        loop {
+            let msg_end = match self.inbuffer.first() {
+                None => break,
+                // A client's request header, its stream's fd comes with it
+                Some(0) => {
+                    if self.inbuffer.len() < protocol::REQUEST_HEADER_SIZE {
+                        break;
+                    }
+                    let fd = match self.stream.dequeue() {
+                        Some(fd) => fd,
+                        None => break,
+                    };
+
+                    let mut header = [0; protocol::REQUEST_HEADER_SIZE];
+                    for (h, b) in header.iter_mut().zip(self.inbuffer.iter()) {
+                        *h = *b;
+                    }
 
-                self.new_msgs.push_back((header, fd));
+                    self.new_msgs.push_back((header, fd));
+                    protocol::REQUEST_HEADER_SIZE
+                }
+                Some(&msg_type) => {
+                    if self.inbuffer.len() < 5 {
+                        break;
+                    }
+                    let msg_len = u32::from_be_bytes([
+                        self.inbuffer[1],
+                        self.inbuffer[2],
+                        self.inbuffer[3],
+                        self.inbuffer[4],
+                    ]) as usize;
+                    let msg_end = msg_len + 5;
+                    if self.inbuffer.len() < msg_end {
+                        break;
+                    }
 
-                let bytes_remaining = self.inbuffer.len() - protocol::REQUEST_HEADER_SIZE;
-                for n in 0..bytes_remaining {
-                    self.inbuffer[n] = self.inbuffer[n + protocol::REQUEST_HEADER_SIZE];
+                    let msg: SessionMessage = bincode::deserialize(&self.inbuffer[5..msg_end])
+                        .expect("worker couldn't deserialize SessionMessage");
+                    match msg_type {
+                        messages::PAUSE_OUTPUT => {
+                            self.output_pauses.push_back((msg.session_id, true))
+                        }
+                        messages::RESUME_OUTPUT => {
+                            self.output_pauses.push_back((msg.session_id, false))
+                        }
+                        messages::DISPATCH_STOPPED => self.dispatch_stopped = true,
+                        _ => {}
+                    }
+                    msg_end
                 }
-                self.inbuffer.truncate(bytes_remaining);
+            };
+
+            let bytes_remaining = self.inbuffer.len() - msg_end;
+            for n in 0..bytes_remaining {
+                self.inbuffer[n] = self.inbuffer[n + msg_end];
             }
-            break;
+            self.inbuffer.truncate(bytes_remaining);
+        }
+
+        if closed {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "worker stream closed",
+            ))?;
         }
 
         Ok(())
     }
 
     fn write(&mut self) -> io::Result<()> {
-        let bytes_written = self.stream.write(&self.outbuffer)?;
+        // Write until we'd block, we're edge triggered
+        let mut bytes_written = 0;
+        while bytes_written < self.outbuffer.len() {
+            match self.stream.write(&self.outbuffer[bytes_written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => bytes_written += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
         let bytes_remaining = self.outbuffer.len() - bytes_written;
 
         for n in 0..bytes_remaining {
@@ -0,0 +1,110 @@
+// Signals read from a file descriptor, so mio can poll for them
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+// Asking the master or a worker to shut down
+pub const SHUTDOWN_SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+#[derive(Debug)]
+pub struct SignalFd {
+    fd: OwnedFd,
+}
+
+impl SignalFd {
+    // Blocks the signals on this thread, threads spawned from it inherit the mask.
+    // So do child processes, which must unblock them, see unblock.
+    pub fn new(signals: &[libc::c_int]) -> io::Result<Self> {
+        let fd = unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            for sig in signals {
+                libc::sigaddset(&mut mask, *sig);
+            }
+
+            let errno = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+            if errno != 0 {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // Next pending signal, None once there are no more
+    pub fn read(&self) -> io::Result<Option<libc::c_int>> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+
+        let n = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                size,
+            )
+        };
+
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        Ok(Some(info.ssi_signo as libc::c_int))
+    }
+}
+
+// Unblocks the signals on this thread, it doesn't allocate so it's safe between fork and exec
+pub fn unblock(signals: &[libc::c_int]) -> io::Result<()> {
+    unsafe {
+        let mut mask: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        for sig in signals {
+            libc::sigaddset(&mut mask, *sig);
+        }
+
+        if libc::sigprocmask(libc::SIG_UNBLOCK, &mask, std::ptr::null_mut()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+impl Source for SignalFd {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).deregister(registry)
+    }
+}
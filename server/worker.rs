@@ -6,17 +6,21 @@ use std::sync::Arc;
 use ndjsonlogger::error;
 
 mod runworker;
-use runworker::{config, run_forever, Error, Result};
+use runworker::{config, forkserver, run_forever, warmup, Error, Result};
 mod messages;
+mod signals;
 
 fn main() -> Result<()> {
     let cfg = config::from_env().map(Arc::new)?;
     let mut sock_path = None;
+    let mut fork_server = false;
 
-    for (n, arg) in env::args().enumerate() {
-        match n {
-            0 => {}
-            1 => {
+    for arg in env::args().skip(1) {
+        match arg.as_ref() {
+            "--fork-server" => {
+                fork_server = true;
+            }
+            _ if sock_path.is_none() => {
                 sock_path = Some(arg.clone());
             }
             _ => {
@@ -27,6 +31,14 @@ fn main() -> Result<()> {
 
     let sock_path = sock_path.ok_or(Error::InvalidArgs)?;
 
+    // The template only returns here in the workers it forks, already warmed up
+    if fork_server {
+        forkserver::run(&cfg)?;
+    } else if let Err(err) = warmup::run(&cfg) {
+        error!("worker warm-up failed, exiting", { error = &err[..] });
+        process::exit(1);
+    }
+
     // Connect to the unix socket
     let stream = match UnixStream::connect(&sock_path) {
         Ok(stream) => stream,
@@ -8,6 +8,14 @@ from random import randint
 from pathlib import Path
 
 from tests.simple import run as run_simple
+from tests.modes import run as run_modes
+from tests.sessions import run as run_sessions
+from tests.locals import run as run_locals
+from tests.errors import run as run_errors
+from tests.cancel import run as run_cancel
+from tests.timeout import run as run_timeout
+from tests.workerlost import run as run_workerlost
+from tests.drain import run as run_drain
 
 CWD = Path(__file__).absolute().parent
 SERVER_BIN = CWD.parent / 'target' / 'release' / 'master'
@@ -39,6 +47,14 @@ class Server:
 def main():
     with Server(SERVER_BIN) as server:
         run_simple(server)
+        run_modes(server)
+        run_sessions(server)
+        run_locals(server)
+        run_errors(server)
+        run_cancel(server)
+        run_timeout(server)
+        run_workerlost(server)
+        run_drain(server)
 
 
 if __name__ == '__main__':
@@ -0,0 +1,54 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import unittest
+from time import sleep
+
+from pyproxy import PyProxySession, PyProxyCancelledError
+
+
+class CancelTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        addr = f"localhost:{self._server._bind_port}"
+        self._py_proxy_session = PyProxySession(addr)
+        self._remote_proc = self._py_proxy_session.connect()
+
+    def tearDown(self):
+        self._remote_proc.disconnect()
+
+    def test_cancel_queued(self):
+        # A session's atoms run one at a time, the second waits on the first
+        running = self._remote_proc.eval("import time; time.sleep(1)")
+        queued = self._remote_proc.eval("2 + 2", mode="eval")
+
+        self.assertEqual(queued.cancel(5), "before-execution")
+        with self.assertRaises(PyProxyCancelledError):
+            queued.wait(5)
+        self.assertIsNone(running.wait(5))
+
+    def test_cancel_running(self):
+        future = self._remote_proc.eval("while True: pass")
+        sleep(0.5)
+
+        self.assertEqual(future.cancel(5), "during-execution")
+        with self.assertRaises(KeyboardInterrupt):
+            future.wait(5)
+
+    def test_cancel_done(self):
+        future = self._remote_proc.eval("2 + 2", mode="eval")
+        self.assertEqual(future.wait(5), 4)
+        self.assertEqual(future.cancel(5), "not-found")
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(CancelTests(server, "test_cancel_queued"))
+    suite.addTest(CancelTests(server, "test_cancel_running"))
+    suite.addTest(CancelTests(server, "test_cancel_done"))
+
+    runner.run(suite)
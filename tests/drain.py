@@ -0,0 +1,92 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import os
+import shutil
+import signal
+import socket
+import subprocess as sp
+import tempfile
+import unittest
+from random import randint
+from time import sleep
+
+from pyproxy import PyProxySession
+
+
+class DrainTests(unittest.TestCase):
+    """
+    Each test starts a server of its own to shut down,
+    the shared one must outlive the other tests
+    """
+
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        # The server makes its run directory under its cwd
+        self._cwd = tempfile.mkdtemp()
+        self._bind_port = randint(10001, 14000)
+
+    def tearDown(self):
+        if self._proc.poll() is None:
+            self._proc.kill()
+            self._proc.wait()
+        shutil.rmtree(self._cwd, ignore_errors=True)
+
+    def start(self, **env):
+        env = {
+            **os.environ,
+            "PYPROXY_BIND_ADDR": f"127.0.0.1:{self._bind_port}",
+            "PYPROXY_OUTPUT_ADDR": f"127.0.0.1:{self._bind_port + 1}",
+            "PYPROXY_NUM_WORKERS": "1",
+            **env,
+        }
+        self._proc = sp.Popen([self._server._path], env=env, cwd=self._cwd)
+
+    def wait_listening(self):
+        for _ in range(50):
+            try:
+                socket.create_connection(("127.0.0.1", self._bind_port)).close()
+                return
+            except ConnectionRefusedError:
+                sleep(0.1)
+        self.fail("server didn't start listening")
+
+    def test_in_flight_atom_finishes(self):
+        self.start(PYPROXY_SHUTDOWN_GRACE_SECS="10")
+        self.wait_listening()
+
+        remote_proc = PyProxySession(f"localhost:{self._bind_port}").connect()
+        future = remote_proc.eval(
+            "import time\ntime.sleep(1)\n'done'", mode="last")
+        sleep(0.3)
+
+        self._proc.send_signal(signal.SIGTERM)
+        self.assertEqual(future.wait(5), "done")
+        remote_proc.disconnect()
+
+        # Once its workers have drained, leaving nothing behind
+        self.assertEqual(self._proc.wait(10), 0)
+        self.assertEqual(os.listdir(self._cwd), [])
+
+    def test_failed_startup_cleans_up(self):
+        warmup = os.path.join(self._cwd, "warmup.py")
+        with open(warmup, "w") as f:
+            f.write("raise RuntimeError('warm-up fails')\n")
+
+        # The fork server's template warms up before the master starts serving
+        self.start(PYPROXY_WORKER_MODE="fork-server",
+                   PYPROXY_WARMUP_SCRIPT=warmup)
+        self.assertNotEqual(self._proc.wait(30), 0)
+        self.assertEqual(os.listdir(self._cwd), ["warmup.py"])
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(DrainTests(server, "test_in_flight_atom_finishes"))
+    suite.addTest(DrainTests(server, "test_failed_startup_cleans_up"))
+
+    runner.run(suite)
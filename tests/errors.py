@@ -0,0 +1,96 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import socket
+import struct
+import unittest
+
+from pyproxy import PyProxySession
+
+ERROR_MSG_TYPE = 4
+UNSUPPORTED_VERSION = 1
+UNRECOGNISED_MESSAGE_TYPE = 2
+
+
+class ErrorTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        self._conn = socket.create_connection(
+            ("localhost", self._server._bind_port), timeout=5)
+
+    def tearDown(self):
+        self._conn.close()
+
+    def send(self, version, msg_type, body=b""):
+        header = bytes([0, version, msg_type, 0]) + struct.pack(">I", len(body))
+        self._conn.sendall(header + body)
+
+    def send_hello(self, versions):
+        # bincode Vec<u8>s, the versions then no features
+        body = struct.pack("<Q", len(versions)) + bytes(versions)
+        body += struct.pack("<Q", 0)
+        self.send(1, 1, body)
+
+    def recv_exact(self, n):
+        buf = b""
+        while len(buf) < n:
+            data = self._conn.recv(n - len(buf))
+            if not data:
+                return None
+            buf += data
+        return buf
+
+    def recv(self):
+        header = self.recv_exact(12)
+        if header is None:
+            return None
+        body = self.recv_exact(struct.unpack(">I", header[4:8])[0])
+        return header[2], body
+
+    def recv_error(self):
+        msg_type, body = self.recv()
+        self.assertEqual(msg_type, ERROR_MSG_TYPE)
+        code = struct.unpack("<H", body[:2])[0]
+        reason_len = struct.unpack("<Q", body[2:10])[0]
+        reason = body[10:10 + reason_len].decode()
+        return code, reason
+
+    def test_remote_exception(self):
+        addr = f"localhost:{self._server._bind_port}"
+        remote_proc = PyProxySession(addr).connect()
+        try:
+            with self.assertRaises(ZeroDivisionError):
+                remote_proc.eval("1 / 0").wait(5)
+        finally:
+            remote_proc.disconnect()
+
+    def test_unsupported_version(self):
+        self.send_hello([9])
+        code, _ = self.recv_error()
+        self.assertEqual(code, UNSUPPORTED_VERSION)
+
+        # Sent before closing
+        self.assertIsNone(self.recv())
+
+    def test_unrecognised_message_type(self):
+        self.send_hello([1])
+        msg_type, _ = self.recv()
+        self.assertEqual(msg_type, 1)
+
+        self.send(1, 99)
+        code, reason = self.recv_error()
+        self.assertEqual(code, UNRECOGNISED_MESSAGE_TYPE)
+        self.assertIn("99", reason)
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(ErrorTests(server, "test_remote_exception"))
+    suite.addTest(ErrorTests(server, "test_unsupported_version"))
+    suite.addTest(ErrorTests(server, "test_unrecognised_message_type"))
+
+    runner.run(suite)
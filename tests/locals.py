@@ -0,0 +1,51 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import unittest
+
+from pyproxy import PyProxySession
+
+
+class ReturnLocalsTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        addr = f"localhost:{self._server._bind_port}"
+        self._py_proxy_session = PyProxySession(addr)
+        self._remote_proc = self._py_proxy_session.connect()
+
+    def tearDown(self):
+        self._remote_proc.disconnect()
+
+    def test_all(self):
+        future = self._remote_proc.eval(
+            "x = 1\ny = 'two'", mode="exec", return_locals=True)
+        self.assertEqual(future.wait(5, with_locals=True),
+                         (None, {"x": 1, "y": "two"}))
+
+    def test_names(self):
+        future = self._remote_proc.eval(
+            "x = 1\ny = 'two'", mode="exec", return_locals=["y", "z"])
+        self.assertEqual(future.wait(5, with_locals=True), (None, {"y": "two"}))
+
+    def test_unpicklable_skipped(self):
+        code = "import threading\nlock = threading.Lock()\nx = 1"
+        future = self._remote_proc.eval(code, mode="exec", return_locals=True)
+        self.assertEqual(future.wait(5, with_locals=True), (None, {"x": 1}))
+
+    def test_not_asked(self):
+        future = self._remote_proc.eval("x = 1\nx", mode="last")
+        self.assertEqual(future.wait(5, with_locals=True), (1, None))
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(ReturnLocalsTests(server, "test_all"))
+    suite.addTest(ReturnLocalsTests(server, "test_names"))
+    suite.addTest(ReturnLocalsTests(server, "test_unpicklable_skipped"))
+    suite.addTest(ReturnLocalsTests(server, "test_not_asked"))
+
+    runner.run(suite)
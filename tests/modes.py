@@ -0,0 +1,68 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import unittest
+
+from pyproxy import PyProxySession
+
+
+class ModeTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        addr = f"localhost:{self._server._bind_port}"
+        self._py_proxy_session = PyProxySession(addr)
+        self._remote_proc = self._py_proxy_session.connect()
+
+    def tearDown(self):
+        self._remote_proc.disconnect()
+
+    def test_eval(self):
+        future = self._remote_proc.eval("2 + 2", mode="eval")
+        self.assertEqual(future.wait(5), 4)
+
+    def test_eval_statement(self):
+        future = self._remote_proc.eval("x = 2 + 2", mode="eval")
+        with self.assertRaises(SyntaxError):
+            future.wait(5)
+
+    def test_exec(self):
+        future = self._remote_proc.eval("x = 2 + 2\nx", mode="exec")
+        self.assertIsNone(future.wait(5))
+
+    def test_single(self):
+        future = self._remote_proc.eval("2 + 2", mode="single")
+        self.assertIsNone(future.wait(5))
+
+        # Like the REPL, expression statements are echoed to stdout
+        output = list(self._remote_proc.output(future))
+        self.assertEqual(output, [(1, b"4")])
+
+    def test_last(self):
+        future = self._remote_proc.eval("x = 2 + 2\nx * 2", mode="last")
+        self.assertEqual(future.wait(5), 8)
+
+    def test_last_statement(self):
+        future = self._remote_proc.eval("x = 2 + 2", mode="last")
+        self.assertIsNone(future.wait(5))
+
+    def test_default(self):
+        # Like the builtin exec, a trailing expression's value is dropped
+        future = self._remote_proc.eval("x = 2 + 2\nx * 2")
+        self.assertIsNone(future.wait(5))
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(ModeTests(server, "test_eval"))
+    suite.addTest(ModeTests(server, "test_eval_statement"))
+    suite.addTest(ModeTests(server, "test_exec"))
+    suite.addTest(ModeTests(server, "test_single"))
+    suite.addTest(ModeTests(server, "test_last"))
+    suite.addTest(ModeTests(server, "test_last_statement"))
+    suite.addTest(ModeTests(server, "test_default"))
+
+    runner.run(suite)
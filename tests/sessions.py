@@ -0,0 +1,69 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import unittest
+
+from pyproxy import PyProxySession
+
+
+class StatefulTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        addr = f"localhost:{self._server._bind_port}"
+
+        # Two stateful sessions and one without state
+        self._py_proxy_sessions = [
+            PyProxySession(addr, stateful=True).connect(),
+            PyProxySession(addr, stateful=True).connect(),
+            PyProxySession(addr).connect(),
+        ]
+
+    def tearDown(self):
+        for s in self._py_proxy_sessions:
+            s.disconnect()
+
+    def test_names_carry_over(self):
+        remote_proc = self._py_proxy_sessions[0]
+        remote_proc.eval("x = 2").wait(5)
+        self.assertEqual(remote_proc.eval("x + 2", mode="eval").wait(5), 4)
+
+    def test_sessions_are_apart(self):
+        first, second, _ = self._py_proxy_sessions
+        first.eval("x = 2").wait(5)
+        with self.assertRaises(NameError):
+            second.eval("x").wait(5)
+
+    def test_stateless(self):
+        remote_proc = self._py_proxy_sessions[2]
+        remote_proc.eval("x = 2").wait(5)
+        with self.assertRaises(NameError):
+            remote_proc.eval("x").wait(5)
+
+    def test_globals_merge(self):
+        remote_proc = self._py_proxy_sessions[0]
+        remote_proc.eval("x = 2").wait(5)
+        future = remote_proc.eval("x + y", mode="eval", globs={"y": 3})
+        self.assertEqual(future.wait(5), 5)
+
+    def test_globals_replace(self):
+        remote_proc = self._py_proxy_sessions[0]
+        remote_proc.eval("x = 2").wait(5)
+        remote_proc.eval("pass", globs={"y": 3}, replace_globals=True).wait(5)
+        self.assertEqual(remote_proc.eval("y", mode="eval").wait(5), 3)
+        with self.assertRaises(NameError):
+            remote_proc.eval("x").wait(5)
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(StatefulTests(server, "test_names_carry_over"))
+    suite.addTest(StatefulTests(server, "test_sessions_are_apart"))
+    suite.addTest(StatefulTests(server, "test_stateless"))
+    suite.addTest(StatefulTests(server, "test_globals_merge"))
+    suite.addTest(StatefulTests(server, "test_globals_replace"))
+
+    runner.run(suite)
@@ -35,7 +35,7 @@ class SimpleTests(unittest.TestCase):
             s.disconnect()
 
     def test_add(self):
-        future = next(self._py_proxy_sessions_round_robin).eval("2 + 2")
+        future = next(self._py_proxy_sessions_round_robin).eval("2 + 2", mode="eval")
         self.assertEqual(future.wait(), 4)
 
     def test_print(self):
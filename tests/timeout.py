@@ -0,0 +1,52 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import unittest
+
+from pyproxy import PyProxySession, PyProxyExecutionTimeoutError
+
+
+class TimeoutTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        addr = f"localhost:{self._server._bind_port}"
+        self._py_proxy_session = PyProxySession(addr)
+        self._remote_proc = self._py_proxy_session.connect()
+
+    def tearDown(self):
+        self._remote_proc.disconnect()
+
+    def test_busy_loop(self):
+        future = self._remote_proc.eval("while True: pass", timeout_ms=200)
+        with self.assertRaises(PyProxyExecutionTimeoutError):
+            future.wait(5)
+
+    def test_sleep(self):
+        future = self._remote_proc.eval(
+            "import time; time.sleep(10)", timeout_ms=200)
+        with self.assertRaises(PyProxyExecutionTimeoutError):
+            future.wait(5)
+
+    def test_within_timeout(self):
+        future = self._remote_proc.eval("2 + 2", mode="eval", timeout_ms=5000)
+        self.assertEqual(future.wait(5), 4)
+
+    def test_runs_after_timeout(self):
+        future = self._remote_proc.eval("while True: pass", timeout_ms=200)
+        with self.assertRaises(PyProxyExecutionTimeoutError):
+            future.wait(5)
+        self.assertEqual(self._remote_proc.eval("2 + 2", mode="eval").wait(5), 4)
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(TimeoutTests(server, "test_busy_loop"))
+    suite.addTest(TimeoutTests(server, "test_sleep"))
+    suite.addTest(TimeoutTests(server, "test_within_timeout"))
+    suite.addTest(TimeoutTests(server, "test_runs_after_timeout"))
+
+    runner.run(suite)
@@ -0,0 +1,48 @@
+#!/usr/bin/env python
+# -*- coding: utf-8 -*-
+
+import unittest
+
+from pyproxy import PyProxySession, PyProxyWorkerLostError
+
+
+class WorkerLostTests(unittest.TestCase):
+    def __init__(self, server, test_name):
+        self._server = server
+        super().__init__(test_name)
+
+    def setUp(self):
+        self._addr = f"localhost:{self._server._bind_port}"
+        self._remote_proc = PyProxySession(self._addr).connect()
+
+    def tearDown(self):
+        self._remote_proc.disconnect()
+
+    def test_worker_exits(self):
+        future = self._remote_proc.eval("import os; os._exit(1)")
+        with self.assertRaises(PyProxyWorkerLostError) as ctx:
+            future.wait(5)
+
+        # The server's notice says why
+        self.assertIn("exited", str(ctx.exception))
+
+    def test_new_session_after_exit(self):
+        future = self._remote_proc.eval("import os; os._exit(1)")
+        with self.assertRaises(PyProxyWorkerLostError):
+            future.wait(5)
+
+        # The worker is respawned, new sessions carry on
+        remote_proc = PyProxySession(self._addr).connect()
+        try:
+            self.assertEqual(remote_proc.eval("2 + 2", mode="eval").wait(5), 4)
+        finally:
+            remote_proc.disconnect()
+
+
+def run(server):
+    runner = unittest.TextTestRunner()
+    suite = unittest.TestSuite()
+    suite.addTest(WorkerLostTests(server, "test_worker_exits"))
+    suite.addTest(WorkerLostTests(server, "test_new_session_after_exit"))
+
+    runner.run(suite)